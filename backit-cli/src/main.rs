@@ -3,14 +3,20 @@ use std::{
     net::IpAddr,
     path::{Path, PathBuf},
     str::FromStr,
+    time::SystemTime,
 };
 
 use backit_core::{
-    ipc::to_server::*,
-    streams::{client, client_codec, StreamExt},
+    ipc::{to_server::*, Tagged},
+    snapshot::SnapshotId,
+    streams::{client, client_handshake, Compat, Hello, StreamExt},
+    sync::ConflictPolicy,
     SinkExt,
 };
 use bpaf::{any, construct, long, positional, pure, short, Parser};
+use output::Output;
+
+mod output;
 
 fn credentials() -> impl Parser<Credentials> {
     let key = short('k')
@@ -37,10 +43,13 @@ fn host_id() -> impl Parser<HostId> {
 }
 
 fn file_target() -> impl Parser<FileTarget> {
-    let dir = short('r')
-        .long("recursive")
-        .argument("DIR")
-        .map(|x| FileTarget::Dir { path: x });
+    let path = short('r').long("recursive").argument("DIR");
+    let exclude = short('e')
+        .long("exclude")
+        .help("leave out files matching a glob, like target/ or *.log")
+        .argument("GLOB")
+        .many();
+    let dir = construct!(FileTarget::Dir { path, exclude });
     let path = positional("FILE");
     let nickname = short('n').long("nickname").argument("NICKNAME").optional();
     let file = construct!(FileTarget::File { nickname, path });
@@ -131,6 +140,46 @@ fn any_host() -> impl Parser<AnyHost> {
     construct!([host_id, credentials])
 }
 
+/// where a transfer goes in the queue
+fn priority() -> impl Parser<i32> {
+    long("priority")
+        .help("transfers with a higher priority start first, the default is 0")
+        .argument::<i32>("N")
+        .fallback(0)
+}
+
+/// bytes with an optional K, M or G suffix, powers of 1024
+fn rate(x: &str) -> Result<u64, String> {
+    let x = x.trim_end_matches(['B', 'b']).trim_end_matches('i');
+    let (number, unit) = match x.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&x[..i], c.to_ascii_uppercase()),
+        _ => (x, ' '),
+    };
+    let factor = match unit {
+        ' ' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return Err(format!("unknown unit {unit}, expected K, M or G")),
+    };
+    let number: u64 = number.parse().map_err(|e| format!("{e}"))?;
+    Ok(number * factor)
+}
+
+/// a host given with `--host`, a value starting with `/` is dialed as a url
+fn host_flag() -> impl Parser<Option<AnyHost>> {
+    long("host")
+        .argument::<String>("HOST")
+        .map(|x| {
+            if x.starts_with('/') {
+                AnyHost::Credentials(Credentials::new_url(x))
+            } else {
+                AnyHost::HostId(HostId::new_nickname(x))
+            }
+        })
+        .optional()
+}
+
 fn backit() -> impl Parser<Backit> {
     struct LocalBackit {
         command: Command,
@@ -172,9 +221,31 @@ fn command() -> impl Parser<Command> {
     let host = {
         let target = file_target();
         let tags = short('t').long("tags").argument("TAGS").many();
-        construct!(Command::Host { tags, target })
-            .to_options()
-            .command("host")
+        let shares = short('s')
+            .long("share")
+            .help("grant rights, like HOST=read,list or @GROUP=read")
+            .argument::<Share>("SHARE")
+            .many();
+        construct!(Command::Host {
+            tags,
+            shares,
+            target
+        })
+        .to_options()
+        .command("host")
+    };
+
+    let share = {
+        let revoke = long("revoke").switch();
+        let target = target();
+        let shares = positional::<Share>("SHARE").some("must be at least 1 share supplied");
+        construct!(Command::Share {
+            revoke,
+            target,
+            shares
+        })
+        .to_options()
+        .command("share")
     };
 
     let unhost = target().some("must be at least 1 target supplied");
@@ -185,17 +256,71 @@ fn command() -> impl Parser<Command> {
     let fetch = {
         let host = any_host();
         let target = target();
-        construct!(Command::Fetch { target, host })
-            .to_options()
-            .command("fetch")
+        let delta = long("delta")
+            .help("only transfer what changed since the copy fetched before")
+            .switch();
+        let priority = priority();
+        construct!(Command::Fetch {
+            target,
+            host,
+            delta,
+            priority
+        })
+        .to_options()
+        .command("fetch")
     };
 
     let push = {
         let host = any_host();
         let target = target();
-        construct!(Command::Push { target, host })
+        let delta = long("delta")
+            .help("only transfer what changed since the copy pushed before")
+            .switch();
+        let priority = priority();
+        construct!(Command::Push {
+            target,
+            host,
+            delta,
+            priority
+        })
+        .to_options()
+        .command("push")
+    };
+
+    let sync = {
+        let host = any_host();
+        let remote = long("remote")
+            .help("the directory on the host, when it is not at the same path")
+            .argument::<PathBuf>("DIR")
+            .optional();
+        let policy = long("policy")
+            .help("how files both sides changed are resolved: newest, keep-both, local or remote")
+            .argument::<ConflictPolicy>("POLICY")
+            .optional();
+        let dir = positional::<PathBuf>("DIR");
+        construct!(Command::Sync {
+            remote,
+            policy,
+            host,
+            dir
+        })
+        .to_options()
+        .command("sync")
+    };
+
+    let pending = {
+        let accept = positional::<u64>("ID")
+            .map(Command::Accept)
+            .to_options()
+            .command("accept");
+        let reject = positional::<u64>("ID")
+            .map(Command::Reject)
             .to_options()
-            .command("push")
+            .command("reject");
+        let list = pure(Command::Pending);
+        construct!([accept, reject, list])
+            .to_options()
+            .command("pending")
     };
 
     let status = {
@@ -205,7 +330,190 @@ fn command() -> impl Parser<Command> {
             .command("status")
     };
 
-    construct!([start, stop, reload, connect, disconnect, host, unhost, fetch, push, status])
+    let file_list = {
+        let host = any_host().optional();
+        construct!(Command::FileList(host))
+            .to_options()
+            .command("filelist")
+    };
+
+    let backup = {
+        let job = short('j').long("job").argument("JOB");
+        let host = host_flag();
+        let target = target();
+        let full = long("full")
+            .help("read every file, also those that did not change since the last backup")
+            .switch();
+        let priority = priority();
+        construct!(Command::Backup {
+            job,
+            host,
+            target,
+            full,
+            priority
+        })
+        .to_options()
+        .command("backup")
+    };
+
+    let snapshots = {
+        let host = host_flag();
+        let job = short('j').long("job").argument("JOB").optional();
+        construct!(Command::Snapshots { host, job })
+            .to_options()
+            .command("snapshots")
+    };
+
+    let restore = {
+        let host = host_flag();
+        let to = long("to").argument::<PathBuf>("DIR").optional();
+        let snapshot = positional::<SnapshotId>("SNAPSHOT");
+        let paths = positional::<PathBuf>("PATH").many();
+        construct!(Command::Restore {
+            host,
+            to,
+            snapshot,
+            paths
+        })
+        .to_options()
+        .command("restore")
+    };
+
+    let diff = {
+        let host = host_flag();
+        let from = positional::<SnapshotId>("SNAPSHOT1");
+        let to = positional::<SnapshotId>("SNAPSHOT2");
+        construct!(Command::Diff { host, from, to })
+            .to_options()
+            .command("diff")
+    };
+
+    let prune = {
+        let host = host_flag();
+        let job = short('j').long("job").argument("JOB").optional();
+        let dry_run = long("dry-run").switch();
+        construct!(Command::Prune { host, job, dry_run })
+            .to_options()
+            .command("prune")
+    };
+
+    let verify = {
+        let host = host_flag();
+        let full = long("full").switch();
+        construct!(Command::Verify { host, full })
+            .to_options()
+            .command("verify")
+    };
+
+    let mount = {
+        let host = host_flag();
+        let source = positional::<String>("SNAPSHOT|HOST")
+            .help("a snapshot, stored locally or on --host, or a host to show the hosted files of");
+        let mountpoint = positional::<PathBuf>("MOUNTPOINT");
+        construct!(host, source, mountpoint)
+            .parse(|(host, source, mountpoint)| {
+                let source = match (source.parse::<SnapshotId>(), host) {
+                    (Ok(snapshot), host) => MountSource::Snapshot { host, snapshot },
+                    (Err(_), None) => {
+                        MountSource::Host(AnyHost::HostId(HostId::new_nickname(source)))
+                    }
+                    (Err(_), Some(_)) => return Err("--host only goes with a snapshot"),
+                };
+                Ok(Command::Mount { source, mountpoint })
+            })
+            .to_options()
+            .command("mount")
+    };
+
+    let unmount = positional::<PathBuf>("MOUNTPOINT");
+    let unmount = construct!(Command::Unmount(unmount))
+        .to_options()
+        .command("unmount");
+
+    let throttle = {
+        let host = host_flag();
+        let upload = long("up")
+            .help("upload limit per second, like 500K or 2M, 0 removes the limit")
+            .argument::<String>("RATE")
+            .parse(|x| rate(&x))
+            .optional();
+        let download = long("down")
+            .help("download limit per second, like 500K or 2M, 0 removes the limit")
+            .argument::<String>("RATE")
+            .parse(|x| rate(&x))
+            .optional();
+        let reset = long("reset")
+            .help("go back to the limits of the config")
+            .switch();
+        construct!(Command::Throttle {
+            host,
+            upload,
+            download,
+            reset
+        })
+        .to_options()
+        .command("throttle")
+    };
+
+    let jobs = pure(Command::Jobs).to_options().command("jobs");
+    let cancel = positional::<u64>("ID")
+        .map(Command::Cancel)
+        .to_options()
+        .command("cancel");
+    let pause = positional::<u64>("ID")
+        .map(Command::Pause)
+        .to_options()
+        .command("pause");
+    let resume = positional::<u64>("ID")
+        .map(Command::Resume)
+        .to_options()
+        .command("resume");
+
+    let log = {
+        let actor = long("actor")
+            .help("a peer id, a uid, local for every local user or daemon")
+            .argument::<String>("ACTOR")
+            .optional();
+        let action = long("action")
+            .help("like fetch, push, host or connect")
+            .argument::<String>("ACTION")
+            .optional();
+        let target = long("target")
+            .help("only entries whose target contains this")
+            .argument::<String>("TARGET")
+            .optional();
+        let since = long("since")
+            .help("only entries younger than this, like 1h or 2days")
+            .argument::<String>("AGE")
+            .parse(|x| humantime::parse_duration(&x).map(|x| SystemTime::now() - x))
+            .optional();
+        let failed = long("failed").help("only what failed").switch();
+        let limit = short('n')
+            .help("only the last N entries")
+            .argument::<usize>("N")
+            .optional();
+        let filter = construct!(LogFilter {
+            actor,
+            action,
+            target,
+            since,
+            failed,
+            limit
+        });
+        let follow = short('f')
+            .long("follow")
+            .help("keep showing new entries")
+            .switch();
+        construct!(Command::Log { filter, follow })
+            .to_options()
+            .command("log")
+    };
+
+    construct!([
+        start, stop, reload, connect, disconnect, host, share, unhost, fetch, push, sync, pending,
+        status, file_list, backup, snapshots, restore, diff, prune, verify, mount, unmount,
+        throttle, jobs, cancel, pause, resume, log
+    ])
 }
 
 /*
@@ -340,14 +648,38 @@ pub fn cli() -> OptionParser<UserCommand>{
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     let backit = backit().to_options().run();
-    println!("cmd = {backit:#?}");
+    let mut output = Output::new(backit.json());
+    if !backit.json() {
+        println!("cmd = {backit:#?}");
+    }
     let client = client().await?;
-    let mut client = client_codec(client);
+    let (mut client, _) = client_handshake(client, Hello::new()).await?;
     let expects_reply = !backit.no_confirm();
-    client.send(backit).await?;
-    if expects_reply {
-        let returned = client.next().await.unwrap()?;
-        println!("reply = {returned:?}");
+    // the cli only sends a single command, so it can use a fixed id
+    let id = 1;
+    client.send(Tagged::new(id, backit)).await?;
+    if !expects_reply {
+        return Ok(());
+    }
+    loop {
+        let Some(returned) = client.next().await else {
+            eyre::bail!("backitd closed the connection before replying");
+        };
+        let returned = returned?;
+        if returned.id() != id && returned.id() != 0 {
+            continue;
+        }
+        match returned.into_body() {
+            Compat::Known(returned) => {
+                output.reply(id, &returned)?;
+                if returned.is_final() {
+                    break;
+                }
+            }
+            Compat::Unknown(e) => {
+                eyre::bail!("backitd sent a reply this cli does not understand, is it newer? ({e})")
+            }
+        }
     }
     Ok(())
 }
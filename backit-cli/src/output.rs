@@ -0,0 +1,97 @@
+use backit_core::ipc::{AuditEntry, Outcome, Progress, RequestId, ServerReply};
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use std::time::Duration;
+
+/// prints the replies of backitd, either for humans or as newline delimited json
+pub enum Output {
+    Human { bar: Option<ProgressBar> },
+    Json,
+}
+impl Output {
+    pub fn new(json: bool) -> Self {
+        if json {
+            Self::Json
+        } else {
+            Self::Human { bar: None }
+        }
+    }
+    pub fn reply(&mut self, id: RequestId, reply: &ServerReply) -> eyre::Result<()> {
+        match self {
+            Self::Json => {
+                let event = serde_json::json!({ "id": id, "event": reply });
+                println!("{}", serde_json::to_string(&event)?);
+            }
+            Self::Human { bar } => match reply {
+                ServerReply::Progress(progress) => {
+                    if let Some(id) = progress.transfer {
+                        println!("queued as transfer {id}");
+                    }
+                    let bar = bar.get_or_insert_with(new_bar);
+                    render(bar, progress);
+                }
+                ServerReply::Log(entries) => entries.iter().for_each(print_entry),
+                ServerReply::LogEntry(entry) => print_entry(entry),
+                reply => {
+                    if let Some(bar) = bar.take() {
+                        bar.finish_and_clear();
+                    }
+                    println!("reply = {reply:?}");
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+fn print_entry(entry: &AuditEntry) {
+    let time = humantime::format_rfc3339_seconds(entry.time);
+    let target = entry.target.as_deref().unwrap_or("-");
+    let result = match &entry.result {
+        Outcome::Success => "ok".to_string(),
+        Outcome::Failure(e) => format!("failed: {e}"),
+    };
+    println!("{time} {} {} {target} {result}", entry.actor, entry.action);
+    for line in entry.output.iter().flat_map(|x| x.lines()) {
+        println!("    {line}");
+    }
+}
+
+fn new_bar() -> ProgressBar {
+    let bar = ProgressBar::new_spinner();
+    bar.enable_steady_tick(Duration::from_millis(100));
+    bar
+}
+
+fn render(bar: &ProgressBar, progress: &Progress) {
+    match progress.bytes_total {
+        Some(total) => {
+            bar.set_style(
+                ProgressStyle::with_template("{spinner} [{bar:40}] {bytes}/{total_bytes} {msg}")
+                    .expect("valid template")
+                    .progress_chars("=> "),
+            );
+            bar.set_length(total);
+        }
+        None => bar.set_style(
+            ProgressStyle::with_template("{spinner} {bytes} {msg}").expect("valid template"),
+        ),
+    }
+    bar.set_position(progress.bytes_done);
+
+    let files = match progress.files_total {
+        Some(total) => format!("{}/{} files", progress.files_done, total),
+        None => format!("{} files", progress.files_done),
+    };
+    let eta = match progress.eta_secs {
+        Some(x) => format!(", eta {}", HumanDuration(Duration::from_secs(x))),
+        None => String::new(),
+    };
+    let current = match &progress.current {
+        Some(x) => format!(" {x}"),
+        None => String::new(),
+    };
+    bar.set_message(format!(
+        "{files}, {}/s{eta}{current}",
+        HumanBytes(progress.bytes_per_sec)
+    ));
+}
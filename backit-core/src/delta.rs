@@ -0,0 +1,439 @@
+//! rsync style deltas between two copies of a file
+//!
+//! the side with the old copy sends a [`Signature`] of its blocks, the side with the new copy
+//! answers with [`DeltaOp`]s, references to blocks the old copy already has and the literal data
+//! in between. blocks are found at any offset with a rolling checksum, so an insert in the middle
+//! of a file only costs the inserted bytes
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// blocks are never smaller than this
+pub const MIN_BLOCK: u32 = 2048;
+/// the block size grows with the file so a signature has at most this many blocks and always
+/// fits in a packet
+pub const MAX_BLOCKS: u64 = 32 * 1024;
+
+/// the block size for an old copy of `len` bytes
+pub fn block_size(len: u64) -> u32 {
+    len.div_ceil(MAX_BLOCKS)
+        .clamp(MIN_BLOCK as u64, u32::MAX as u64) as u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockSig {
+    /// the rolling checksum, cheap to compare at every offset
+    pub weak: u32,
+    /// the start of the blake3 hash, only compared when the weak checksum matches
+    pub strong: [u8; 16],
+}
+
+/// the blocks of an old copy, a partial block at the end is left out
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature {
+    pub block_size: u32,
+    pub blocks: Vec<BlockSig>,
+}
+
+/// a piece of the new copy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeltaOp {
+    /// `count` blocks of the old copy, starting at block `index`
+    Copy {
+        index: u64,
+        count: u64,
+    },
+    Literal(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+impl DeltaOp {
+    /// how many bytes of the new copy this stands for
+    pub fn len(&self, block_size: u32) -> u64 {
+        match self {
+            Self::Copy { count, .. } => count * block_size as u64,
+            Self::Literal(x) => x.len() as u64,
+        }
+    }
+    /// [`DeltaOp::len`] for ops that come from a peer, `None` when it does not fit in a u64
+    pub fn checked_len(&self, block_size: u32) -> Option<u64> {
+        match self {
+            Self::Copy { count, .. } => count.checked_mul(block_size as u64),
+            Self::Literal(x) => Some(x.len() as u64),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Copy { count, .. } => *count == 0,
+            Self::Literal(x) => x.is_empty(),
+        }
+    }
+}
+
+fn strong(data: &[u8]) -> [u8; 16] {
+    let mut out = [0; 16];
+    out.copy_from_slice(&blake3::hash(data).as_bytes()[..16]);
+    out
+}
+
+/// the checksum of rsync, it can slide over the data one byte at a time
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, x) in data.iter().enumerate() {
+            a = a.wrapping_add(*x as u32);
+            b = b.wrapping_add(((data.len() - i) as u32).wrapping_mul(*x as u32));
+        }
+        Self {
+            a,
+            b,
+            len: data.len() as u32,
+        }
+    }
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+    /// move the window one byte, `out` leaves it at the start and `new` comes in at the end
+    fn roll(&mut self, out: u8, new: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(new as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+}
+
+/// add an op, merging copies of consecutive blocks and leaving out empty ops
+fn push_op(ops: &mut Vec<DeltaOp>, op: DeltaOp) {
+    if op.is_empty() {
+        return;
+    }
+    if let (
+        Some(DeltaOp::Copy { index, count }),
+        DeltaOp::Copy {
+            index: next,
+            count: more,
+        },
+    ) = (ops.last_mut(), &op)
+    {
+        if *index + *count == *next {
+            *count += more;
+            return;
+        }
+    }
+    ops.push(op);
+}
+
+/// read until the buffer is full or the input ends, returns how much was read
+fn read_full(input: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match input.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(x) => n += x,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// the signature of an old copy that is `len` bytes long
+pub fn signature(mut input: impl Read, len: u64) -> io::Result<Signature> {
+    let block_size = block_size(len);
+    let mut buf = vec![0; block_size as usize];
+    let mut blocks = Vec::new();
+    while read_full(&mut input, &mut buf)? == buf.len() {
+        blocks.push(BlockSig {
+            weak: Rolling::new(&buf).digest(),
+            strong: strong(&buf),
+        });
+    }
+    Ok(Signature { block_size, blocks })
+}
+
+/// looks up blocks of a signature in a new copy
+pub struct Matcher<'a> {
+    signature: &'a Signature,
+    /// block indexes by weak checksum
+    weak: HashMap<u32, Vec<u64>>,
+}
+impl<'a> Matcher<'a> {
+    pub fn new(signature: &'a Signature) -> Self {
+        let mut weak: HashMap<u32, Vec<u64>> = HashMap::new();
+        for (i, x) in signature.blocks.iter().enumerate() {
+            weak.entry(x.weak).or_default().push(i as u64);
+        }
+        Self { signature, weak }
+    }
+    fn find(&self, weak: u32, window: &[u8]) -> Option<u64> {
+        let candidates = self.weak.get(&weak)?;
+        let strong = strong(window);
+        candidates
+            .iter()
+            .copied()
+            .find(|x| self.signature.blocks[*x as usize].strong == strong)
+    }
+
+    /// the ops for the new copy read from `input`, from where it is now
+    ///
+    /// this stops at the end of the input, once the literals add up to `max_literal` or once
+    /// `max_input` bytes were covered, so the ops of a large file can be sent in several
+    /// packets. returns the ops and how many bytes of the input they cover, the next call
+    /// continues from there
+    pub fn delta(
+        &self,
+        mut input: impl Read,
+        max_literal: usize,
+        max_input: u64,
+    ) -> io::Result<(Vec<DeltaOp>, u64)> {
+        let block_size = self.signature.block_size as usize;
+        let read_size = (block_size * 16).max(1024 * 1024);
+        let mut ops: Vec<DeltaOp> = Vec::new();
+        let mut literal_total = 0;
+        // input that is not covered by ops yet is `buf[start..]`, `buf[start..pos]` is literal
+        let mut buf: Vec<u8> = Vec::new();
+        let mut drained: u64 = 0;
+        let mut start = 0;
+        let mut pos = 0;
+        let mut eof = false;
+        let mut rolling: Option<Rolling> = None;
+
+        loop {
+            if buf.len() - pos < block_size && !eof {
+                buf.drain(..start);
+                drained += start as u64;
+                pos -= start;
+                start = 0;
+                let old = buf.len();
+                buf.resize(old + read_size, 0);
+                let n = read_full(&mut input, &mut buf[old..])?;
+                buf.truncate(old + n);
+                eof = n < read_size;
+                continue;
+            }
+            if buf.len() - pos < block_size {
+                // the tail is shorter than a block so it is literal, as far as it fits
+                pos = buf
+                    .len()
+                    .min(start.saturating_add(max_literal - literal_total));
+                break;
+            }
+            if literal_total + (pos - start) >= max_literal || drained + pos as u64 >= max_input {
+                break;
+            }
+            let window = &buf[pos..pos + block_size];
+            let weak = match &rolling {
+                Some(x) => x.digest(),
+                None => {
+                    let x = Rolling::new(window);
+                    let weak = x.digest();
+                    rolling = Some(x);
+                    weak
+                }
+            };
+            if let Some(index) = self.find(weak, window) {
+                literal_total += pos - start;
+                push_op(&mut ops, DeltaOp::Literal(buf[start..pos].to_vec()));
+                push_op(&mut ops, DeltaOp::Copy { index, count: 1 });
+                pos += block_size;
+                start = pos;
+                rolling = None;
+                continue;
+            }
+            match (&mut rolling, buf.get(pos + block_size)) {
+                (Some(x), Some(new)) => x.roll(buf[pos], *new),
+                // the next byte is not read yet, the checksum starts over after the read
+                _ => rolling = None,
+            }
+            pos += 1;
+        }
+        push_op(&mut ops, DeltaOp::Literal(buf[start..pos].to_vec()));
+        Ok((ops, drained + pos as u64))
+    }
+}
+
+/// write the part of the new copy an op stands for, `old` is the copy the signature was made of
+pub fn apply(
+    op: &DeltaOp,
+    block_size: u32,
+    old: &mut (impl Read + Seek),
+    out: &mut impl Write,
+) -> io::Result<()> {
+    match op {
+        DeltaOp::Copy { index, count } => {
+            let out_of_range =
+                || io::Error::new(io::ErrorKind::InvalidData, "the op is not in the old copy");
+            let len = count
+                .checked_mul(block_size as u64)
+                .ok_or_else(out_of_range)?;
+            let start = index
+                .checked_mul(block_size as u64)
+                .ok_or_else(out_of_range)?;
+            old.seek(SeekFrom::Start(start))?;
+            if io::copy(&mut old.take(len), out)? != len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the old copy changed since its signature was made",
+                ));
+            }
+        }
+        DeltaOp::Literal(data) => out.write_all(data)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// reproducible bytes that do not repeat, so blocks only match where they should
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    /// the ops that turn `old` into `new`, made in as many calls as it takes, checked by applying
+    /// them
+    fn delta(old: &[u8], new: &[u8], max_literal: usize) -> Vec<DeltaOp> {
+        let signature = signature(old, old.len() as u64).unwrap();
+        let matcher = Matcher::new(&signature);
+        let mut ops = Vec::new();
+        let mut covered = 0;
+        loop {
+            let (more, len) = matcher
+                .delta(&new[covered as usize..], max_literal, u64::MAX)
+                .unwrap();
+            if more.is_empty() {
+                break;
+            }
+            assert_eq!(
+                more.iter()
+                    .map(|x| x.len(signature.block_size))
+                    .sum::<u64>(),
+                len
+            );
+            ops.extend(more);
+            covered += len;
+        }
+        assert_eq!(covered, new.len() as u64);
+        let mut out = Vec::new();
+        for op in &ops {
+            apply(op, signature.block_size, &mut Cursor::new(old), &mut out).unwrap();
+        }
+        assert_eq!(out, new);
+        ops
+    }
+    fn literal_len(ops: &[DeltaOp]) -> usize {
+        ops.iter()
+            .map(|x| match x {
+                DeltaOp::Literal(x) => x.len(),
+                DeltaOp::Copy { .. } => 0,
+            })
+            .sum()
+    }
+
+    #[test]
+    fn rolling_matches_a_fresh_checksum() {
+        let data = noise(4096, 1);
+        let window = 512;
+        let mut rolling = Rolling::new(&data[..window]);
+        for i in 1..data.len() - window {
+            rolling.roll(data[i - 1], data[i + window - 1]);
+            assert_eq!(
+                rolling.digest(),
+                Rolling::new(&data[i..i + window]).digest()
+            );
+        }
+    }
+
+    #[test]
+    fn identical() {
+        let data = noise(MIN_BLOCK as usize * 10, 2);
+        let ops = delta(&data, &data, usize::MAX);
+        assert_eq!(
+            ops,
+            vec![DeltaOp::Copy {
+                index: 0,
+                count: 10
+            }]
+        );
+    }
+
+    #[test]
+    fn insert() {
+        let old = noise(MIN_BLOCK as usize * 10, 3);
+        let inserted = noise(100, 4);
+        let at = MIN_BLOCK as usize * 4 + 17;
+        let new = [&old[..at], &inserted, &old[at..]].concat();
+        let ops = delta(&old, &new, usize::MAX);
+        // the block the insert lands in is sent as it is now, every other block is copied
+        assert_eq!(literal_len(&ops), MIN_BLOCK as usize + inserted.len());
+    }
+
+    #[test]
+    fn delete() {
+        let old = noise(MIN_BLOCK as usize * 10, 5);
+        let at = MIN_BLOCK as usize * 6 + 5;
+        let new = [&old[..at], &old[at + 300..]].concat();
+        let ops = delta(&old, &new, usize::MAX);
+        assert_eq!(literal_len(&ops), MIN_BLOCK as usize - 300);
+    }
+
+    #[test]
+    fn tail_shorter_than_a_block() {
+        let old = noise(MIN_BLOCK as usize * 3 + 100, 6);
+        let ops = delta(&old, &old, usize::MAX);
+        // the partial block at the end is not in the signature
+        assert_eq!(
+            ops,
+            vec![
+                DeltaOp::Copy { index: 0, count: 3 },
+                DeltaOp::Literal(old[MIN_BLOCK as usize * 3..].to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn shorter_than_a_block() {
+        let old = noise(100, 7);
+        let new = noise(200, 8);
+        assert!(signature(&old[..], old.len() as u64)
+            .unwrap()
+            .blocks
+            .is_empty());
+        assert_eq!(delta(&old, &new, usize::MAX), vec![DeltaOp::Literal(new)]);
+    }
+
+    #[test]
+    fn literal_cap() {
+        let old = noise(MIN_BLOCK as usize * 4, 9);
+        let new = noise(MIN_BLOCK as usize * 20, 10);
+        let cap = MIN_BLOCK as usize * 3;
+        let signature = signature(&old[..], old.len() as u64).unwrap();
+        let (ops, len) = Matcher::new(&signature)
+            .delta(&new[..], cap, u64::MAX)
+            .unwrap();
+        assert_eq!(literal_len(&ops), cap);
+        assert_eq!(len, cap as u64);
+        // the rest follows in later calls
+        assert_eq!(literal_len(&delta(&old, &new, cap)), new.len());
+    }
+}
@@ -12,7 +12,10 @@ pub use tokio_serde::Framed;
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use uuid::Uuid;
 
+pub mod delta;
+pub mod snapshot;
 pub mod streams;
+pub mod sync;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeviceName {
@@ -25,12 +28,22 @@ pub mod ipc {
     pub type Stream = interprocess::local_socket::tokio::Stream;
     use std::{
         ffi::{OsStr, OsString},
+        fmt::Display,
         net::IpAddr,
         path::PathBuf,
+        time::SystemTime,
     };
 
     use either::Either;
     use from_client::*;
+
+    use crate::{
+        snapshot::{
+            ChunkHash, EntryKind, FileMeta, PruneReport, SnapshotDiff, SnapshotId, SnapshotInfo,
+            VerifyReport,
+        },
+        sync::SyncReport,
+    };
     use serde::{Deserialize, Serialize};
     use uuid::Uuid;
 
@@ -38,10 +51,16 @@ pub mod ipc {
         pub use super::from_client::*;
     }
     pub(crate) mod from_client {
-        use std::path::PathBuf;
+        use std::{
+            collections::BTreeSet, fmt::Display, path::PathBuf, str::FromStr, time::SystemTime,
+        };
+
+        use super::{PushId, TransferId};
 
         use serde::{Deserialize, Serialize};
 
+        use crate::{snapshot::SnapshotId, sync::ConflictPolicy};
+
         #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
         pub enum Credentials {
             /// a single use key used to identify and connect to a remote client
@@ -77,6 +96,9 @@ pub mod ipc {
             pub fn new_id(id: String) -> Self {
                 Self { nickname_or_id: id }
             }
+            pub fn as_str(&self) -> &str {
+                &self.nickname_or_id
+            }
         }
         #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
         pub enum FileTarget {
@@ -86,6 +108,9 @@ pub mod ipc {
             },
             Dir {
                 path: PathBuf,
+                /// globs of files to leave out, on top of the `.backitignore` files and the config
+                #[serde(default)]
+                exclude: Vec<String>,
             },
         }
         impl FileTarget {
@@ -93,7 +118,10 @@ pub mod ipc {
                 Self::File { path, nickname }
             }
             pub fn new_dir(path: PathBuf) -> Self {
-                Self::Dir { path }
+                Self::Dir {
+                    path,
+                    exclude: Vec::new(),
+                }
             }
         }
 
@@ -110,6 +138,100 @@ pub mod ipc {
                 Self::Tags(tags)
             }
         }
+        /// which entries of the audit log to show, every field that is set has to match
+        #[derive(
+            Debug, Clone, Default, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+        )]
+        #[serde(default)]
+        pub struct LogFilter {
+            /// a peer id, a uid, `local` for every local user or `daemon`
+            pub actor: Option<String>,
+            pub action: Option<String>,
+            /// part of the target
+            pub target: Option<String>,
+            pub since: Option<SystemTime>,
+            /// only what failed
+            pub failed: bool,
+            /// only the last entries that match
+            pub limit: Option<usize>,
+        }
+        /// what a peer may do with a hosted file
+        #[derive(
+            Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+        )]
+        #[serde(rename_all = "snake_case")]
+        pub enum Right {
+            /// fetch the file
+            Read,
+            /// push a new version of the file
+            Write,
+            /// see the file in a remote filelist
+            List,
+        }
+        impl FromStr for Right {
+            type Err = String;
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    "read" | "r" => Ok(Self::Read),
+                    "write" | "w" => Ok(Self::Write),
+                    "list" | "l" => Ok(Self::List),
+                    _ => Err(format!("unknown right {s}, expected read, write or list")),
+                }
+            }
+        }
+        impl Display for Right {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(match self {
+                    Self::Read => "read",
+                    Self::Write => "write",
+                    Self::List => "list",
+                })
+            }
+        }
+
+        /// who a hosted file is shared with
+        #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+        pub enum Grantee {
+            Host(HostId),
+            /// a group of peers from the daemon config
+            Group(String),
+        }
+
+        /// rights on a hosted file for a host or group, written as `<host>=<rights>` or
+        /// `@<group>=<rights>` with comma separated rights, like `@family=read,list`
+        #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+        pub struct Share {
+            pub grantee: Grantee,
+            pub rights: BTreeSet<Right>,
+        }
+        impl FromStr for Share {
+            type Err = String;
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let (grantee, rights) = s
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected <host>=<rights>, got {s}"))?;
+                let grantee = match grantee.strip_prefix('@') {
+                    Some(group) => Grantee::Group(group.to_string()),
+                    None => Grantee::Host(HostId::new_nickname(grantee.to_string())),
+                };
+                let rights = rights
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<BTreeSet<Right>, _>>()?;
+                Ok(Self { grantee, rights })
+            }
+        }
+        impl Display for Share {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match &self.grantee {
+                    Grantee::Host(x) => write!(f, "{}=", x.as_str())?,
+                    Grantee::Group(x) => write!(f, "@{x}=")?,
+                }
+                let rights: Vec<String> = self.rights.iter().map(Right::to_string).collect();
+                f.write_str(&rights.join(","))
+            }
+        }
+
         #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
         pub enum AnyHost {
             HostId(HostId),
@@ -123,6 +245,18 @@ pub mod ipc {
                 Self::Credentials(credentials)
             }
         }
+        /// what a mount shows
+        #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+        pub enum MountSource {
+            /// the files of a snapshot stored locally or on a host
+            Snapshot {
+                host: Option<AnyHost>,
+                snapshot: SnapshotId,
+            },
+            /// the hosted files of a host that we may read
+            Host(AnyHost),
+        }
+
         #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
         pub enum Command {
             Start,
@@ -140,19 +274,137 @@ pub mod ipc {
             Host {
                 target: FileTarget,
                 tags: Vec<String>,
+                /// rights to grant on the hosted files, nobody but us can see them otherwise
+                #[serde(default)]
+                shares: Vec<Share>,
+            },
+            /// grant or with `revoke` take away rights on hosted files
+            Share {
+                target: Target,
+                shares: Vec<Share>,
+                revoke: bool,
             },
             Unhost(Vec<Target>),
 
+            /// with `delta` files we have an older copy of are sent as the differences to it
             Fetch {
                 host: AnyHost,
                 target: Target,
+                #[serde(default)]
+                delta: bool,
+                /// transfers with a higher priority leave the queue first
+                #[serde(default)]
+                priority: i32,
             },
+            /// with `delta` files the host has an older copy of are sent as the differences to it
             Push {
                 host: AnyHost,
                 target: Target,
+                #[serde(default)]
+                delta: bool,
+                #[serde(default)]
+                priority: i32,
+            },
+            /// make a directory we host and one the host hosts the same, changes on either side
+            /// go to the other one, the host has to give us read and write rights on its
+            /// directory
+            Sync {
+                host: AnyHost,
+                dir: PathBuf,
+                /// the directory on the host, the same path as `dir` when unset
+                remote: Option<PathBuf>,
+                /// how files both sides changed are resolved, the policy of the config when unset
+                policy: Option<ConflictPolicy>,
+            },
+            /// list the pushes of other peers that wait for approval
+            Pending,
+            /// move the files of a pending push into the inbox of the peer
+            Accept(PushId),
+            /// throw away a pending push
+            Reject(PushId),
+            /// back up every hosted file matching the target as a new snapshot of the job, the
+            /// snapshot is stored locally when no host is given
+            Backup {
+                job: String,
+                host: Option<AnyHost>,
+                target: Target,
+                /// read every file, also those the index says did not change
+                #[serde(default)]
+                full: bool,
+                #[serde(default)]
+                priority: i32,
+            },
+            /// list the snapshots stored locally or on a host, optionally only those of one job
+            Snapshots {
+                host: Option<AnyHost>,
+                job: Option<String>,
+            },
+            /// restore the files of a snapshot below any of `paths`, or every file if empty
+            ///
+            /// files are restored to their original path unless `to` is given, then they are
+            /// restored below that directory
+            Restore {
+                host: Option<AnyHost>,
+                snapshot: SnapshotId,
+                paths: Vec<PathBuf>,
+                to: Option<PathBuf>,
+            },
+            /// list what changed between two snapshots
+            Diff {
+                host: Option<AnyHost>,
+                from: SnapshotId,
+                to: SnapshotId,
+            },
+            /// remove the snapshots the retention rules of a job no longer keep, every job with
+            /// rules when no job is given, and reclaim the chunks that are no longer used
+            Prune {
+                host: Option<AnyHost>,
+                job: Option<String>,
+                dry_run: bool,
+            },
+            /// check that every chunk the snapshots of the local store, or those we stored on a
+            /// host, refer to is there, and with `full` that it is intact
+            Verify {
+                host: Option<AnyHost>,
+                full: bool,
+            },
+            /// show a snapshot or the files of a host as a read only filesystem, file data is
+            /// only fetched once it is read
+            Mount {
+                source: MountSource,
+                mountpoint: PathBuf,
+            },
+            /// take away a filesystem made with mount
+            Unmount(PathBuf),
+            /// change the rate limits in bytes per second of a peer, or of all peers together
+            /// when no host is given, until the daemon restarts, a limit of 0 removes it
+            Throttle {
+                host: Option<AnyHost>,
+                upload: Option<u64>,
+                download: Option<u64>,
+                /// go back to the limits of the config instead
+                #[serde(default)]
+                reset: bool,
+            },
+            /// list the fetches, pushes and backups in the transfer queue
+            Jobs,
+            /// take a transfer out of the queue, stopping it when it runs
+            Cancel(TransferId),
+            /// keep a transfer in the queue without running it, a running transfer is stopped
+            /// and starts over once it is resumed
+            Pause(TransferId),
+            /// let a paused transfer run again, a transfer that waits for a retry runs right away
+            Resume(TransferId),
+            /// list the entries of the audit log matching the filter, with `follow` new entries
+            /// keep coming until the client goes away
+            Log {
+                filter: LogFilter,
+                #[serde(default)]
+                follow: bool,
             },
-            // Backup
             ServerStatus(Option<AnyHost>),
+            /// list the hosted files of the local or a remote host
+            FileList(Option<AnyHost>),
         }
         #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
         pub struct Backit {
@@ -171,16 +423,89 @@ pub mod ipc {
             pub fn no_confirm(&self) -> bool {
                 self.no_confirm
             }
+            pub fn json(&self) -> bool {
+                self.json
+            }
             pub fn command(&self) -> &Command {
                 &self.command
             }
         }
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
+    /// id picked by the client for every command, all replies to that command carry the same id
+    ///
+    /// ids only have to be unique per connection, id 0 is reserved for replies that can not be
+    /// matched to a command, like a packet that failed to decode
+    pub type RequestId = u64;
+
+    /// a push another peer sent us, unique per daemon
+    pub type PushId = u64;
+
+    /// a fetch, push or backup in the transfer queue of a daemon, unique per daemon
+    pub type TransferId = u64;
+
+    /// a packet tagged with the [`RequestId`] of the command it belongs to
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Tagged<T> {
+        id: RequestId,
+        body: T,
+    }
+    impl<T> Tagged<T> {
+        pub fn new(id: RequestId, body: T) -> Self {
+            Self { id, body }
+        }
+        pub fn id(&self) -> RequestId {
+            self.id
+        }
+        pub fn body(&self) -> &T {
+            &self.body
+        }
+        pub fn into_body(self) -> T {
+            self.body
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub enum ServerError {
         InvalidPacket,
         NotImplemented,
+        /// the command was sent by a newer client and is not known by this daemon
+        UnsupportedCommand,
+        /// the user on the other side of the socket is not allowed to command this daemon
+        PermissionDenied,
+        /// the nickname or id does not belong to a known host
+        UnknownHost(String),
+        /// talking to a remote host failed
+        Peer(String),
+        /// a local file operation failed
+        Io(String),
+        /// there is no snapshot with this id, or it belongs to someone else
+        UnknownSnapshot(SnapshotId),
+        /// a chunk a snapshot refers to is missing from the store
+        MissingChunk(ChunkHash),
+        /// storing this would take a peer over the quota it has on the daemon
+        QuotaExceeded {
+            used: u64,
+            quota: u64,
+        },
+        /// the disk of the daemon has no room left above its reserve
+        DiskFull {
+            free: u64,
+            reserve: u64,
+        },
+        /// there is no push with this id, or it was sent by someone else
+        UnknownPush(PushId),
+        /// a synced file changed while it was being synced, the next sync picks it up
+        Changed(PathBuf),
+        /// there is no transfer with this id in the queue
+        UnknownTransfer(TransferId),
+        /// the transfer was cancelled before it finished
+        Cancelled,
+        /// the pre or post command of a backup job failed and aborted the backup
+        ScriptFailed {
+            job: String,
+            error: String,
+        },
     }
 
     #[derive(Serialize, Deserialize, Debug)]
@@ -195,30 +520,412 @@ pub mod ipc {
         HostFile(FileTarget),
         UnHostFile,
 
-        Fetched(Vec<u8>),
-        Backuped,
+        /// the files that were fetched, where they were written to
+        Fetched(Vec<PathBuf>),
+        /// how many hosted files got their rights changed
+        Shared(usize),
+        Pushed(PushStatus),
+        Pending(Vec<PendingPush>),
+        /// where the files of an accepted push ended up
+        Accepted(Vec<PathBuf>),
+        Rejected,
+        Synced(SyncReport),
+        Backuped(SnapshotInfo),
+        Snapshots(Vec<SnapshotInfo>),
+        Restored {
+            files: usize,
+            bytes: u64,
+        },
+        Diff(SnapshotDiff),
+        Pruned(PruneReport),
+        Verified(VerifyReport),
+        /// where the filesystem was mounted
+        Mounted(PathBuf),
+        Unmounted,
+        Throttled,
+        Jobs(Vec<TransferInfo>),
+        Cancelled,
+        Paused,
+        Resumed,
+        Log(Vec<AuditEntry>),
+        /// an entry of a log that is followed, these keep coming until the client goes away
+        LogEntry(AuditEntry),
 
         Info(ServerInfo),
-        //FileList(FileInfo),
+        FileList(Vec<FileInfo>),
         Error(ServerError),
+
+        /// sent while a long running command is busy, always followed by a final reply
+        Progress(Progress),
+    }
+    impl ServerReply {
+        /// true if this is the last reply for a command, the client can forget the
+        /// [`RequestId`] once it got it
+        pub fn is_final(&self) -> bool {
+            !matches!(self, Self::Progress(_))
+        }
+    }
+
+    /// how far a transfer or backup is, totals are `None` while they are still being counted
+    #[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+    pub struct Progress {
+        pub bytes_done: u64,
+        pub bytes_total: Option<u64>,
+        pub files_done: u64,
+        pub files_total: Option<u64>,
+        /// average rate since the start of the transfer in bytes per second
+        pub bytes_per_sec: u64,
+        /// estimated seconds until the transfer finishes, `None` when the total is unknown
+        pub eta_secs: Option<u64>,
+        /// the file that is currently being processed
+        #[serde(default)]
+        pub current: Option<String>,
+        /// set in the first reply to a queued transfer, to pause or cancel it with
+        #[serde(default)]
+        pub transfer: Option<TransferId>,
     }
     #[derive(Debug, Serialize, Deserialize, Clone)]
     pub struct ServerInfo {
         active: bool,
         file_count: usize,
+        /// the result of the last background scrub of the store
+        #[serde(default)]
+        last_scrub: Option<VerifyReport>,
+        /// what connected hosts store here and what we store on them, only shown to local users
+        #[serde(default)]
+        storage: Vec<HostStorage>,
     }
     impl ServerInfo {
-        pub fn new(active: bool, file_count: usize) -> Self {
-            Self { active, file_count }
+        pub fn new(active: bool, file_count: usize, last_scrub: Option<VerifyReport>) -> Self {
+            Self {
+                active,
+                file_count,
+                last_scrub,
+                storage: Vec::new(),
+            }
+        }
+        pub fn with_storage(mut self, storage: Vec<HostStorage>) -> Self {
+            self.storage = storage;
+            self
         }
     }
+
+    /// what happened to a push once all of its data arrived
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+    pub enum PushStatus {
+        /// the files are in the inbox of the receiver
+        Accepted,
+        /// the receiver has to approve the push first
+        Pending(PushId),
+    }
+
+    /// a push that waits for approval, as shown by `pending`
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+    pub struct PendingPush {
+        pub id: PushId,
+        pub host: HostId,
+        pub time: SystemTime,
+        pub files: Vec<FileInfo>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+    pub enum TransferState {
+        /// waits for a free slot
+        Queued,
+        Running,
+        Paused,
+        /// failed and is tried again at this time
+        Retrying(SystemTime),
+    }
+
+    /// who did something the audit log records
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Actor {
+        /// a user of this machine, by uid
+        Local(u32),
+        /// another daemon, by peer id
+        Peer(String),
+        /// the daemon by itself, like an automatic backup
+        Daemon,
+    }
+    impl Actor {
+        /// see [`LogFilter::actor`]
+        pub fn matches(&self, x: &str) -> bool {
+            match self {
+                Self::Local(uid) => x == "local" || x == uid.to_string(),
+                Self::Peer(peer) => x == peer,
+                Self::Daemon => x == "daemon",
+            }
+        }
+    }
+    impl Display for Actor {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Local(uid) => write!(f, "uid {uid}"),
+                Self::Peer(peer) => f.write_str(peer),
+                Self::Daemon => f.write_str("daemon"),
+            }
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Outcome {
+        Success,
+        Failure(String),
+    }
+
+    /// an entry of the audit log, ids go up in the order entries were made
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+    pub struct AuditEntry {
+        pub id: u64,
+        pub time: SystemTime,
+        pub actor: Actor,
+        pub action: String,
+        pub target: Option<String>,
+        pub result: Outcome,
+        /// what a command run by the daemon printed
+        #[serde(default)]
+        pub output: Option<String>,
+    }
+    impl LogFilter {
+        pub fn matches(&self, entry: &AuditEntry) -> bool {
+            if matches!(&self.actor, Some(x) if !entry.actor.matches(x)) {
+                return false;
+            }
+            if matches!(&self.action, Some(x) if *x != entry.action) {
+                return false;
+            }
+            let target = entry.target.as_deref().unwrap_or_default();
+            if matches!(&self.target, Some(x) if !target.contains(x.as_str())) {
+                return false;
+            }
+            if matches!(self.since, Some(x) if entry.time < x) {
+                return false;
+            }
+            !self.failed || matches!(entry.result, Outcome::Failure(_))
+        }
+    }
+
+    /// a transfer in the queue, as shown by `jobs`
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+    pub struct TransferInfo {
+        pub id: TransferId,
+        pub command: Command,
+        pub priority: i32,
+        pub state: TransferState,
+        pub added: SystemTime,
+        /// how often it failed so far
+        pub attempts: u32,
+        /// why the last attempt failed
+        pub error: Option<String>,
+    }
+
+    /// how much one daemon stores on behalf of a peer
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct StorageUsage {
+        pub used: u64,
+        /// no limit when unset
+        pub quota: Option<u64>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+    pub struct HostStorage {
+        pub host: HostId,
+        /// what the host stores on this daemon
+        pub here: StorageUsage,
+        /// what this daemon stores on the host, unset when the host could not be asked
+        pub there: Option<StorageUsage>,
+    }
+
+    /// a file hosted by a daemon, as shown by `filelist`
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+    pub struct FileInfo {
+        pub path: PathBuf,
+        pub nickname: Option<String>,
+        pub tags: Vec<String>,
+        pub size: u64,
+        pub mtime: Option<SystemTime>,
+        /// who the file is shared with, only shown to local users
+        #[serde(default)]
+        pub shares: Vec<Share>,
+        /// only filled in when the file is transferred, so the copy gets the same metadata
+        #[serde(default)]
+        pub meta: FileMeta,
+        /// only filled in when the file is transferred, symlinks and further names of a file are
+        /// made again on the other side instead of sending what they point to
+        #[serde(default)]
+        pub kind: EntryKind,
+    }
 }
 
+/// the packets backit daemons send each other over the p2p request response protocol
 pub mod tcp {
+    use std::{collections::BTreeMap, path::PathBuf};
+
     use serde::{Deserialize, Serialize};
 
-    #[derive(Serialize, Deserialize, Debug)]
-    pub enum SendPacket {}
-    #[derive(Serialize, Deserialize, Debug)]
-    pub enum ReceivePacket {}
+    use crate::{
+        delta::{DeltaOp, Signature},
+        ipc::{
+            to_server::Target, FileInfo, PushId, PushStatus, ServerError, ServerInfo, StorageUsage,
+        },
+        snapshot::{ChunkHash, GcStats, SnapshotId, SnapshotInfo, VerifyReport},
+        sync::FileVersion,
+    };
+
+    /// id of a delta fetch, see [`SendPacket::StartDelta`]
+    pub type DeltaId = u64;
+
+    /// a request sent to a remote daemon
+    ///
+    /// the snapshot requests only ever see the snapshots the requesting peer stored itself
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum SendPacket {
+        Info,
+        /// the hosted files the peer may list
+        FileList,
+        /// the hosted files matching a target that the peer may read
+        FetchList(Target),
+        /// read part of a hosted file
+        ReadFile {
+            path: PathBuf,
+            offset: u64,
+            len: u64,
+        },
+        /// announce a push of these files, answered with the id to send their data under
+        StartPush(Vec<FileInfo>),
+        /// part of the file at `index` in the list the push was started with
+        PushData {
+            id: PushId,
+            index: usize,
+            offset: u64,
+            #[serde(with = "serde_bytes")]
+            data: Vec<u8>,
+        },
+        /// every file of the push was sent
+        FinishPush(PushId),
+        /// start fetching a hosted file as a delta to our copy with this signature, answered
+        /// with the id to ask for the ops under
+        StartDelta {
+            path: PathBuf,
+            signature: Signature,
+        },
+        /// the ops for the file from `offset` on, they cover as much as fits in a packet
+        ReadDelta {
+            id: DeltaId,
+            offset: u64,
+        },
+        FinishDelta(DeltaId),
+        /// the signature of the copy the peer has of the file at `index` of a push
+        PushSignature {
+            id: PushId,
+            index: usize,
+        },
+        /// part of the file at `index` of a push as ops on the copy the peer has, starting at
+        /// `offset`
+        PushDelta {
+            id: PushId,
+            index: usize,
+            offset: u64,
+            block_size: u32,
+            ops: Vec<DeltaOp>,
+        },
+        /// the versions of the files in a hosted directory we sync with, paths in the sync
+        /// packets are relative to that directory
+        SyncIndex(PathBuf),
+        /// read part of a file of a synced directory
+        SyncRead {
+            dir: PathBuf,
+            path: PathBuf,
+            offset: u64,
+            len: u64,
+        },
+        /// part of a new version of a file of a synced directory, a write at offset 0 starts over
+        SyncWrite {
+            dir: PathBuf,
+            path: PathBuf,
+            offset: u64,
+            #[serde(with = "serde_bytes")]
+            data: Vec<u8>,
+        },
+        /// take this version of a file, with the content that was written or by removing it
+        ///
+        /// a version with the content the peer already has only updates its version vector
+        SyncCommit {
+            dir: PathBuf,
+            path: PathBuf,
+            version: FileVersion,
+        },
+
+        /// ask which of these chunks the peer does not have yet
+        HasChunks(Vec<ChunkHash>),
+        PutChunk {
+            hash: ChunkHash,
+            #[serde(with = "serde_bytes")]
+            data: Vec<u8>,
+        },
+        GetChunk(ChunkHash),
+        /// part of the cbor encoded manifest of a snapshot, a manifest can be larger than a
+        /// packet so it is sent in order from offset 0 and stored once all `total` bytes are in
+        PutSnapshot {
+            id: SnapshotId,
+            offset: u64,
+            total: u64,
+            #[serde(with = "serde_bytes")]
+            data: Vec<u8>,
+        },
+        Snapshots {
+            job: Option<String>,
+        },
+        /// the cbor encoded manifest of a snapshot from `offset` on
+        GetSnapshot {
+            id: SnapshotId,
+            offset: u64,
+        },
+        /// remove snapshots and collect the chunks nothing refers to anymore
+        ForgetSnapshots {
+            ids: Vec<SnapshotId>,
+            dry_run: bool,
+        },
+        /// check the snapshots we pushed and the chunks they refer to
+        Verify {
+            full: bool,
+        },
+        /// how much we store on the peer
+        Usage,
+    }
+    /// the answer of a remote daemon to a [`SendPacket`]
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum ReceivePacket {
+        Info(ServerInfo),
+        FileList(Vec<FileInfo>),
+        Error(ServerError),
+
+        Ok,
+        MissingChunks(Vec<ChunkHash>),
+        Chunk(#[serde(with = "serde_bytes")] Vec<u8>),
+        Snapshots(Vec<SnapshotInfo>),
+        /// part of an encoded manifest, `total` is the size of all of it
+        SnapshotPart {
+            total: u64,
+            #[serde(with = "serde_bytes")]
+            data: Vec<u8>,
+        },
+        Collected(GcStats),
+        Verified(VerifyReport),
+        Usage(StorageUsage),
+        /// part of a hosted file, shorter than asked at the end of the file
+        FileData(#[serde(with = "serde_bytes")] Vec<u8>),
+        PushStarted(PushId),
+        PushFinished(PushStatus),
+        DeltaStarted(DeltaId),
+        /// ops for part of a file, empty at the end of the file
+        Delta(Vec<DeltaOp>),
+        /// unset when there is no copy to make a delta to
+        Signature(Option<Signature>),
+        /// by path relative to the synced directory, removed files are in there as well
+        SyncIndex(BTreeMap<PathBuf, FileVersion>),
+    }
 }
@@ -0,0 +1,295 @@
+//! the format backups are stored in
+//!
+//! files are cut into content defined chunks that are stored once by their hash, a snapshot is
+//! an immutable manifest that lists which chunks make up every file at the time of the backup
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use uuid::Uuid;
+
+/// blake3 hash of a chunk, chunks are stored and fetched by this
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ChunkHash([u8; 32]);
+impl ChunkHash {
+    pub fn of(data: &[u8]) -> Self {
+        Self(*blake3::hash(data).as_bytes())
+    }
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+impl Display for ChunkHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for x in &self.0 {
+            write!(f, "{x:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SnapshotId(Uuid);
+impl SnapshotId {
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        self.0.as_bytes()
+    }
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(Uuid::from_bytes(bytes))
+    }
+}
+impl Default for SnapshotId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Display for SnapshotId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl FromStr for SnapshotId {
+    type Err = uuid::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+/// posix metadata of a file, every field is optional so snapshots made on other platforms or
+/// before it was recorded still restore
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileMeta {
+    /// permission bits, including setuid, setgid and sticky
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// names of the owner and group, these are used before the ids when they exist on restore
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub atime: Option<SystemTime>,
+    /// extended attributes by name, posix acls are kept here as `system.posix_acl_access` and
+    /// `system.posix_acl_default`
+    pub xattrs: BTreeMap<String, ByteBuf>,
+}
+
+/// what an entry of a snapshot is
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    /// a regular file, made of the chunks of its entry
+    #[default]
+    File,
+    /// a symlink to this target, it has no chunks
+    Symlink(PathBuf),
+    /// another name of the file at this path in the same snapshot, it has no chunks
+    Hardlink(PathBuf),
+}
+
+/// a single file in a snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: Option<SystemTime>,
+    /// the chunks that make up the file, in order
+    pub chunks: Vec<ChunkHash>,
+    #[serde(default)]
+    pub kind: EntryKind,
+    #[serde(default)]
+    pub meta: FileMeta,
+    /// the file had holes, chunks of zeros are not written on restore so it stays sparse
+    #[serde(default)]
+    pub sparse: bool,
+}
+
+/// everything that was backed up by a single run of a backup job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: SnapshotId,
+    pub job: String,
+    pub time: SystemTime,
+    pub files: Vec<ManifestEntry>,
+    /// set when this is the copy of a snapshot that a peer of a set keeps, the store holds these
+    /// pieces of chunks and not necessarily the chunks of `files`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pieces: Option<Vec<ChunkHash>>,
+    /// set on the copies of a snapshot the peers of a set keep, where the pieces of every chunk
+    /// of `files` are so the set can be read without the database of the daemon that wrote it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub placements: Option<Vec<(ChunkHash, Placement)>>,
+}
+impl Snapshot {
+    pub fn new(job: String, files: Vec<ManifestEntry>) -> Self {
+        Self {
+            id: SnapshotId::new(),
+            job,
+            time: SystemTime::now(),
+            files,
+            pieces: None,
+            placements: None,
+        }
+    }
+    /// every chunk and piece this snapshot keeps alive in the store it is in
+    pub fn chunks(&self) -> impl Iterator<Item = &ChunkHash> {
+        self.files
+            .iter()
+            .flat_map(|x| &x.chunks)
+            .chain(self.pieces.iter().flatten())
+    }
+    pub fn info(&self) -> SnapshotInfo {
+        SnapshotInfo {
+            id: self.id,
+            job: self.job.clone(),
+            time: self.time,
+            file_count: self.files.len(),
+            size: self.files.iter().map(|x| x.size).sum(),
+            skipped: Vec::new(),
+        }
+    }
+    pub fn entry(&self, path: &Path) -> Option<&ManifestEntry> {
+        self.files.iter().find(|x| x.path == path)
+    }
+    /// the entries below any of the given paths, every entry when `paths` is empty
+    pub fn select<'a>(&'a self, paths: &'a [PathBuf]) -> impl Iterator<Item = &'a ManifestEntry> {
+        self.files
+            .iter()
+            .filter(move |x| paths.is_empty() || paths.iter().any(|p| x.path.starts_with(p)))
+    }
+    /// what changed between an older snapshot and this one
+    pub fn diff(&self, older: &Snapshot) -> SnapshotDiff {
+        let old: BTreeMap<&Path, &ManifestEntry> =
+            older.files.iter().map(|x| (x.path.as_path(), x)).collect();
+        let new: BTreeMap<&Path, &ManifestEntry> =
+            self.files.iter().map(|x| (x.path.as_path(), x)).collect();
+        let mut diff = SnapshotDiff::default();
+        for (path, entry) in &new {
+            match old.get(path) {
+                None => diff.added.push(path.to_path_buf()),
+                Some(x) if x.chunks != entry.chunks || x.kind != entry.kind => {
+                    diff.changed.push(path.to_path_buf())
+                }
+                Some(_) => {}
+            }
+        }
+        diff.removed = old
+            .keys()
+            .filter(|x| !new.contains_key(*x))
+            .map(|x| x.to_path_buf())
+            .collect();
+        diff
+    }
+}
+
+/// where the pieces of a chunk stored on a set of peers are
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Placement {
+    /// length of the chunk, shards are padded to the same length
+    pub len: u64,
+    /// the pieces with the peers holding them, erasure coded shards are in shard order with the
+    /// data shards first
+    pub pieces: Vec<(ChunkHash, Vec<String>)>,
+    /// number of data shards, 0 for a replicated chunk
+    pub data_shards: usize,
+}
+impl Placement {
+    pub fn holds(&self, peer: &str) -> bool {
+        self.pieces.iter().any(|(_, x)| x.iter().any(|x| x == peer))
+    }
+    /// whether the pieces fit the redundancy, a placement can come from a peer
+    pub fn is_valid(&self) -> bool {
+        match self.data_shards {
+            0 => self.pieces.len() == 1,
+            data => data < self.pieces.len(),
+        }
+    }
+}
+
+/// a snapshot without its file list, as shown by `snapshots`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub id: SnapshotId,
+    pub job: String,
+    pub time: SystemTime,
+    pub file_count: usize,
+    /// total size of the files, not of the stored chunks
+    pub size: u64,
+    /// files that were gone by the time they were read, only set in the reply to a backup
+    #[serde(default)]
+    pub skipped: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotDiff {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub changed: Vec<PathBuf>,
+}
+
+/// what a garbage collection of a chunk store removed, or would remove on a dry run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GcStats {
+    pub chunks_removed: u64,
+    pub bytes_freed: u64,
+    /// a peer collects in the background, what it frees is not known yet and not counted
+    #[serde(default)]
+    pub deferred: bool,
+}
+
+/// the result of applying the retention rules of one or more jobs
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PruneReport {
+    pub dry_run: bool,
+    pub kept: Vec<SnapshotInfo>,
+    pub removed: Vec<SnapshotInfo>,
+    pub gc: GcStats,
+}
+
+/// the result of checking stored snapshots against the chunks they refer to
+///
+/// a quick check only looks at which chunks exist, a full check reads every chunk back, checks
+/// its hash and checks that the chunks of a file add up to its size
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifyReport {
+    pub full: bool,
+    /// when the check finished
+    pub time: SystemTime,
+    pub snapshots: usize,
+    /// unique chunks that were checked
+    pub chunks: u64,
+    /// bytes that were read back, 0 for a quick check
+    pub bytes: u64,
+    pub missing: Vec<ChunkHash>,
+    pub corrupt: Vec<ChunkHash>,
+    /// snapshots that can not be restored completely
+    pub damaged: Vec<SnapshotId>,
+}
+impl VerifyReport {
+    pub fn new(full: bool) -> Self {
+        Self {
+            full,
+            time: SystemTime::now(),
+            snapshots: 0,
+            chunks: 0,
+            bytes: 0,
+            missing: Vec::new(),
+            corrupt: Vec::new(),
+            damaged: Vec::new(),
+        }
+    }
+    pub fn is_ok(&self) -> bool {
+        self.damaged.is_empty()
+    }
+}
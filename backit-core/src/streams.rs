@@ -1,54 +1,382 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use bytes::Bytes;
 pub use futures_util::{SinkExt, StreamExt};
 pub use interprocess::local_socket::tokio::prelude::*;
-use interprocess::local_socket::{
-    tokio::Listener, traits::tokio::Stream, GenericFilePath, GenericNamespaced, Name, NameType,
-    ToFsName, ToNsName,
-};
-use serde::{Deserialize, Serialize};
+use interprocess::local_socket::{tokio::Listener, traits::tokio::Stream, Name};
+#[cfg(unix)]
+use interprocess::local_socket::{GenericFilePath, ToFsName};
+#[cfg(not(unix))]
+use interprocess::local_socket::{GenericNamespaced, ToNsName};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
 use tokio::io::{AsyncRead, AsyncWrite};
 pub use tokio_serde::Framed;
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use uuid::Uuid;
 
-use crate::ipc::{from_client::*, ServerReply};
+use crate::ipc::{from_client::*, ServerReply, Tagged};
 
 type UserCommand = Backit;
 
+/// version of the ipc protocol spoken by this build
+///
+/// bump this whenever a change to [`crate::ipc`] can not be decoded by an older build,
+/// adding a new variant or an optional field does not need a bump
+pub const PROTOCOL_VERSION: u32 = 4;
+/// the oldest protocol version this build is still able to talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+
+/// first frame sent by both sides of an ipc connection, before any [`Backit`] or [`ServerReply`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    version: u32,
+    min_version: u32,
+    /// optional features this side supports, these are plain strings so a newer build can
+    /// announce capabilities an older build does not know about without breaking the handshake
+    #[serde(default)]
+    capabilities: BTreeSet<String>,
+    /// version of the crate that built the binary, only used in error messages
+    #[serde(default)]
+    build: String,
+}
+impl Hello {
+    pub fn new() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: BTreeSet::new(),
+            build: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+    pub fn with_capability(mut self, capability: &str) -> Self {
+        self.capabilities.insert(capability.to_string());
+        self
+    }
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    pub fn build(&self) -> &str {
+        &self.build
+    }
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+    /// pick the protocol version both sides understand
+    pub fn negotiate(&self, theirs: &Hello) -> Result<Negotiated, HandshakeError> {
+        let version = self.version.min(theirs.version);
+        if version < self.min_version || version < theirs.min_version {
+            return Err(HandshakeError::Incompatible {
+                ours: self.clone(),
+                theirs: theirs.clone(),
+            });
+        }
+        Ok(Negotiated {
+            version,
+            capabilities: self
+                .capabilities
+                .intersection(&theirs.capabilities)
+                .cloned()
+                .collect(),
+            peer: theirs.clone(),
+        })
+    }
+}
+impl Default for Hello {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// the result of a successful handshake
+#[derive(Debug, Clone)]
+pub struct Negotiated {
+    version: u32,
+    capabilities: BTreeSet<String>,
+    peer: Hello,
+}
+impl Negotiated {
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    /// true if both sides announced the capability
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+    pub fn peer(&self) -> &Hello {
+        &self.peer
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("ipc connection failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not decode the handshake, the other side is probably a backit build from before protocol versioning: {0}")]
+    Decode(#[from] serde_cbor::Error),
+    #[error("connection closed during the handshake")]
+    Closed,
+    #[error("{}", incompatible_message(.ours, .theirs))]
+    Incompatible { ours: Hello, theirs: Hello },
+}
+
+fn incompatible_message(ours: &Hello, theirs: &Hello) -> String {
+    let upgrade = if ours.version < theirs.version {
+        "this side"
+    } else {
+        "the other side"
+    };
+    format!(
+        "incompatible ipc protocol: this side ({}) speaks v{} and accepts v{}..=v{}, the other side ({}) speaks v{} and accepts v{}..=v{}; upgrade {} so both use the same backit version",
+        ours.build,
+        ours.version,
+        ours.min_version,
+        ours.version,
+        theirs.build,
+        theirs.version,
+        theirs.min_version,
+        theirs.version,
+        upgrade,
+    )
+}
+
+/// an ipc packet that also decodes when the other side sends something this build does not know
+///
+/// all enums in [`crate::ipc`] are encoded with their variant name, so a packet only fails to
+/// decode when the other side uses a variant that was added after this build
+#[derive(Debug, Clone)]
+pub enum Compat<T> {
+    Known(T),
+    /// the packet was valid cbor but does not match any known variant, the description holds the
+    /// decode error
+    Unknown(String),
+}
+impl<T> Compat<T> {
+    pub fn known(self) -> Option<T> {
+        match self {
+            Self::Known(x) => Some(x),
+            Self::Unknown(_) => None,
+        }
+    }
+}
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Compat<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_cbor::Value::deserialize(deserializer)?;
+        Ok(match serde_cbor::value::from_value(value) {
+            Ok(x) => Self::Known(x),
+            Err(e) => Self::Unknown(e.to_string()),
+        })
+    }
+}
+
+type RawCodec<T> = tokio_util::codec::Framed<T, LengthDelimitedCodec>;
+
 pub type ClientCodec<T> = Framed<
-    tokio_util::codec::Framed<T, LengthDelimitedCodec>,
-    ServerReply,
-    UserCommand,
-    tokio_serde::formats::Cbor<ServerReply, UserCommand>,
+    RawCodec<T>,
+    Tagged<Compat<ServerReply>>,
+    Tagged<UserCommand>,
+    tokio_serde::formats::Cbor<Tagged<Compat<ServerReply>>, Tagged<UserCommand>>,
 >;
 pub type ServerCodec<T> = Framed<
-    tokio_util::codec::Framed<T, LengthDelimitedCodec>,
-    UserCommand,
-    ServerReply,
-    tokio_serde::formats::Cbor<UserCommand, ServerReply>,
+    RawCodec<T>,
+    Tagged<Compat<UserCommand>>,
+    Tagged<ServerReply>,
+    tokio_serde::formats::Cbor<Tagged<Compat<UserCommand>>, Tagged<ServerReply>>,
 >;
 
+fn raw_codec<T: AsyncRead + AsyncWrite>(socket: T) -> RawCodec<T> {
+    tokio_util::codec::Framed::new(socket, LengthDelimitedCodec::new())
+}
+
+/// wrap a socket without doing a handshake, only use this if the handshake already happened
 pub fn client_codec<T: AsyncRead + AsyncWrite>(socket: T) -> ClientCodec<T> {
-    let a = tokio_util::codec::Framed::new(socket, LengthDelimitedCodec::new());
-    tokio_serde::Framed::new(a, tokio_serde::formats::Cbor::default())
+    tokio_serde::Framed::new(raw_codec(socket), tokio_serde::formats::Cbor::default())
 }
+/// wrap a socket without doing a handshake, only use this if the handshake already happened
 pub fn server_codec<T: AsyncRead + AsyncWrite>(socket: T) -> ServerCodec<T> {
-    let a = tokio_util::codec::Framed::new(socket, LengthDelimitedCodec::new());
-    tokio_serde::Framed::new(a, tokio_serde::formats::Cbor::default())
+    tokio_serde::Framed::new(raw_codec(socket), tokio_serde::formats::Cbor::default())
+}
+
+async fn send_hello<T: AsyncRead + AsyncWrite + Unpin>(
+    raw: &mut RawCodec<T>,
+    hello: &Hello,
+) -> Result<(), HandshakeError> {
+    raw.send(Bytes::from(serde_cbor::to_vec(hello)?)).await?;
+    Ok(())
+}
+async fn receive_hello<T: AsyncRead + AsyncWrite + Unpin>(
+    raw: &mut RawCodec<T>,
+) -> Result<Hello, HandshakeError> {
+    let frame = raw.next().await.ok_or(HandshakeError::Closed)??;
+    Ok(serde_cbor::from_slice(&frame)?)
+}
+
+/// send our [`Hello`] and wait for the one of the daemon
+///
+/// fails with [`HandshakeError::Incompatible`] if the daemon speaks a protocol version we can not
+/// talk to
+pub async fn client_handshake<T: AsyncRead + AsyncWrite + Unpin>(
+    socket: T,
+    hello: Hello,
+) -> Result<(ClientCodec<T>, Negotiated), HandshakeError> {
+    let mut raw = raw_codec(socket);
+    send_hello(&mut raw, &hello).await?;
+    let theirs = receive_hello(&mut raw).await?;
+    let negotiated = hello.negotiate(&theirs)?;
+    Ok((
+        tokio_serde::Framed::new(raw, tokio_serde::formats::Cbor::default()),
+        negotiated,
+    ))
+}
+
+/// wait for the [`Hello`] of a client and answer with our own
+///
+/// our hello is always sent, even when the versions are incompatible, so the client can report
+/// why the connection was refused
+pub async fn server_handshake<T: AsyncRead + AsyncWrite + Unpin>(
+    socket: T,
+    hello: Hello,
+) -> Result<(ServerCodec<T>, Negotiated), HandshakeError> {
+    let mut raw = raw_codec(socket);
+    let theirs = receive_hello(&mut raw).await?;
+    send_hello(&mut raw, &hello).await?;
+    let negotiated = hello.negotiate(&theirs)?;
+    Ok((
+        tokio_serde::Framed::new(raw, tokio_serde::formats::Cbor::default()),
+        negotiated,
+    ))
+}
+
+/// environment variable that overrides the path of the ipc socket, set this to talk to the daemon
+/// of another user that allowed us in its config
+pub const SOCKET_ENV: &str = "BACKIT_SOCKET";
+
+/// path of the ipc socket of the current user
+///
+/// this is `$BACKIT_SOCKET` when set, otherwise `$XDG_RUNTIME_DIR/backit/backit.sock` with a
+/// fallback to `/tmp/backit-<uid>/backit.sock`
+#[cfg(unix)]
+pub fn socket_path() -> PathBuf {
+    custom_socket_path().unwrap_or_else(|| default_socket_dir().join("backit.sock"))
+}
+
+#[cfg(unix)]
+fn custom_socket_path() -> Option<PathBuf> {
+    std::env::var_os(SOCKET_ENV)
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from)
+}
+
+/// the directory of the socket when `$BACKIT_SOCKET` is not set, the daemon owns it
+#[cfg(unix)]
+fn default_socket_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR").filter(|x| !x.is_empty()) {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("backit"),
+        None => PathBuf::from(format!("/tmp/backit-{}", nix::unistd::getuid())),
+    }
 }
 
 fn ipc_name() -> std::io::Result<Name<'static>> {
-    if GenericNamespaced::is_supported() {
-        "backit.sock".to_ns_name::<GenericNamespaced>()
+    #[cfg(unix)]
+    {
+        socket_path().to_fs_name::<GenericFilePath>()
+    }
+    #[cfg(not(unix))]
+    {
+        let user = std::env::var("USERNAME").unwrap_or_default();
+        format!("backit-{user}.sock").to_ns_name::<GenericNamespaced>()
+    }
+}
+
+/// create the socket directory and remove a socket left behind by a daemon that did not shut down
+/// cleanly
+///
+/// the default directory has to be ours and not writable by anyone else, otherwise someone could
+/// have swapped the socket for their own. it is made 0700, or 0711 when the socket is `shared` so
+/// other users can reach the socket but not list or change the directory
+///
+/// the directory of a `$BACKIT_SOCKET` is left as it is, it only has to be safe: ours and not
+/// writable by others, or a sticky directory of root like `/tmp`
+#[cfg(unix)]
+fn prepare_socket_path(path: &Path, shared: bool) -> std::io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    let denied = |why: String| std::io::Error::new(std::io::ErrorKind::PermissionDenied, why);
+    let uid = nix::unistd::getuid().as_raw();
+    let custom = custom_socket_path().is_some();
+    let dir = match path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => return Err(denied(format!("{} is not a socket path", path.display()))),
+    };
+    let mode = if shared { 0o711 } else { 0o700 };
+    if !custom {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(mode)
+            .create(dir)?;
+    }
+    let meta = std::fs::symlink_metadata(dir)?;
+    let sticky = meta.uid() == 0 && meta.mode() & 0o1000 != 0;
+    let why = if !meta.is_dir() {
+        Some("is not a directory")
+    } else if custom && sticky {
+        None
+    } else if meta.uid() != uid {
+        Some("belongs to another user")
+    } else if meta.mode() & 0o022 != 0 {
+        Some("is writable by other users")
     } else {
-        "/tmp/backit.sock".to_fs_name::<GenericFilePath>()
+        None
+    };
+    if let Some(why) = why {
+        return Err(denied(format!("socket directory {} {why}", dir.display())));
+    }
+    if !custom && meta.mode() & 0o777 != mode {
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(mode))?;
+    }
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        // in a shared directory the name could have been taken by someone else
+        if meta.uid() != uid {
+            return Err(denied(format!(
+                "{} belongs to another user",
+                path.display()
+            )));
+        }
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("another backitd is already listening on {}", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                std::fs::remove_file(path)?
+            }
+            Err(e) => return Err(e),
+        }
     }
+    Ok(())
 }
 
-pub fn server() -> std::io::Result<Listener> {
-    interprocess::local_socket::ListenerOptions::new()
-        .name(ipc_name()?)
-        .create_tokio()
+/// start listening for user commands
+///
+/// the socket is only connectable by the current user unless `shared` is set, in that case anyone
+/// who can reach the socket path may connect and the daemon has to check the peer credentials of
+/// every connection itself
+pub fn server(shared: bool) -> std::io::Result<Listener> {
+    let options = interprocess::local_socket::ListenerOptions::new().name(ipc_name()?);
+    #[cfg(unix)]
+    let options = {
+        use interprocess::os::unix::local_socket::ListenerOptionsExt;
+        prepare_socket_path(&socket_path(), shared)?;
+        options.mode(if shared { 0o666 } else { 0o600 })
+    };
+    #[cfg(not(unix))]
+    let _ = shared;
+    options.create_tokio()
 }
-pub async fn client() -> std::io::Result<impl Stream> {
+pub async fn client() -> std::io::Result<crate::ipc::Stream> {
     interprocess::local_socket::tokio::Stream::connect(ipc_name()?).await
 }
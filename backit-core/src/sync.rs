@@ -0,0 +1,189 @@
+//! keeping a hosted directory the same on two peers
+//!
+//! every file has a [`VersionVector`], a counter per peer that is bumped each time that peer
+//! sees the file change. a version that has every counter at least as high as another one
+//! descends from it and replaces it, when neither does both peers changed the file since they
+//! last synced and that is a conflict, see [`ConflictPolicy`]
+
+use std::{
+    cmp::Ordering, collections::BTreeMap, fmt::Display, path::PathBuf, str::FromStr,
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::snapshot::ChunkHash;
+
+/// how two versions of a file relate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Equal,
+    /// the first version is older, the second one descends from it
+    Before,
+    /// the first version descends from the second one
+    After,
+    /// both changed since the version they have in common
+    Concurrent,
+}
+
+/// change counters of a file by peer id
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionVector(BTreeMap<String, u64>);
+impl VersionVector {
+    pub fn get(&self, peer: &str) -> u64 {
+        self.0.get(peer).copied().unwrap_or(0)
+    }
+    /// count a change made by a peer
+    pub fn bump(&mut self, peer: &str) {
+        *self.0.entry(peer.to_string()).or_default() += 1;
+    }
+    /// the version that descends from both
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut out = self.clone();
+        for (peer, x) in &other.0 {
+            let counter = out.0.entry(peer.clone()).or_default();
+            *counter = (*counter).max(*x);
+        }
+        out
+    }
+    pub fn compare(&self, other: &Self) -> Causality {
+        let mut out = Causality::Equal;
+        for peer in self.0.keys().chain(other.0.keys()) {
+            let step = match self.get(peer).cmp(&other.get(peer)) {
+                Ordering::Equal => continue,
+                Ordering::Less => Causality::Before,
+                Ordering::Greater => Causality::After,
+            };
+            out = match out {
+                Causality::Equal => step,
+                x if x == step => x,
+                _ => return Causality::Concurrent,
+            };
+        }
+        out
+    }
+}
+
+/// a file of a synced directory as one peer has it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub version: VersionVector,
+    /// blake3 hash of the content, unset once the file was removed
+    pub hash: Option<ChunkHash>,
+    pub size: u64,
+    /// for a removed file this is when the removal was noticed
+    pub mtime: Option<SystemTime>,
+}
+impl FileVersion {
+    pub fn is_removed(&self) -> bool {
+        self.hash.is_none()
+    }
+}
+
+/// what to do with a file both peers changed since they last synced
+#[derive(
+    Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// keep the version that was modified last
+    Newest,
+    /// keep ours and put the version of the peer next to it as a `.conflict` copy, a change
+    /// always wins from a removal
+    #[default]
+    KeepBoth,
+    /// keep the version of the peer that runs the sync
+    Local,
+    /// keep the version of the other peer
+    Remote,
+}
+impl FromStr for ConflictPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newest" => Ok(Self::Newest),
+            "keep-both" | "keep_both" => Ok(Self::KeepBoth),
+            "local" => Ok(Self::Local),
+            "remote" => Ok(Self::Remote),
+            _ => Err(format!(
+                "unknown policy {s}, expected newest, keep-both, local or remote"
+            )),
+        }
+    }
+}
+impl Display for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Newest => "newest",
+            Self::KeepBoth => "keep-both",
+            Self::Local => "local",
+            Self::Remote => "remote",
+        })
+    }
+}
+
+/// what a sync changed, paths are relative to the synced directory
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncReport {
+    /// files we got from the peer
+    pub pulled: Vec<PathBuf>,
+    /// files we sent to the peer
+    pub pushed: Vec<PathBuf>,
+    /// files we removed because the peer removed them
+    pub removed_here: Vec<PathBuf>,
+    /// files the peer removed because we removed them
+    pub removed_there: Vec<PathBuf>,
+    /// files both peers changed, they were resolved with the policy unless one of them changed
+    /// again during the sync, then they are left for the next sync
+    pub conflicts: Vec<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(counters: &[(&str, u64)]) -> VersionVector {
+        VersionVector(
+            counters
+                .iter()
+                .map(|(peer, x)| (peer.to_string(), *x))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn compare() {
+        let a = version(&[("a", 1), ("b", 2)]);
+        assert_eq!(a.compare(&a), Causality::Equal);
+        // a missing counter is zero
+        assert_eq!(
+            a.compare(&version(&[("a", 1), ("b", 2), ("c", 0)])),
+            Causality::Equal
+        );
+        assert_eq!(
+            a.compare(&version(&[("a", 2), ("b", 2)])),
+            Causality::Before
+        );
+        assert_eq!(
+            a.compare(&version(&[("a", 1), ("b", 2), ("c", 1)])),
+            Causality::Before
+        );
+        assert_eq!(a.compare(&version(&[("b", 1)])), Causality::After);
+        assert_eq!(VersionVector::default().compare(&a), Causality::Before);
+        assert_eq!(
+            a.compare(&version(&[("a", 2), ("b", 1)])),
+            Causality::Concurrent
+        );
+        assert_eq!(a.compare(&version(&[("c", 1)])), Causality::Concurrent);
+    }
+
+    #[test]
+    fn merge_descends_from_both() {
+        let a = version(&[("a", 3), ("b", 1)]);
+        let b = version(&[("b", 2), ("c", 1)]);
+        let merged = a.merge(&b);
+        assert_eq!(merged, version(&[("a", 3), ("b", 2), ("c", 1)]));
+        assert_eq!(a.compare(&merged), Causality::Before);
+        assert_eq!(b.compare(&merged), Causality::Before);
+    }
+}
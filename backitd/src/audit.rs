@@ -0,0 +1,260 @@
+//! the audit log, who did what to this daemon and how it went
+//!
+//! entries are appended to a tree of the db under increasing ids and never changed. commands of
+//! local users are recorded once their final reply is sent, see [`Responder::audited`], requests
+//! of peers once they are answered. reading pieces of a file is only recorded for the first
+//! piece, so a fetch shows up once
+
+use std::{collections::HashSet, time::SystemTime};
+
+use backit_core::{
+    ipc::{
+        to_server::{AnyHost, Command, Credentials, FileTarget, LogFilter, Target},
+        Actor, AuditEntry, Outcome, ServerError, ServerReply,
+    },
+    tcp::SendPacket,
+};
+use tokio::sync::broadcast;
+
+use crate::{reply::Responder, Server};
+
+/// entries a follower may lag behind before it misses some
+const FOLLOW_BUFFER: usize = 256;
+
+/// appends entries to the log and tells those who follow it
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    db: sled::Db,
+    follow: broadcast::Sender<AuditEntry>,
+}
+impl AuditLog {
+    pub fn new(db: sled::Db) -> Self {
+        Self {
+            db,
+            follow: broadcast::channel(FOLLOW_BUFFER).0,
+        }
+    }
+
+    fn tree(&self) -> sled::Result<sled::Tree> {
+        self.db.open_tree("audit")
+    }
+
+    /// add an entry, a log that can not be written is only reported
+    pub fn record(&self, actor: Actor, action: &str, target: Option<String>, result: Outcome) {
+        self.record_output(actor, action, target, result, None)
+    }
+
+    /// add an entry with what a command printed
+    pub fn record_output(
+        &self,
+        actor: Actor,
+        action: &str,
+        target: Option<String>,
+        result: Outcome,
+        output: Option<String>,
+    ) {
+        let write = || -> eyre::Result<AuditEntry> {
+            let entry = AuditEntry {
+                id: self.db.generate_id()?,
+                time: SystemTime::now(),
+                actor,
+                action: action.to_string(),
+                target,
+                result,
+                output,
+            };
+            self.tree()?
+                .insert(entry.id.to_be_bytes(), serde_cbor::to_vec(&entry)?)?;
+            Ok(entry)
+        };
+        match write() {
+            Ok(entry) => {
+                let _ = self.follow.send(entry);
+            }
+            Err(e) => eprintln!("could not write the audit log: {e}"),
+        }
+    }
+
+    /// the entries matching a filter, oldest first
+    pub fn entries(&self, filter: &LogFilter) -> Result<Vec<AuditEntry>, ServerError> {
+        let mut out = Vec::new();
+        let tree = self.tree().map_err(db_err)?;
+        for x in tree.iter().rev() {
+            let (_, bytes) = x.map_err(db_err)?;
+            let entry: AuditEntry = serde_cbor::from_slice(&bytes).map_err(db_err)?;
+            // everything before this is older
+            if matches!(filter.since, Some(since) if entry.time < since) {
+                break;
+            }
+            if filter.matches(&entry) {
+                out.push(entry);
+            }
+            if filter.limit.is_some_and(|x| out.len() >= x) {
+                break;
+            }
+        }
+        out.reverse();
+        Ok(out)
+    }
+}
+
+fn db_err(e: impl ToString) -> ServerError {
+    ServerError::Io(e.to_string())
+}
+
+/// how the final reply to a command ends up in the log
+pub fn outcome(reply: &ServerReply) -> Outcome {
+    match reply {
+        ServerReply::Error(e) => Outcome::Failure(format!("{e:?}")),
+        _ => Outcome::Success,
+    }
+}
+
+fn target(x: &Target) -> String {
+    match x {
+        Target::Nickname(x) => x.clone(),
+        Target::Tags(x) => format!("tags {}", x.join(",")),
+    }
+}
+
+pub fn host(x: &AnyHost) -> String {
+    match x {
+        AnyHost::HostId(x) => x.as_str().to_string(),
+        AnyHost::Credentials(Credentials::Key(_)) => "a key".to_string(),
+        AnyHost::Credentials(Credentials::Url(x)) => x.clone(),
+        AnyHost::Credentials(Credentials::Password { id, .. }) => id.clone(),
+    }
+}
+
+/// what the log records of a command, nothing for commands that only look
+pub fn describe(command: &Command) -> Option<(&'static str, Option<String>)> {
+    let on = |x: Option<&AnyHost>, what: String| match x {
+        Some(x) => format!("{what} on {}", host(x)),
+        None => what,
+    };
+    Some(match command {
+        Command::Start => ("start", None),
+        Command::Stop => ("stop", None),
+        Command::Reload => ("reload", None),
+        Command::Connect {
+            connection_type, ..
+        } => (
+            "connect",
+            Some(host(&AnyHost::Credentials(connection_type.clone()))),
+        ),
+        Command::Disconnect(x) => ("disconnect", Some(x.as_str().to_string())),
+        Command::Host { target: x, .. } => {
+            let (FileTarget::File { path, .. } | FileTarget::Dir { path, .. }) = x;
+            ("host", Some(path.display().to_string()))
+        }
+        Command::Share { target: x, .. } => ("share", Some(target(x))),
+        Command::Unhost(x) => {
+            let x: Vec<_> = x.iter().map(target).collect();
+            ("unhost", Some(x.join(" ")))
+        }
+        Command::Fetch {
+            host: h, target: x, ..
+        } => ("fetch", Some(on(Some(h), target(x)))),
+        Command::Push {
+            host: h, target: x, ..
+        } => ("push", Some(on(Some(h), target(x)))),
+        Command::Sync { host: h, dir, .. } => {
+            ("sync", Some(on(Some(h), dir.display().to_string())))
+        }
+        Command::Accept(x) => ("accept", Some(format!("push {x}"))),
+        Command::Reject(x) => ("reject", Some(format!("push {x}"))),
+        Command::Backup { job, host: h, .. } => ("backup", Some(on(h.as_ref(), job.clone()))),
+        Command::Restore {
+            host: h, snapshot, ..
+        } => ("restore", Some(on(h.as_ref(), snapshot.to_string()))),
+        Command::Prune { host: h, job, .. } => {
+            let job = job.clone().unwrap_or_else(|| "every job".to_string());
+            ("prune", Some(on(h.as_ref(), job)))
+        }
+        Command::Verify { host: h, .. } => ("verify", h.as_ref().map(host)),
+        Command::Mount { mountpoint, .. } => ("mount", Some(mountpoint.display().to_string())),
+        Command::Unmount(x) => ("unmount", Some(x.display().to_string())),
+        Command::Throttle { host: h, .. } => ("throttle", h.as_ref().map(host)),
+        Command::Cancel(x) => ("cancel", Some(format!("transfer {x}"))),
+        Command::Pause(x) => ("pause", Some(format!("transfer {x}"))),
+        Command::Resume(x) => ("resume", Some(format!("transfer {x}"))),
+        Command::Pending
+        | Command::Snapshots { .. }
+        | Command::Diff { .. }
+        | Command::Jobs
+        | Command::Log { .. }
+        | Command::ServerStatus(_)
+        | Command::FileList(_) => return None,
+    })
+}
+
+/// what the log records of a request of a peer, nothing for requests that are part of a larger
+/// operation or only look
+pub fn describe_packet(request: &SendPacket) -> Option<(&'static str, Option<String>)> {
+    Some(match request {
+        SendPacket::ReadFile {
+            path, offset: 0, ..
+        }
+        | SendPacket::StartDelta { path, .. } => ("fetch", Some(path.display().to_string())),
+        SendPacket::StartPush(files) => ("push", Some(format!("{} files", files.len()))),
+        SendPacket::FinishPush(x) => ("finish-push", Some(format!("push {x}"))),
+        SendPacket::SyncCommit { dir, path, .. } => {
+            ("sync", Some(dir.join(path).display().to_string()))
+        }
+        SendPacket::PutSnapshot { id, offset: 0, .. } => ("store-snapshot", Some(id.to_string())),
+        SendPacket::GetSnapshot { id, offset: 0 } => ("get-snapshot", Some(id.to_string())),
+        SendPacket::ForgetSnapshots {
+            ids,
+            dry_run: false,
+        } => {
+            let ids: Vec<_> = ids.iter().map(|x| x.to_string()).collect();
+            ("forget-snapshots", Some(ids.join(" ")))
+        }
+        _ => return None,
+    })
+}
+
+impl Server {
+    /// send the entries of the log, with `follow` keep sending new ones until the client is gone
+    pub async fn log(&self, filter: &LogFilter, follow: bool, reply: &Responder) {
+        // subscribe first so nothing made while reading the tree is missed
+        let mut new = self.audit.follow.subscribe();
+        let entries = match self.audit.entries(filter) {
+            Ok(x) => x,
+            Err(e) => return reply.send(ServerReply::Error(e)),
+        };
+        if !follow {
+            return reply.send(ServerReply::Log(entries));
+        }
+        // ids are taken before the entry is written, so a newer entry can have a lower id than
+        // one that was read and only what was read is skipped. what was made while reading is
+        // among the newest entries
+        let mut sent: HashSet<u64> = entries
+            .iter()
+            .rev()
+            .take(FOLLOW_BUFFER)
+            .map(|x| x.id)
+            .collect();
+        for entry in entries {
+            reply.send(ServerReply::LogEntry(entry));
+        }
+        loop {
+            match new.recv().await {
+                Ok(entry) => {
+                    if sent.remove(&entry.id) || !filter.matches(&entry) {
+                        continue;
+                    }
+                    reply.send(ServerReply::LogEntry(entry));
+                    // the connection only notices the client left once it writes to it
+                    if reply.is_closed() {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(x)) => {
+                    eprintln!("a follower of the audit log missed {x} entries");
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
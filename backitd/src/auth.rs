@@ -0,0 +1,84 @@
+//! access control for the ipc socket
+
+use std::io;
+
+use backit_core::ipc;
+
+use crate::config::IpcConfig;
+
+/// the process on the other side of an ipc connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// read the credentials of the process that connected to us
+#[cfg(unix)]
+pub fn peer_credentials(stream: ipc::Stream) -> io::Result<(ipc::Stream, PeerCredentials)> {
+    use interprocess::local_socket::{tokio::RecvHalf, traits::tokio::Stream};
+    use std::os::fd::AsFd;
+
+    let (recv, send) = stream.split();
+    let credentials = match &recv {
+        RecvHalf::UdSocket(x) => socket_credentials(x.as_fd()),
+    };
+    let stream = ipc::Stream::reunite(recv, send)
+        .map_err(|_| io::Error::other("could not reunite the ipc stream"))?;
+    Ok((stream, credentials?))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn socket_credentials(fd: std::os::fd::BorrowedFd<'_>) -> io::Result<PeerCredentials> {
+    use nix::sys::socket::{getsockopt, sockopt::PeerCredentials as SoPeerCred};
+    let cred = getsockopt(&fd, SoPeerCred)?;
+    Ok(PeerCredentials {
+        uid: cred.uid(),
+        gid: cred.gid(),
+    })
+}
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn socket_credentials(fd: std::os::fd::BorrowedFd<'_>) -> io::Result<PeerCredentials> {
+    let (uid, gid) = nix::unistd::getpeereid(fd)?;
+    Ok(PeerCredentials {
+        uid: uid.as_raw(),
+        gid: gid.as_raw(),
+    })
+}
+
+impl IpcConfig {
+    /// check if a connecting user may command this daemon
+    #[cfg(unix)]
+    pub fn allows(&self, peer: &PeerCredentials) -> bool {
+        use nix::unistd::{getgrouplist, getuid, Gid, Group, Uid, User};
+        use std::ffi::CString;
+
+        if peer.uid == getuid().as_raw() || self.allowed_uids.contains(&peer.uid) {
+            return true;
+        }
+        if self.allowed_groups.is_empty() {
+            return false;
+        }
+        let mut groups = vec![Gid::from_raw(peer.gid)];
+        if let Ok(Some(user)) = User::from_uid(Uid::from_raw(peer.uid)) {
+            if let Ok(name) = CString::new(user.name) {
+                groups.extend(getgrouplist(&name, user.gid).unwrap_or_default());
+            }
+        }
+        self.allowed_groups.iter().any(
+            |name| matches!(Group::from_name(name), Ok(Some(group)) if groups.contains(&group.gid)),
+        )
+    }
+}
+
+/// named pipes are only reachable by the current user, so there is nothing to check
+#[cfg(not(unix))]
+pub fn peer_credentials(stream: ipc::Stream) -> io::Result<(ipc::Stream, PeerCredentials)> {
+    Ok((stream, PeerCredentials { uid: 0, gid: 0 }))
+}
+#[cfg(not(unix))]
+impl IpcConfig {
+    pub fn allows(&self, _peer: &PeerCredentials) -> bool {
+        true
+    }
+}
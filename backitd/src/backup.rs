@@ -0,0 +1,882 @@
+//! making snapshots of hosted files and restoring them, to and from local or remote stores
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::File,
+    io::{self, SeekFrom},
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use backit_core::{
+    ipc::{
+        to_server::{AnyHost, Target},
+        ServerError,
+    },
+    snapshot::{
+        ChunkHash, EntryKind, GcStats, ManifestEntry, Snapshot, SnapshotDiff, SnapshotId,
+        SnapshotInfo, VerifyReport,
+    },
+    tcp::{ReceivePacket, SendPacket},
+};
+use fastcdc::v2020::StreamCDC;
+use libp2p::PeerId;
+use tokio::{
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::{
+    audit,
+    index::{self, FileIndex, FileStat, IndexEntry},
+    meta,
+    p2p::Client,
+    peerset::PeerSet,
+    progress::ProgressTracker,
+    reply::Responder,
+    scripts::{JobScript, Phase},
+    store::{Store, StoreError, LOCAL_OWNER, MAX_MANIFEST},
+    Server,
+};
+
+/// chunk sizes for content defined chunking, small enough that a chunk fits in a single p2p
+/// request
+pub const MIN_CHUNK: u32 = 64 * 1024;
+pub const AVG_CHUNK: u32 = 256 * 1024;
+pub const MAX_CHUNK: u32 = 1024 * 1024;
+/// how many hashes are asked about in a single request, this keeps the request well below the
+/// maximum packet size
+const HASH_BATCH: usize = 16 * 1024;
+/// how much chunk data a backup holds back to ask for the missing chunks in one request
+const CHUNK_BATCH_BYTES: usize = 64 * 1024 * 1024;
+/// how much of a manifest is sent in a single packet
+pub const MANIFEST_PIECE: usize = 1024 * 1024;
+
+impl From<StoreError> for ServerError {
+    fn from(value: StoreError) -> Self {
+        match value {
+            StoreError::QuotaExceeded { used, quota } => ServerError::QuotaExceeded { used, quota },
+            value => ServerError::Io(value.to_string()),
+        }
+    }
+}
+
+/// where snapshots are written to and read from
+pub enum Repo {
+    Local(Store),
+    /// the store of a peer, which only shows us the snapshots we pushed to it
+    Remote {
+        client: Client,
+        peer: PeerId,
+    },
+    /// chunks spread over several peers
+    Set(PeerSet),
+}
+impl Repo {
+    async fn request(&mut self, request: SendPacket) -> Result<ReceivePacket, ServerError> {
+        let Self::Remote { client, peer } = self else {
+            unreachable!("only remote repos send requests")
+        };
+        match client.request(*peer, request).await {
+            Ok(ReceivePacket::Error(e)) => Err(e),
+            Ok(x) => Ok(x),
+            Err(e) => Err(ServerError::Peer(e.to_string())),
+        }
+    }
+
+    /// a name for the repo that stays the same between runs
+    pub fn key(&self) -> String {
+        match self {
+            Self::Local(_) => LOCAL_OWNER.to_string(),
+            Self::Remote { peer, .. } => peer.to_string(),
+            Self::Set(set) => format!("set/{}", set.name()),
+        }
+    }
+    pub async fn missing_chunks(
+        &mut self,
+        hashes: Vec<ChunkHash>,
+    ) -> Result<Vec<ChunkHash>, ServerError> {
+        match self {
+            Self::Local(store) => Ok(store.missing_chunks(&hashes)?),
+            Self::Set(set) => set.missing_chunks(&hashes).await,
+            Self::Remote { .. } => match self.request(SendPacket::HasChunks(hashes)).await? {
+                ReceivePacket::MissingChunks(x) => Ok(x),
+                _ => Err(ServerError::InvalidPacket),
+            },
+        }
+    }
+    pub async fn put_chunk(&mut self, hash: ChunkHash, data: Vec<u8>) -> Result<(), ServerError> {
+        match self {
+            Self::Local(store) => Ok(store.put_chunk(LOCAL_OWNER, &hash, &data, None)?),
+            Self::Set(set) => set.put_chunk(hash, data).await,
+            Self::Remote { .. } => match self.request(SendPacket::PutChunk { hash, data }).await? {
+                ReceivePacket::Ok => Ok(()),
+                _ => Err(ServerError::InvalidPacket),
+            },
+        }
+    }
+    pub async fn get_chunk(&mut self, hash: ChunkHash) -> Result<Vec<u8>, ServerError> {
+        let data = match self {
+            Self::Local(store) => store
+                .get_chunk(&hash)?
+                .ok_or(ServerError::MissingChunk(hash))?,
+            Self::Set(set) => set.get_chunk(hash).await?,
+            Self::Remote { .. } => match self.request(SendPacket::GetChunk(hash)).await? {
+                ReceivePacket::Chunk(x) => x,
+                _ => return Err(ServerError::InvalidPacket),
+            },
+        };
+        // a remote store could hand us anything, so never trust it
+        if ChunkHash::of(&data) != hash {
+            return Err(ServerError::MissingChunk(hash));
+        }
+        Ok(data)
+    }
+    pub async fn put_snapshot(&mut self, snapshot: Snapshot) -> Result<(), ServerError> {
+        match self {
+            Self::Local(store) => Ok(store.put_snapshot(LOCAL_OWNER, &snapshot, None)?),
+            Self::Set(set) => set.put_snapshot(snapshot).await,
+            Self::Remote { .. } => {
+                for request in snapshot_packets(&snapshot)? {
+                    match self.request(request).await? {
+                        ReceivePacket::Ok => {}
+                        _ => return Err(ServerError::InvalidPacket),
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+    pub async fn snapshots(
+        &mut self,
+        job: Option<String>,
+    ) -> Result<Vec<SnapshotInfo>, ServerError> {
+        match self {
+            Self::Local(store) => Ok(store.snapshot_infos(LOCAL_OWNER, job.as_deref())?),
+            Self::Set(set) => set.snapshots(job).await,
+            Self::Remote { .. } => match self.request(SendPacket::Snapshots { job }).await? {
+                ReceivePacket::Snapshots(x) => Ok(x),
+                _ => Err(ServerError::InvalidPacket),
+            },
+        }
+    }
+    /// remove snapshots and garbage collect the store, `grace` is only used for the local store
+    /// since a peer uses its own settings
+    pub async fn forget(
+        &mut self,
+        ids: Vec<SnapshotId>,
+        dry_run: bool,
+        grace: Duration,
+    ) -> Result<GcStats, ServerError> {
+        match self {
+            Self::Local(store) => {
+                let store = store.clone();
+                tokio::task::spawn_blocking(move || {
+                    forget(&store, LOCAL_OWNER, &ids, dry_run, grace)
+                })
+                .await
+                .map_err(|e| ServerError::Io(e.to_string()))?
+            }
+            Self::Set(set) => set.forget(ids, dry_run).await,
+            Self::Remote { .. } => {
+                match self
+                    .request(SendPacket::ForgetSnapshots { ids, dry_run })
+                    .await?
+                {
+                    ReceivePacket::Collected(x) => Ok(x),
+                    _ => Err(ServerError::InvalidPacket),
+                }
+            }
+        }
+    }
+    /// check the stored chunks, the local store is checked as a whole, including what peers
+    /// pushed to us, while a peer only checks what we pushed to it
+    pub async fn verify(
+        &mut self,
+        full: bool,
+        mut progress: ProgressTracker,
+    ) -> Result<VerifyReport, ServerError> {
+        match self {
+            Self::Local(store) => {
+                let store = store.clone();
+                tokio::task::spawn_blocking(move || {
+                    let report = store.verify(None, full, |x| progress.add_bytes(x));
+                    progress.finish();
+                    report.map_err(ServerError::from)
+                })
+                .await
+                .map_err(|e| ServerError::Io(e.to_string()))?
+            }
+            Self::Set(set) => {
+                progress.finish();
+                set.verify(full).await
+            }
+            Self::Remote { .. } => match self.request(SendPacket::Verify { full }).await? {
+                ReceivePacket::Verified(x) => Ok(x),
+                _ => Err(ServerError::InvalidPacket),
+            },
+        }
+    }
+    pub async fn snapshot(&mut self, id: SnapshotId) -> Result<Snapshot, ServerError> {
+        match self {
+            Self::Local(store) => store
+                .get_snapshot(LOCAL_OWNER, &id)?
+                .ok_or(ServerError::UnknownSnapshot(id)),
+            Self::Set(set) => set.snapshot(id).await,
+            Self::Remote { .. } => {
+                let mut parts = ManifestParts::new(id);
+                while let Some(request) = parts.next_request() {
+                    let response = self.request(request).await?;
+                    parts.add(response)?;
+                }
+                parts.finish()
+            }
+        }
+    }
+}
+
+/// the packets that store a snapshot on a peer, a manifest can be larger than a single packet
+pub fn snapshot_packets(snapshot: &Snapshot) -> Result<Vec<SendPacket>, ServerError> {
+    let manifest = serde_cbor::to_vec(snapshot).map_err(|e| ServerError::Io(e.to_string()))?;
+    let total = manifest.len() as u64;
+    Ok(manifest
+        .chunks(MANIFEST_PIECE)
+        .enumerate()
+        .map(|(i, data)| SendPacket::PutSnapshot {
+            id: snapshot.id,
+            offset: (i * MANIFEST_PIECE) as u64,
+            total,
+            data: data.to_vec(),
+        })
+        .collect())
+}
+
+/// collects the parts of a manifest a peer sends for [`SendPacket::GetSnapshot`]
+pub struct ManifestParts {
+    id: SnapshotId,
+    data: Vec<u8>,
+    /// unset until the first part is in
+    total: Option<u64>,
+}
+impl ManifestParts {
+    pub fn new(id: SnapshotId) -> Self {
+        Self {
+            id,
+            data: Vec::new(),
+            total: None,
+        }
+    }
+    /// the request for the next part, none once the manifest is complete
+    pub fn next_request(&self) -> Option<SendPacket> {
+        let offset = self.data.len() as u64;
+        match self.total {
+            Some(total) if offset >= total => None,
+            _ => Some(SendPacket::GetSnapshot {
+                id: self.id,
+                offset,
+            }),
+        }
+    }
+    pub fn add(&mut self, response: ReceivePacket) -> Result<(), ServerError> {
+        let ReceivePacket::SnapshotPart { total, data } = response else {
+            return Err(ServerError::InvalidPacket);
+        };
+        let len = (self.data.len() + data.len()) as u64;
+        // a part that adds nothing would have us ask forever
+        let changed = self.total.is_some_and(|x| x != total);
+        if changed || total > MAX_MANIFEST || len > total || (data.is_empty() && len < total) {
+            return Err(ServerError::InvalidPacket);
+        }
+        self.data.extend(data);
+        self.total = Some(total);
+        Ok(())
+    }
+    pub fn finish(self) -> Result<Snapshot, ServerError> {
+        let snapshot: Snapshot =
+            serde_cbor::from_slice(&self.data).map_err(|_| ServerError::InvalidPacket)?;
+        match snapshot.id == self.id {
+            true => Ok(snapshot),
+            false => Err(ServerError::InvalidPacket),
+        }
+    }
+}
+
+/// remove snapshots of an owner and collect the chunks that became unused, this blocks
+///
+/// on a dry run nothing is removed, so the chunks of the snapshots that would be removed are not
+/// counted as freed
+pub fn forget(
+    store: &Store,
+    owner: &str,
+    ids: &[SnapshotId],
+    dry_run: bool,
+    grace: Duration,
+) -> Result<GcStats, ServerError> {
+    if !dry_run {
+        for id in ids {
+            store.delete_snapshot(owner, id)?;
+        }
+    }
+    Ok(store.gc(grace, dry_run)?)
+}
+
+/// what the chunker thread hands to the upload loop
+enum Chunked {
+    File(PathBuf),
+    Chunk {
+        hash: ChunkHash,
+        data: Vec<u8>,
+    },
+    Done(IndexEntry),
+    /// the file did not change since the last backup and was not read
+    Unchanged(IndexEntry),
+    /// the file was removed after the file list was made
+    Skipped(PathBuf),
+}
+
+/// true if the file takes less space on disk than its size
+#[cfg(unix)]
+fn is_sparse(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512 < metadata.len()
+}
+#[cfg(not(unix))]
+fn is_sparse(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// cut files into chunks, this does blocking io so it runs on its own thread
+///
+/// files that did not change since they were put in the index are not read, symlinks are
+/// recorded with their target and a file with several names in the backup is only chunked for
+/// the first one. a file that is gone by the time it is read is skipped
+fn chunk_files(
+    files: Vec<PathBuf>,
+    mut index: FileIndex,
+    tx: mpsc::Sender<Chunked>,
+) -> io::Result<()> {
+    let mut inodes: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    for path in files {
+        let metadata = match std::fs::symlink_metadata(&path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if tx.blocking_send(Chunked::Skipped(path)).is_err() {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let stat = FileStat::of(&metadata);
+        if tx.blocking_send(Chunked::File(path.clone())).is_err() {
+            return Ok(());
+        }
+        let kind = if metadata.is_symlink() {
+            EntryKind::Symlink(std::fs::read_link(&path)?)
+        } else {
+            match meta::inode(&metadata) {
+                Some(x) if inodes.contains_key(&x) => EntryKind::Hardlink(inodes[&x].clone()),
+                Some(x) => {
+                    inodes.insert(x, path.clone());
+                    EntryKind::File
+                }
+                None => EntryKind::File,
+            }
+        };
+        if let Some(old) = index.remove(&path) {
+            if old.stat == stat && old.entry.kind == kind {
+                if tx.blocking_send(Chunked::Unchanged(old)).is_err() {
+                    return Ok(());
+                }
+                continue;
+            }
+        }
+        if kind != EntryKind::File {
+            let entry = ManifestEntry {
+                meta: meta::read(&path, &metadata),
+                path,
+                size: 0,
+                mtime: stat.mtime,
+                chunks: Vec::new(),
+                kind,
+                sparse: false,
+            };
+            let done = IndexEntry {
+                stat,
+                hash: None,
+                entry,
+            };
+            if tx.blocking_send(Chunked::Done(done)).is_err() {
+                return Ok(());
+            }
+            continue;
+        }
+
+        let file = File::open(&path)?;
+        let mut hasher = blake3::Hasher::new();
+        let mut chunks = Vec::new();
+        let mut size = 0;
+        for chunk in StreamCDC::new(file, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK) {
+            let chunk = chunk.map_err(io::Error::other)?;
+            let hash = ChunkHash::of(&chunk.data);
+            hasher.update(&chunk.data);
+            chunks.push(hash);
+            size += chunk.length as u64;
+            if tx
+                .blocking_send(Chunked::Chunk {
+                    hash,
+                    data: chunk.data,
+                })
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+        let entry = ManifestEntry {
+            meta: meta::read(&path, &metadata),
+            path,
+            size,
+            mtime: stat.mtime,
+            chunks,
+            kind,
+            sparse: is_sparse(&metadata),
+        };
+        let done = IndexEntry {
+            stat,
+            hash: Some(ChunkHash::from_bytes(*hasher.finalize().as_bytes())),
+            entry,
+        };
+        if tx.blocking_send(Chunked::Done(done)).is_err() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// drop the entries of an index whose chunks the repo no longer has, for example because their
+/// snapshots were pruned, those files are read again
+async fn check_index(repo: &mut Repo, index: &mut FileIndex) -> Result<(), ServerError> {
+    let chunks: BTreeSet<ChunkHash> = index
+        .values()
+        .flat_map(|x| x.entry.chunks.iter().copied())
+        .collect();
+    let chunks: Vec<ChunkHash> = chunks.into_iter().collect();
+    let mut missing = BTreeSet::new();
+    for batch in chunks.chunks(HASH_BATCH) {
+        missing.extend(repo.missing_chunks(batch.to_vec()).await?);
+    }
+    index.retain(|_, x| x.entry.chunks.iter().all(|x| !missing.contains(x)));
+    Ok(())
+}
+
+/// send the chunks of a batch the repo does not have yet, they are asked about in one request
+async fn put_chunks(
+    repo: &mut Repo,
+    chunks: Vec<(ChunkHash, Vec<u8>)>,
+    progress: &mut ProgressTracker,
+) -> Result<(), ServerError> {
+    if chunks.is_empty() {
+        return Ok(());
+    }
+    let hashes = chunks.iter().map(|(hash, _)| *hash).collect();
+    let mut missing: HashSet<ChunkHash> = repo.missing_chunks(hashes).await?.into_iter().collect();
+    for (hash, data) in chunks {
+        let len = data.len() as u64;
+        // a chunk that is in the batch twice is only sent once
+        if missing.remove(&hash) {
+            repo.put_chunk(hash, data).await?;
+        }
+        progress.add_bytes(len);
+    }
+    Ok(())
+}
+
+/// back up files into a new snapshot, only chunks the repo does not have yet are sent
+///
+/// files that did not change since their entry in `index` are taken over from it, returns the
+/// snapshot and the index for the next backup
+pub async fn backup(
+    repo: &mut Repo,
+    job: String,
+    files: Vec<(PathBuf, u64)>,
+    mut index: FileIndex,
+    progress: &mut ProgressTracker,
+) -> Result<(Snapshot, Vec<IndexEntry>, Vec<PathBuf>), ServerError> {
+    progress.set_totals(
+        Some(files.iter().map(|(_, size)| size).sum()),
+        Some(files.len() as u64),
+    );
+    check_index(repo, &mut index).await?;
+    let (tx, mut rx) = mpsc::channel(8);
+    let paths = files.into_iter().map(|(path, _)| path).collect();
+    let chunker = tokio::task::spawn_blocking(move || chunk_files(paths, index, tx));
+
+    let mut done = Vec::new();
+    let mut skipped = Vec::new();
+    let mut pending = Vec::new();
+    let mut pending_bytes = 0;
+    while let Some(x) = rx.recv().await {
+        match x {
+            Chunked::File(path) => progress.start_file(path.display().to_string()),
+            Chunked::Chunk { hash, data } => {
+                pending_bytes += data.len();
+                pending.push((hash, data));
+                if pending.len() >= HASH_BATCH || pending_bytes >= CHUNK_BATCH_BYTES {
+                    put_chunks(repo, std::mem::take(&mut pending), progress).await?;
+                    pending_bytes = 0;
+                }
+            }
+            Chunked::Done(x) => {
+                done.push(x);
+                progress.finish_file();
+            }
+            Chunked::Unchanged(x) => {
+                progress.add_bytes(x.entry.size);
+                done.push(x);
+                progress.finish_file();
+            }
+            Chunked::Skipped(path) => {
+                skipped.push(path);
+                progress.finish_file();
+            }
+        }
+    }
+    chunker
+        .await
+        .map_err(|e| ServerError::Io(e.to_string()))?
+        .map_err(|e| ServerError::Io(e.to_string()))?;
+    put_chunks(repo, pending, progress).await?;
+
+    let entries = done.iter().map(|x| x.entry.clone()).collect();
+    let snapshot = Snapshot::new(job, entries);
+    repo.put_snapshot(snapshot.clone()).await?;
+    progress.finish();
+    Ok((snapshot, done, skipped))
+}
+
+/// where a file of a snapshot ends up, below `to` when given
+///
+/// the path comes from a manifest or a peer, so it may only go down from the root. a `..` would
+/// let it write anywhere
+pub fn restore_path(path: &Path, to: Option<&Path>) -> Result<PathBuf, ServerError> {
+    let relative = path.strip_prefix("/").unwrap_or(path);
+    let valid = relative.components().next().is_some()
+        && relative
+            .components()
+            .all(|x| matches!(x, Component::Normal(_)));
+    if !valid {
+        return Err(ServerError::InvalidPacket);
+    }
+    Ok(match to {
+        Some(dir) => dir.join(relative),
+        None => path.to_path_buf(),
+    })
+}
+
+/// make sure writing to `path` stays inside `dir`, which it would not if a directory between
+/// them is a symlink
+///
+/// directories that do not exist yet are fine, they are created. `path` itself may be a symlink,
+/// it is replaced and never written through
+pub fn check_inside(dir: &Path, path: &Path) -> Result<(), ServerError> {
+    let relative = path
+        .strip_prefix(dir)
+        .map_err(|_| ServerError::InvalidPacket)?;
+    let mut at = dir.to_path_buf();
+    for part in relative.parent().into_iter().flat_map(Path::components) {
+        at.push(part);
+        match std::fs::symlink_metadata(&at) {
+            Ok(x) if x.file_type().is_symlink() => {
+                return Err(ServerError::Io(format!("{} is a symlink", at.display())))
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(ServerError::Io(format!("{}: {e}", at.display()))),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub async fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    tokio::fs::symlink(target, path).await
+}
+#[cfg(not(unix))]
+pub async fn symlink(_target: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "symlinks can not be restored on this platform",
+    ))
+}
+
+/// write a single file of a snapshot, the file only replaces an existing one once it is complete
+///
+/// `data` is the entry that holds the chunks, for a hardlink whose first name is not restored
+/// that is the entry of the first name. metadata is only `trusted` when the snapshot comes from
+/// our own store
+async fn restore_file(
+    repo: &mut Repo,
+    entry: &ManifestEntry,
+    data: &ManifestEntry,
+    dest: &Path,
+    trusted: bool,
+    progress: &mut ProgressTracker,
+) -> Result<(), ServerError> {
+    let io_err = |e: io::Error| ServerError::Io(format!("{}: {e}", dest.display()));
+    if let Some(dir) = dest.parent() {
+        tokio::fs::create_dir_all(dir).await.map_err(io_err)?;
+    }
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    let partial = dest.with_file_name(format!(".{name}.backit-restore"));
+    // the snapshot may have a symlink of that name, it must not be written through
+    match tokio::fs::remove_file(&partial).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(io_err(e)),
+    }
+    if let EntryKind::Symlink(target) = &entry.kind {
+        symlink(target, &partial).await.map_err(io_err)?;
+    } else {
+        let mut file = tokio::fs::File::create(&partial).await.map_err(io_err)?;
+        let mut size = 0;
+        for hash in &data.chunks {
+            let chunk = repo.get_chunk(*hash).await?;
+            size += chunk.len() as u64;
+            // skipping zeros leaves a hole, set_len below fills in a hole at the end
+            if data.sparse && chunk.iter().all(|x| *x == 0) {
+                file.seek(SeekFrom::Start(size)).await.map_err(io_err)?;
+            } else {
+                file.write_all(&chunk).await.map_err(io_err)?;
+            }
+            progress.add_bytes(chunk.len() as u64);
+        }
+        file.flush().await.map_err(io_err)?;
+        let file = file.into_std().await;
+        file.set_len(size).map_err(io_err)?;
+        file.sync_all().map_err(io_err)?;
+    }
+    meta::apply(&partial, &entry.meta, entry.mtime, trusted).map_err(io_err)?;
+    tokio::fs::rename(&partial, dest).await.map_err(io_err)?;
+    Ok(())
+}
+
+/// restore every file of the snapshot below one of `paths`, returns the file count and bytes
+pub async fn restore(
+    repo: &mut Repo,
+    snapshot: &Snapshot,
+    paths: &[PathBuf],
+    to: Option<&Path>,
+    progress: &mut ProgressTracker,
+) -> Result<(usize, u64), ServerError> {
+    let mut entries: Vec<&ManifestEntry> = snapshot.select(paths).collect();
+    // symlinks last, so no file of the snapshot is written through one of them
+    entries.sort_by_key(|x| matches!(x.kind, EntryKind::Symlink(_)));
+    let trusted = matches!(repo, Repo::Local(_));
+    let bytes = entries.iter().map(|x| x.size).sum();
+    progress.set_totals(Some(bytes), Some(entries.len() as u64));
+    // where the first name of every file was restored to, for the hardlinks to it
+    let mut restored: BTreeMap<&Path, PathBuf> = BTreeMap::new();
+    for entry in &entries {
+        progress.start_file(entry.path.display().to_string());
+        let dest = restore_path(&entry.path, to)?;
+        if let Some(to) = to {
+            check_inside(to, &dest)?;
+        }
+        match &entry.kind {
+            EntryKind::Hardlink(first) if restored.contains_key(first.as_path()) => {
+                let io_err = |e: io::Error| ServerError::Io(format!("{}: {e}", dest.display()));
+                if let Some(dir) = dest.parent() {
+                    tokio::fs::create_dir_all(dir).await.map_err(io_err)?;
+                }
+                let _ = tokio::fs::remove_file(&dest).await;
+                tokio::fs::hard_link(&restored[first.as_path()], &dest)
+                    .await
+                    .map_err(io_err)?;
+            }
+            // the first name is not restored, so this one gets the data
+            EntryKind::Hardlink(first) => {
+                let data = snapshot
+                    .entry(first)
+                    .ok_or_else(|| ServerError::Io(format!("{} is missing", first.display())))?;
+                restore_file(repo, entry, data, &dest, trusted, progress).await?;
+                restored.insert(first, dest);
+            }
+            _ => {
+                restore_file(repo, entry, entry, &dest, trusted, progress).await?;
+                restored.insert(&entry.path, dest);
+            }
+        }
+        progress.finish_file();
+    }
+    progress.finish();
+    Ok((entries.len(), bytes))
+}
+
+/// the post command of a backup that is running, it is started once the backup is dropped
+/// before it got to run it, like when the backup is cancelled or paused
+struct PostCommand {
+    server: Server,
+    job: String,
+    script: Option<JobScript>,
+    env: Vec<(&'static str, String)>,
+}
+impl PostCommand {
+    /// start the command in its own task, so it runs to its end even when the backup does not
+    fn start(&mut self, result: &str) -> Option<JoinHandle<Result<(), ServerError>>> {
+        let script = self.script.take()?;
+        let (server, job) = (self.server.clone(), std::mem::take(&mut self.job));
+        let mut env = std::mem::take(&mut self.env);
+        env.push(("BACKIT_RESULT", result.to_string()));
+        let runtime = tokio::runtime::Handle::try_current().ok()?;
+        Some(
+            runtime.spawn(async move { server.run_script(&job, Phase::Post, &script, &env).await }),
+        )
+    }
+    /// run the command after the backup, with how it went
+    fn run(
+        &mut self,
+        result: &Result<SnapshotInfo, ServerError>,
+    ) -> Option<JoinHandle<Result<(), ServerError>>> {
+        match result {
+            Ok(x) => {
+                self.env.push(("BACKIT_SNAPSHOT", x.id.to_string()));
+                self.start("success")
+            }
+            Err(e) => {
+                self.env.push(("BACKIT_ERROR", format!("{e:?}")));
+                self.start("failure")
+            }
+        }
+    }
+}
+impl Drop for PostCommand {
+    fn drop(&mut self) {
+        self.start("cancelled");
+    }
+}
+
+impl Server {
+    /// the local store, the store of a remote host, or a set of peers when the host is the name
+    /// of a set
+    pub async fn repo(&self, host: Option<&AnyHost>) -> Result<Repo, ServerError> {
+        if let Some(AnyHost::HostId(id)) = host {
+            if let Some(set) = self.peer_set(id.as_str()) {
+                return Ok(Repo::Set(set?));
+            }
+        }
+        Ok(match host {
+            None => Repo::Local(self.store.clone()),
+            Some(host) => Repo::Remote {
+                client: self.client.clone(),
+                peer: self.resolve_host(host).await?,
+            },
+        })
+    }
+
+    /// back up the files matching the target between the pre and post commands of the job, with
+    /// `full` every file is read even when the index says it did not change
+    pub async fn backup(
+        &self,
+        job: &str,
+        host: Option<&AnyHost>,
+        target: &Target,
+        full: bool,
+        reply: &Responder,
+    ) -> Result<SnapshotInfo, ServerError> {
+        let config = self.config.jobs.get(job);
+        let env = vec![
+            ("BACKIT_JOB", job.to_string()),
+            ("BACKIT_HOST", host.map(audit::host).unwrap_or_default()),
+        ];
+        // from here on the post command runs, also when the backup is cancelled or paused
+        let mut post = PostCommand {
+            server: self.clone(),
+            job: job.to_string(),
+            script: config.and_then(|x| x.post.clone()),
+            env,
+        };
+        let result = match config.and_then(|x| x.pre.as_ref()) {
+            Some(pre) => self.run_script(job, Phase::Pre, pre, &post.env).await,
+            None => Ok(()),
+        };
+        let result = match result {
+            Ok(()) => self.backup_files(job, host, target, full, reply).await,
+            Err(e) => Err(e),
+        };
+        let Some(after) = post.run(&result) else {
+            return result;
+        };
+        let after = after.await.map_err(|e| ServerError::Io(e.to_string()))?;
+        // the snapshot is stored either way, a failed post command only fails the job
+        let snapshot = result?;
+        after?;
+        Ok(snapshot)
+    }
+
+    async fn backup_files(
+        &self,
+        job: &str,
+        host: Option<&AnyHost>,
+        target: &Target,
+        full: bool,
+        reply: &Responder,
+    ) -> Result<SnapshotInfo, ServerError> {
+        let files: Vec<(PathBuf, u64)> = self
+            .state
+            .lock()
+            .await
+            .catalog
+            .matching(target)
+            .map(|x| (x.path.clone(), x.size))
+            .collect();
+        let mut repo = self.repo(host).await?;
+        let key = repo.key();
+        let index = match full {
+            true => FileIndex::new(),
+            false => {
+                let (store, key, job) = (self.store.clone(), key.clone(), job.to_string());
+                tokio::task::spawn_blocking(move || index::load(&store, &key, &job))
+                    .await
+                    .map_err(|e| ServerError::Io(e.to_string()))??
+            }
+        };
+        let mut progress = ProgressTracker::new(reply.clone());
+        let (snapshot, done, skipped) =
+            backup(&mut repo, job.to_string(), files, index, &mut progress).await?;
+        let (store, job) = (self.store.clone(), job.to_string());
+        tokio::task::spawn_blocking(move || index::save(&store, &key, &job, &done))
+            .await
+            .map_err(|e| ServerError::Io(e.to_string()))??;
+        Ok(SnapshotInfo {
+            skipped,
+            ..snapshot.info()
+        })
+    }
+
+    pub async fn restore(
+        &self,
+        host: Option<&AnyHost>,
+        id: SnapshotId,
+        paths: &[PathBuf],
+        to: Option<&Path>,
+        reply: &Responder,
+    ) -> Result<(usize, u64), ServerError> {
+        let mut repo = self.repo(host).await?;
+        let snapshot = repo.snapshot(id).await?;
+        let mut progress = ProgressTracker::new(reply.clone());
+        restore(&mut repo, &snapshot, paths, to, &mut progress).await
+    }
+
+    pub async fn diff(
+        &self,
+        host: Option<&AnyHost>,
+        from: SnapshotId,
+        to: SnapshotId,
+    ) -> Result<SnapshotDiff, ServerError> {
+        let mut repo = self.repo(host).await?;
+        let from = repo.snapshot(from).await?;
+        let to = repo.snapshot(to).await?;
+        Ok(to.diff(&from))
+    }
+}
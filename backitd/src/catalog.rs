@@ -0,0 +1,423 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use backit_core::{
+    ipc::{
+        to_server::{FileTarget, Grantee, Right, Share, Target},
+        FileInfo,
+    },
+    snapshot::{EntryKind, FileMeta},
+};
+
+use crate::filter::{Filter, IgnoreConfig};
+
+/// peer groups from the config, group name to peer ids
+pub type Groups = BTreeMap<String, Vec<String>>;
+
+/// add or with `revoke` take away rights
+fn share_acl(acl: &mut BTreeMap<Grantee, BTreeSet<Right>>, shares: &[Share], revoke: bool) {
+    for share in shares {
+        let rights = acl.entry(share.grantee.clone()).or_default();
+        if revoke {
+            rights.retain(|x| !share.rights.contains(x));
+        } else {
+            rights.extend(share.rights.iter().copied());
+        }
+    }
+    acl.retain(|_, x| !x.is_empty());
+}
+
+/// true if the peer got the right directly or through one of its groups
+fn acl_allows(
+    acl: &BTreeMap<Grantee, BTreeSet<Right>>,
+    peer: &str,
+    groups: &Groups,
+    right: Right,
+) -> bool {
+    acl.iter().any(|(grantee, rights)| {
+        rights.contains(&right)
+            && match grantee {
+                Grantee::Host(x) => x.as_str() == peer,
+                Grantee::Group(x) => groups.get(x).is_some_and(|x| x.iter().any(|x| x == peer)),
+            }
+    })
+}
+
+/// a single file we host
+#[derive(Debug, Clone)]
+pub struct HostedFile {
+    pub path: PathBuf,
+    pub nickname: Option<String>,
+    pub tags: BTreeSet<String>,
+    pub size: u64,
+    pub mtime: Option<SystemTime>,
+    /// rights of other peers, hosts in here are always peer ids
+    pub acl: BTreeMap<Grantee, BTreeSet<Right>>,
+}
+impl HostedFile {
+    fn read(path: PathBuf, nickname: Option<String>, tags: BTreeSet<String>) -> io::Result<Self> {
+        let metadata = std::fs::symlink_metadata(&path)?;
+        Ok(Self {
+            path,
+            nickname,
+            tags,
+            size: metadata.len(),
+            mtime: metadata.modified().ok(),
+            acl: BTreeMap::new(),
+        })
+    }
+    /// true if the peer got the right directly or through one of its groups
+    pub fn allows(&self, peer: &str, groups: &Groups, right: Right) -> bool {
+        acl_allows(&self.acl, peer, groups, right)
+    }
+    fn share(&mut self, shares: &[Share], revoke: bool) {
+        share_acl(&mut self.acl, shares, revoke);
+    }
+    pub fn matches(&self, target: &Target) -> bool {
+        match target {
+            Target::Nickname(x) => self.nickname.as_ref() == Some(x),
+            Target::Tags(tags) => tags.iter().all(|x| self.tags.contains(x)),
+        }
+    }
+    pub fn info(&self) -> FileInfo {
+        FileInfo {
+            path: self.path.clone(),
+            nickname: self.nickname.clone(),
+            tags: self.tags.iter().cloned().collect(),
+            size: self.size,
+            mtime: self.mtime,
+            shares: self
+                .acl
+                .iter()
+                .map(|(grantee, rights)| Share {
+                    grantee: grantee.clone(),
+                    rights: rights.clone(),
+                })
+                .collect(),
+            meta: FileMeta::default(),
+            kind: EntryKind::default(),
+        }
+    }
+    /// the info a peer gets, without who else the file is shared with
+    pub fn public_info(&self) -> FileInfo {
+        FileInfo {
+            shares: Vec::new(),
+            ..self.info()
+        }
+    }
+}
+
+/// a directory we host, files that show up below it later get its tags and rights
+#[derive(Debug, Clone)]
+pub struct HostedDir {
+    pub tags: BTreeSet<String>,
+    pub acl: BTreeMap<Grantee, BTreeSet<Right>>,
+    /// which files below it are left out
+    pub filter: Filter,
+}
+impl HostedDir {
+    /// true if the peer got the right on the directory directly or through one of its groups
+    pub fn allows(&self, peer: &str, groups: &Groups, right: Right) -> bool {
+        acl_allows(&self.acl, peer, groups, right)
+    }
+    fn matches(&self, target: &Target) -> bool {
+        match target {
+            Target::Nickname(_) => false,
+            Target::Tags(tags) => tags.iter().all(|x| self.tags.contains(x)),
+        }
+    }
+}
+
+/// what hosting a target found on disk, see [`Catalog::host`]
+///
+/// the disk is read without the catalog, so it does not have to be locked while a large
+/// directory is walked
+#[derive(Debug)]
+pub enum HostScan {
+    File(HostedFile),
+    Dir {
+        path: PathBuf,
+        filter: Filter,
+        files: Vec<HostedFile>,
+    },
+}
+impl HostScan {
+    pub fn new(target: &FileTarget, ignore: &IgnoreConfig) -> io::Result<Self> {
+        match target {
+            FileTarget::File { path, nickname } => {
+                let path = std::fs::canonicalize(path)?;
+                Ok(Self::File(HostedFile::read(
+                    path,
+                    nickname.clone(),
+                    BTreeSet::new(),
+                )?))
+            }
+            FileTarget::Dir { path, exclude } => {
+                let path = std::fs::canonicalize(path)?;
+                let filter = Filter::new(&path, ignore, exclude)?;
+                let files = filter
+                    .walk(&path)?
+                    .into_iter()
+                    .map(|path| HostedFile::read(path, None, BTreeSet::new()))
+                    .collect::<io::Result<_>>()?;
+                Ok(Self::Dir {
+                    path,
+                    filter,
+                    files,
+                })
+            }
+        }
+    }
+}
+
+/// what a path that changed looks like on disk now, see [`Catalog::refresh`]
+///
+/// like [`HostScan`] it is read without the catalog
+#[derive(Debug)]
+pub struct Scan {
+    path: PathBuf,
+    found: Found,
+}
+#[derive(Debug)]
+enum Found {
+    /// the files below a directory that are not ignored, `None` for those that could not be read
+    Walked(Vec<(PathBuf, Option<HostedFile>)>),
+    File {
+        file: Option<HostedFile>,
+        /// whether the innermost hosted directory ignores it
+        ignored: bool,
+    },
+    /// special files are never hosted
+    Special,
+    Gone,
+}
+impl Scan {
+    /// look at a path, `filter` is that of the innermost hosted directory it is in
+    pub fn new(path: PathBuf, filter: Option<&Filter>) -> Self {
+        let read = |path: &Path| HostedFile::read(path.to_path_buf(), None, BTreeSet::new()).ok();
+        let found = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => match filter {
+                Some(filter) => Found::Walked(
+                    filter
+                        .walk(&path)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|x| {
+                            let file = read(&x);
+                            (x, file)
+                        })
+                        .collect(),
+                ),
+                None => Found::Special,
+            },
+            Ok(metadata) if metadata.is_file() || metadata.is_symlink() => Found::File {
+                file: read(&path),
+                ignored: filter.is_some_and(|x| x.ignores(&path)),
+            },
+            Ok(_) => Found::Special,
+            Err(_) => Found::Gone,
+        };
+        Self { path, found }
+    }
+}
+
+/// every file we host, keyed by absolute path
+///
+/// hosting a directory hosts every file below it that is not ignored, the directory is
+/// remembered so files that are added to it later are hosted as well, see [`Catalog::refresh`]
+///
+/// changed ignore rules only apply to files that are new, hosting the directory again applies
+/// them to everything
+#[derive(Debug, Default)]
+pub struct Catalog {
+    files: BTreeMap<PathBuf, HostedFile>,
+    dirs: BTreeMap<PathBuf, HostedDir>,
+}
+impl Catalog {
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+    pub fn files(&self) -> impl Iterator<Item = &HostedFile> {
+        self.files.values()
+    }
+    pub fn get(&self, path: &Path) -> Option<&HostedFile> {
+        self.files.get(path)
+    }
+    pub fn dirs(&self) -> impl Iterator<Item = &Path> {
+        self.dirs.keys().map(PathBuf::as_path)
+    }
+    /// a directory that was hosted as a whole
+    pub fn dir(&self, path: &Path) -> Option<&HostedDir> {
+        self.dirs.get(path)
+    }
+    /// the innermost hosted directory a path is in
+    fn dir_of(&self, path: &Path) -> Option<&HostedDir> {
+        self.dirs
+            .iter()
+            .filter(|(dir, _)| path.starts_with(dir))
+            .max_by_key(|(dir, _)| dir.components().count())
+            .map(|(_, x)| x)
+    }
+    pub fn matching<'a>(&'a self, target: &'a Target) -> impl Iterator<Item = &'a HostedFile> {
+        self.files.values().filter(move |x| x.matches(target))
+    }
+
+    /// host a file or every file in a directory, returns how many files were added or updated
+    ///
+    /// the shares are added to those of files that were already hosted, the hosts in them have to
+    /// be peer ids
+    pub fn host(&mut self, scan: HostScan, tags: &[String], shares: &[Share]) -> usize {
+        let tags: BTreeSet<String> = tags.iter().cloned().collect();
+        let files = match scan {
+            HostScan::File(file) => vec![file],
+            HostScan::Dir {
+                path,
+                filter,
+                files,
+            } => {
+                // files that are ignored now are no longer hosted, files with a nickname were
+                // hosted by themselves and stay
+                self.files.retain(|x, file| {
+                    !x.starts_with(&path)
+                        || file.nickname.is_some()
+                        || files.binary_search_by(|file| file.path.cmp(x)).is_ok()
+                });
+                let mut acl = self.dirs.remove(&path).map(|x| x.acl).unwrap_or_default();
+                share_acl(&mut acl, shares, false);
+                let dir = HostedDir {
+                    tags: tags.clone(),
+                    acl,
+                    filter,
+                };
+                self.dirs.insert(path, dir);
+                files
+            }
+        };
+        let count = files.len();
+        for mut file in files {
+            file.tags = tags.clone();
+            if let Some(old) = self.files.get(&file.path) {
+                file.acl = old.acl.clone();
+            }
+            file.share(shares, false);
+            self.files.insert(file.path.clone(), file);
+        }
+        count
+    }
+    /// change the rights on every file that matches the target, returns how many files matched
+    pub fn share(&mut self, target: &Target, shares: &[Share], revoke: bool) -> usize {
+        let mut count = 0;
+        for file in self.files.values_mut().filter(|x| x.matches(target)) {
+            file.share(shares, revoke);
+            count += 1;
+        }
+        for dir in self.dirs.values_mut().filter(|x| x.matches(target)) {
+            share_acl(&mut dir.acl, shares, revoke);
+        }
+        count
+    }
+    /// stop hosting every file and directory that matches the target, returns how many files
+    /// were removed
+    pub fn unhost(&mut self, target: &Target) -> usize {
+        let before = self.files.len();
+        self.files.retain(|_, x| !x.matches(target));
+        self.dirs.retain(|_, x| !x.matches(target));
+        before - self.files.len()
+    }
+
+    /// what has to be looked at on disk to refresh the paths, see [`Scan::new`]
+    pub fn to_scan(&self, paths: Vec<PathBuf>) -> Vec<(PathBuf, Option<Filter>)> {
+        paths
+            .into_iter()
+            .map(|x| {
+                let filter = self.dir_of(&x).map(|x| x.filter.clone());
+                (x, filter)
+            })
+            .collect()
+    }
+    /// every hosted file and directory, to compare them to what is on disk and catch up on
+    /// changes the watcher missed
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.files.keys().chain(self.dirs.keys()).cloned().collect()
+    }
+
+    /// bring a path and everything below it up to date after it changed on disk, returns the
+    /// files that were added, changed or removed, removed files with their last known state
+    pub fn refresh(&mut self, scan: Scan) -> Vec<HostedFile> {
+        let mut changed = Vec::new();
+        match scan.found {
+            Found::Walked(files) => {
+                if self.dir_of(&scan.path).is_none() {
+                    return changed;
+                }
+                for file in files.into_iter().filter_map(|(_, x)| x) {
+                    changed.extend(self.refresh_file(file, false));
+                }
+            }
+            Found::File { file, ignored } => {
+                changed.extend(file.and_then(|x| self.refresh_file(x, ignored)))
+            }
+            Found::Special => {}
+            Found::Gone => {
+                let gone: Vec<PathBuf> = self
+                    .files
+                    .keys()
+                    .filter(|x| x.starts_with(&scan.path))
+                    .cloned()
+                    .collect();
+                for path in gone {
+                    changed.extend(self.files.remove(&path));
+                }
+            }
+        }
+        changed
+    }
+    /// `ignored` is set when the innermost hosted directory ignores the file, only new files are
+    /// left out for that
+    fn refresh_file(&mut self, read: HostedFile, ignored: bool) -> Option<HostedFile> {
+        let (nickname, tags, acl) = match self.files.get(&read.path) {
+            Some(x) => (x.nickname.clone(), x.tags.clone(), x.acl.clone()),
+            None => {
+                let dir = self.dir_of(&read.path)?;
+                if ignored {
+                    return None;
+                }
+                (None, dir.tags.clone(), dir.acl.clone())
+            }
+        };
+        let file = HostedFile {
+            nickname,
+            tags,
+            acl,
+            ..read
+        };
+        if let Some(old) = self.files.get(&file.path) {
+            if old.size == file.size && old.mtime == file.mtime {
+                return None;
+            }
+        }
+        self.files.insert(file.path.clone(), file.clone());
+        Some(file)
+    }
+    pub fn file_infos(&self) -> Vec<FileInfo> {
+        self.files.values().map(HostedFile::info).collect()
+    }
+    /// the files a peer has a right on
+    pub fn allowed<'a>(
+        &'a self,
+        peer: &'a str,
+        groups: &'a Groups,
+        right: Right,
+    ) -> impl Iterator<Item = &'a HostedFile> {
+        self.files
+            .values()
+            .filter(move |x| x.allows(peer, groups, right))
+    }
+}
@@ -0,0 +1,250 @@
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+use backit_core::ipc::to_server::Target;
+use bytesize::ByteSize;
+use eyre::WrapErr;
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    catalog::Groups, filter::IgnoreConfig, hooks::HooksConfig, metrics::MetricsConfig,
+    peerset::PeerSetConfig, queue::QueueConfig, retention::Retention, scripts::JobScript,
+    sync::SyncConfig, throttle::ThrottleConfig,
+};
+
+/// environment variable that overrides the location of the config file
+pub const CONFIG_ENV: &str = "BACKITD_CONFIG";
+
+/// path of the config file
+///
+/// this is `$BACKITD_CONFIG` when set, otherwise `$XDG_CONFIG_HOME/backit/backitd.json` with a
+/// fallback to `~/.config/backit/backitd.json`
+pub(crate) fn config_path() -> PathBuf {
+    if let Some(path) = std::env::var_os(CONFIG_ENV).filter(|x| !x.is_empty()) {
+        return path.into();
+    }
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME").filter(|x| !x.is_empty()) {
+        Some(x) => PathBuf::from(x),
+        None => PathBuf::from(std::env::var_os("HOME").unwrap_or_default()).join(".config"),
+    };
+    config_dir.join("backit").join("backitd.json")
+}
+
+/// directory the daemon keeps its state in
+///
+/// this is `$XDG_DATA_HOME/backit` with a fallback to `~/.local/share/backit`
+pub(crate) fn data_dir() -> PathBuf {
+    let data_dir = match std::env::var_os("XDG_DATA_HOME").filter(|x| !x.is_empty()) {
+        Some(x) => PathBuf::from(x),
+        None => PathBuf::from(std::env::var_os("HOME").unwrap_or_default())
+            .join(".local")
+            .join("share"),
+    };
+    data_dir.join("backit")
+}
+
+/// the daemon config, every field has a default so an empty or missing file is a valid config
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub ipc: IpcConfig,
+    pub p2p: P2pConfig,
+    pub store: StoreConfig,
+    /// settings of backup jobs by job name, a job does not need an entry to be backed up
+    pub jobs: BTreeMap<String, JobConfig>,
+    /// sets of peers a backup can be spread over, a set is used by passing its name as host
+    pub sets: BTreeMap<String, PeerSetConfig>,
+    pub quota: QuotaConfig,
+    /// named groups of peer ids that hosted files can be shared with
+    pub groups: Groups,
+    pub inbox: InboxConfig,
+    pub watch: WatchConfig,
+    /// which files below hosted directories are left out
+    pub ignore: IgnoreConfig,
+    pub sync: SyncConfig,
+    /// rate limits on the traffic with other daemons
+    pub throttle: ThrottleConfig,
+    /// how many fetches, pushes and backups run at the same time and how they are retried
+    pub queue: QueueConfig,
+    pub metrics: MetricsConfig,
+    /// what runs on backups, verification failures, offline peers and quotas
+    pub hooks: HooksConfig,
+}
+impl Config {
+    /// read the config from [`config_path`], a missing file gives the default config
+    pub fn load() -> eyre::Result<Self> {
+        let path = config_path();
+        match std::fs::read(&path) {
+            Ok(x) => serde_json::from_slice(&x)
+                .wrap_err_with(|| format!("invalid config file {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => {
+                Err(e).wrap_err_with(|| format!("could not read config file {}", path.display()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct P2pConfig {
+    /// addresses to accept connections from other daemons on
+    pub listen: Vec<Multiaddr>,
+}
+impl Default for P2pConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec!["/ip4/0.0.0.0/tcp/0".parse().expect("valid multiaddr")],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoreConfig {
+    /// chunks a backup stored or reused less than this long ago are never garbage collected
+    #[serde(with = "humantime_serde")]
+    pub gc_grace: Duration,
+    /// how often the whole store is read back and checked in the background, `null` disables it
+    #[serde(with = "humantime_serde")]
+    pub scrub_interval: Option<Duration>,
+}
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            gc_grace: Duration::from_secs(24 * 60 * 60),
+            scrub_interval: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PushPolicy {
+    /// move pushed files into the inbox right away
+    Accept,
+    /// keep pushed files aside until they are accepted with `pending`
+    #[default]
+    Ask,
+    /// refuse pushes
+    Reject,
+}
+
+/// what happens to files other peers push to us
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InboxConfig {
+    /// policy for peers without an entry in `peers`
+    pub policy: PushPolicy,
+    /// policies by peer id
+    pub peers: BTreeMap<String, PushPolicy>,
+    /// inbox by peer id, the default is `inbox/<peer id>` in the data directory
+    pub dirs: BTreeMap<String, PathBuf>,
+}
+impl InboxConfig {
+    pub fn policy(&self, peer: &str) -> PushPolicy {
+        self.peers.get(peer).copied().unwrap_or(self.policy)
+    }
+    pub fn dir(&self, peer: &str) -> PathBuf {
+        match self.dirs.get(peer) {
+            Some(x) => x.clone(),
+            None => data_dir().join("inbox").join(peer),
+        }
+    }
+}
+
+/// limits on what peers can push to us
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// quota of a peer without an entry in `peers`, no limit when unset
+    pub default: Option<ByteSize>,
+    /// quotas by peer id
+    pub peers: BTreeMap<String, ByteSize>,
+    /// pushes are refused once the disk of the store has less than this free
+    pub reserve: ByteSize,
+}
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            default: None,
+            peers: BTreeMap::new(),
+            reserve: ByteSize::gib(1),
+        }
+    }
+}
+impl QuotaConfig {
+    pub fn quota(&self, peer: &str) -> Option<u64> {
+        self.peers.get(peer).or(self.default.as_ref()).map(|x| x.0)
+    }
+}
+
+/// how hosted directories are kept current
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WatchConfig {
+    /// how often every hosted file and directory is compared to what is on disk, this catches
+    /// changes the watcher missed
+    #[serde(with = "humantime_serde")]
+    pub rescan_interval: Duration,
+}
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            rescan_interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobConfig {
+    pub retention: Retention,
+    /// back the job up by itself when its files change
+    pub auto: Option<AutoBackup>,
+    /// runs before every backup of the job
+    pub pre: Option<JobScript>,
+    /// runs after every backup of the job
+    pub post: Option<JobScript>,
+}
+
+/// a backup that runs once the files of a job stopped changing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutoBackup {
+    /// the hosted files that make up the job
+    pub target: Target,
+    /// nickname or peer id of the host to back up to, the local store when unset
+    #[serde(default)]
+    pub host: Option<String>,
+    /// how long the files have to be left alone before the backup starts
+    #[serde(default = "default_debounce", with = "humantime_serde")]
+    pub debounce: Duration,
+}
+fn default_debounce() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// who may send commands over the ipc socket
+///
+/// the user running the daemon is always allowed
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IpcConfig {
+    /// extra users that are allowed, by uid
+    pub allowed_uids: Vec<u32>,
+    /// extra groups that are allowed, by group name, a user is allowed if this contains its
+    /// primary group or one of its supplementary groups
+    pub allowed_groups: Vec<String>,
+}
+impl IpcConfig {
+    /// true if users other than the daemon user can connect, this makes the socket world
+    /// connectable and its directory 0711 so the others can reach it
+    ///
+    /// the default socket directory below `$XDG_RUNTIME_DIR` is in a directory only the daemon
+    /// user can enter, so a shared daemon and its users set `BACKIT_SOCKET` to a socket outside
+    /// of it, like `/tmp/backit-<uid>.sock`. the daemon does not change the modes of that
+    /// directory, it has to be the daemon user's and not writable by others, or sticky like `/tmp`
+    pub fn is_shared(&self) -> bool {
+        !self.allowed_uids.is_empty() || !self.allowed_groups.is_empty()
+    }
+}
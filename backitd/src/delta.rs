@@ -0,0 +1,263 @@
+//! transferring files as deltas to a copy the other side already has, see [`backit_core::delta`]
+//!
+//! for a fetch we send the signature of the copy we fetched before and the peer sends the ops,
+//! for a push the peer sends the signature of the copy in its inbox and we send the ops. the
+//! signature of a fetch is kept in a session so it only crosses the network once, a session the
+//! fetcher never finishes is dropped once it was left alone for a while
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use backit_core::{
+    delta::{self, DeltaOp, Matcher, Signature},
+    ipc::{
+        to_server::{AnyHost, Right},
+        FileInfo, PushId, ServerError,
+    },
+    tcp::{DeltaId, ReceivePacket, SendPacket},
+};
+use libp2p::PeerId;
+
+use crate::{meta, progress::ProgressTracker, share::FILE_PIECE, Server};
+
+/// how much of a file a single packet of ops may cover, so making them stays well within the
+/// request timeout
+const MAX_DELTA_INPUT: u64 = 256 * 1024 * 1024;
+
+/// a session nobody read from for this long is dropped
+const SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+/// the most delta fetches a single peer can have open, starting another one drops its oldest
+const MAX_SESSIONS: usize = 16;
+
+/// a delta fetch a peer started
+pub struct DeltaSession {
+    peer: PeerId,
+    path: PathBuf,
+    signature: Arc<Signature>,
+    used: Instant,
+}
+
+/// the signature of a file, `None` when there is no such file or a symlink in its place, this
+/// blocks
+pub fn signature_of(path: &Path) -> io::Result<Option<Signature>> {
+    match std::fs::symlink_metadata(path) {
+        Ok(x) if x.is_symlink() => return Ok(None),
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let file = meta::open(path)?;
+    let len = file.metadata()?.len();
+    delta::signature(BufReader::new(file), len).map(Some)
+}
+
+/// the ops for at most `len` bytes of a file from `offset` on, with how much they cover, this
+/// blocks
+pub fn make_delta(
+    path: &Path,
+    offset: u64,
+    len: u64,
+    signature: &Signature,
+) -> io::Result<(Vec<DeltaOp>, u64)> {
+    let mut file = meta::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    Matcher::new(signature).delta(file.take(len), FILE_PIECE as usize, MAX_DELTA_INPUT)
+}
+
+/// write ops to `dest` from `offset` on, the blocks they copy come from `base`, returns how many
+/// bytes were written, this blocks
+pub fn apply_delta(
+    base: &Path,
+    dest: &Path,
+    offset: u64,
+    block_size: u32,
+    ops: &[DeltaOp],
+) -> io::Result<u64> {
+    let mut old = meta::open(base)?;
+    let mut out = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dest)?;
+    out.seek(SeekFrom::Start(offset))?;
+    let mut out = BufWriter::new(out);
+    let mut len = 0;
+    for op in ops {
+        delta::apply(op, block_size, &mut old, &mut out)?;
+        len += op.len(block_size);
+    }
+    out.flush()?;
+    Ok(len)
+}
+
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> Result<T, ServerError> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| ServerError::Io(e.to_string()))?
+        .map_err(|e| ServerError::Io(e.to_string()))
+}
+
+impl Server {
+    /// start a delta fetch of a hosted file for a peer, it needs the same right as a fetch
+    pub async fn start_delta(
+        &self,
+        peer: &PeerId,
+        path: PathBuf,
+        signature: Signature,
+    ) -> Result<DeltaId, ServerError> {
+        let mut state = self.state.lock().await;
+        let allowed = state
+            .catalog
+            .get(&path)
+            .is_some_and(|x| x.allows(&peer.to_string(), &self.config.groups, Right::Read));
+        if !allowed {
+            return Err(ServerError::PermissionDenied);
+        }
+        // the matcher would never move on with empty blocks
+        if signature.block_size < delta::MIN_BLOCK
+            || signature.blocks.len() as u64 > delta::MAX_BLOCKS
+        {
+            return Err(ServerError::InvalidPacket);
+        }
+        state.deltas.retain(|_, x| x.used.elapsed() < SESSION_TTL);
+        let mut open: Vec<(Instant, DeltaId)> = state
+            .deltas
+            .iter()
+            .filter(|(_, x)| x.peer == *peer)
+            .map(|(id, x)| (x.used, *id))
+            .collect();
+        open.sort();
+        for (_, id) in open
+            .iter()
+            .take((open.len() + 1).saturating_sub(MAX_SESSIONS))
+        {
+            state.deltas.remove(id);
+        }
+        let id = self
+            .store
+            .db()
+            .generate_id()
+            .map_err(|e| ServerError::Io(e.to_string()))?;
+        let session = DeltaSession {
+            peer: *peer,
+            path,
+            signature: Arc::new(signature),
+            used: Instant::now(),
+        };
+        state.deltas.insert(id, session);
+        Ok(id)
+    }
+    pub async fn read_delta(
+        &self,
+        peer: &PeerId,
+        id: DeltaId,
+        offset: u64,
+    ) -> Result<Vec<DeltaOp>, ServerError> {
+        let (path, signature) = match self.state.lock().await.deltas.get_mut(&id) {
+            Some(x) if x.peer == *peer => {
+                x.used = Instant::now();
+                (x.path.clone(), x.signature.clone())
+            }
+            _ => return Err(ServerError::InvalidPacket),
+        };
+        let (ops, _) = blocking(move || make_delta(&path, offset, u64::MAX, &signature)).await?;
+        Ok(ops)
+    }
+    pub async fn finish_delta(&self, peer: &PeerId, id: DeltaId) {
+        let mut state = self.state.lock().await;
+        if state.deltas.get(&id).is_some_and(|x| x.peer == *peer) {
+            state.deltas.remove(&id);
+        }
+    }
+
+    /// fetch a file as a delta to `base`, the result is written to `dest`
+    pub async fn fetch_delta(
+        &self,
+        host: &AnyHost,
+        file: &FileInfo,
+        base: &Path,
+        dest: &Path,
+        signature: Signature,
+        progress: &mut ProgressTracker,
+    ) -> Result<(), ServerError> {
+        let block_size = signature.block_size;
+        let request = SendPacket::StartDelta {
+            path: file.path.clone(),
+            signature,
+        };
+        let id = match self.request(host, request).await? {
+            ReceivePacket::DeltaStarted(x) => x,
+            _ => return Err(ServerError::InvalidPacket),
+        };
+        // the new copy has to be written from scratch, not on top of an older partial one
+        let _ = tokio::fs::remove_file(dest).await;
+        let mut offset = 0;
+        loop {
+            let ops = match self
+                .request(host, SendPacket::ReadDelta { id, offset })
+                .await?
+            {
+                ReceivePacket::Delta(x) => x,
+                _ => return Err(ServerError::InvalidPacket),
+            };
+            if ops.is_empty() {
+                break;
+            }
+            let (base, dest) = (base.to_path_buf(), dest.to_path_buf());
+            let len = blocking(move || apply_delta(&base, &dest, offset, block_size, &ops)).await?;
+            offset += len;
+            progress.add_bytes(len);
+        }
+        self.request(host, SendPacket::FinishDelta(id)).await?;
+        // an empty file never got any ops
+        if offset == 0 {
+            tokio::fs::File::create(dest)
+                .await
+                .map_err(|e| ServerError::Io(format!("{}: {e}", dest.display())))?;
+        }
+        Ok(())
+    }
+
+    /// send a file of a push as a delta to the copy the peer has
+    pub async fn push_delta_file(
+        &self,
+        host: &AnyHost,
+        id: PushId,
+        index: usize,
+        file: &FileInfo,
+        signature: Signature,
+        progress: &mut ProgressTracker,
+    ) -> Result<(), ServerError> {
+        let signature = Arc::new(signature);
+        let mut offset = 0;
+        // only send what was announced, even when the file grew since
+        while offset < file.size {
+            let (path, len, sig) = (file.path.clone(), file.size - offset, signature.clone());
+            let (ops, covered) = blocking(move || make_delta(&path, offset, len, &sig)).await?;
+            if ops.is_empty() {
+                break;
+            }
+            let request = SendPacket::PushDelta {
+                id,
+                index,
+                offset,
+                block_size: signature.block_size,
+                ops,
+            };
+            match self.request(host, request).await? {
+                ReceivePacket::Ok => {}
+                _ => return Err(ServerError::InvalidPacket),
+            }
+            offset += covered;
+            progress.add_bytes(covered);
+        }
+        Ok(())
+    }
+}
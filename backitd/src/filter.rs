@@ -0,0 +1,179 @@
+//! which files below a hosted directory are left out
+//!
+//! a directory can hold `.backitignore` files with the syntax of `.gitignore`, on top of those
+//! the globs from the config and the ones given to `host` apply. special files are never hosted,
+//! symlinks are hosted as links
+
+use std::{
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use bytesize::ByteSize;
+use ignore::{
+    gitignore::Gitignore,
+    overrides::{Override, OverrideBuilder},
+    Match, WalkBuilder,
+};
+use serde::{Deserialize, Serialize};
+
+/// name of the ignore files looked for in hosted directories
+pub const IGNORE_FILE: &str = ".backitignore";
+
+/// the tag that marks a cache directory, see <https://bford.info/cachedir/>
+const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// ignore rules for every hosted directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IgnoreConfig {
+    /// when not empty only files matching one of these globs are hosted
+    pub include: Vec<String>,
+    /// files and directories matching these globs are left out, like `target/` or
+    /// `node_modules/`
+    pub exclude: Vec<String>,
+    /// files bigger than this are left out
+    pub max_size: Option<ByteSize>,
+    /// leave out directories with a `CACHEDIR.TAG`, which cargo puts in `target/`
+    pub skip_caches: bool,
+}
+impl Default for IgnoreConfig {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            max_size: None,
+            skip_caches: true,
+        }
+    }
+}
+
+fn glob_err(e: ignore::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+/// true if a directory has a valid cache directory tag
+fn is_cache(dir: &Path) -> bool {
+    let mut signature = [0; CACHEDIR_SIGNATURE.len()];
+    std::fs::File::open(dir.join(CACHEDIR_TAG))
+        .and_then(|mut x| x.read_exact(&mut signature))
+        .is_ok_and(|()| signature == CACHEDIR_SIGNATURE)
+}
+
+/// the rules of a single hosted directory
+#[derive(Debug, Clone)]
+pub struct Filter {
+    root: PathBuf,
+    overrides: Override,
+    max_size: Option<u64>,
+    skip_caches: bool,
+}
+impl Filter {
+    /// `exclude` are extra globs given when the directory was hosted
+    pub fn new(root: &Path, config: &IgnoreConfig, exclude: &[String]) -> io::Result<Self> {
+        let mut overrides = OverrideBuilder::new(root);
+        for glob in &config.include {
+            overrides.add(glob).map_err(glob_err)?;
+        }
+        for glob in config.exclude.iter().chain(exclude) {
+            overrides.add(&format!("!{glob}")).map_err(glob_err)?;
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+            overrides: overrides.build().map_err(glob_err)?,
+            max_size: config.max_size.map(|x| x.0),
+            skip_caches: config.skip_caches,
+        })
+    }
+    /// the directories from the root down to `dir`, both included
+    fn ancestors<'a>(&'a self, dir: &'a Path) -> impl Iterator<Item = &'a Path> {
+        dir.ancestors().take_while(|x| x.starts_with(&self.root))
+    }
+    fn ignores_dir(&self, dir: &Path) -> bool {
+        (self.skip_caches && is_cache(dir)) || self.overrides.matched(dir, true).is_ignore()
+    }
+
+    /// every file below `dir` that is not left out, `dir` is the root or a directory below it
+    pub fn walk(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        if self.ancestors(dir).any(|x| self.ignores_dir(x)) {
+            return Ok(Vec::new());
+        }
+        let mut walker = WalkBuilder::new(dir);
+        walker
+            .standard_filters(false)
+            .add_custom_ignore_filename(IGNORE_FILE)
+            .overrides(self.overrides.clone())
+            .max_filesize(self.max_size);
+        // the ignore files between the root and `dir` still apply
+        for parent in self.ancestors(dir).skip(1) {
+            let file = parent.join(IGNORE_FILE);
+            if file.is_file() {
+                if let Some(e) = walker.add_ignore(file) {
+                    return Err(glob_err(e));
+                }
+            }
+        }
+        let skip_caches = self.skip_caches;
+        walker.filter_entry(move |entry| {
+            !(skip_caches
+                && entry.file_type().is_some_and(|x| x.is_dir())
+                && is_cache(entry.path()))
+        });
+
+        let mut out = Vec::new();
+        for entry in walker.build() {
+            let entry = entry.map_err(|e| match e.into_io_error() {
+                Some(e) => e,
+                None => io::Error::other("invalid ignore file"),
+            })?;
+            if entry
+                .file_type()
+                .is_some_and(|x| x.is_file() || x.is_symlink())
+            {
+                out.push(entry.into_path());
+            }
+        }
+        out.sort();
+        Ok(out)
+    }
+    /// true if a single file below the root is left out, this gives the same answer as
+    /// [`Filter::walk`] without walking the whole directory
+    pub fn ignores(&self, path: &Path) -> bool {
+        let Ok(metadata) = std::fs::symlink_metadata(path) else {
+            return true;
+        };
+        let hosted = metadata.is_file() || metadata.is_symlink();
+        if !hosted || self.max_size.is_some_and(|x| metadata.len() > x) {
+            return true;
+        }
+        self.excludes(path)
+    }
+    /// true if the globs or ignore files leave out a path below the root, it does not have to
+    /// exist, like a file a peer is about to sync to us
+    pub fn excludes(&self, path: &Path) -> bool {
+        if self.overrides.matched(path, false).is_ignore() {
+            return true;
+        }
+        let Some(parent) = path.parent() else {
+            return false;
+        };
+        if self.ancestors(parent).any(|x| self.ignores_dir(x)) {
+            return true;
+        }
+        // the ignore file closest to the path that has an opinion wins
+        for dir in self.ancestors(parent) {
+            let file = dir.join(IGNORE_FILE);
+            if !file.is_file() {
+                continue;
+            }
+            let (ignore, _) = Gitignore::new(file);
+            match ignore.matched_path_or_any_parents(path, false) {
+                Match::None => {}
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+        false
+    }
+}
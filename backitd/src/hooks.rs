@@ -0,0 +1,465 @@
+//! hooks that tell the outside world about backups, verification, peers and quotas
+//!
+//! a hook is a local command, a desktop notification over d-bus or an http webhook. hooks run in
+//! the background, one that fails or hangs is reported and does not hold anything up. a peer
+//! counts as offline once no request to or from it succeeded for `peer_offline_after`, and the
+//! hook fires once until the peer is seen again
+
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{bail, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::{peerset, Server};
+
+/// how long a hook may take before it is given up
+const HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// how often the peers are checked for being offline
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    BackupSucceeded,
+    BackupFailed,
+    PeerOffline,
+    VerifyFailed,
+    QuotaNearing,
+}
+impl EventKind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::BackupSucceeded => "backup_succeeded",
+            Self::BackupFailed => "backup_failed",
+            Self::PeerOffline => "peer_offline",
+            Self::VerifyFailed => "verify_failed",
+            Self::QuotaNearing => "quota_nearing",
+        }
+    }
+}
+
+/// something a hook can run on, webhooks get it as json with the kind in `event`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    BackupSucceeded {
+        job: String,
+        snapshot: String,
+        /// where the snapshot is stored, locally when unset
+        host: Option<String>,
+    },
+    BackupFailed {
+        job: String,
+        host: Option<String>,
+        error: String,
+    },
+    PeerOffline {
+        peer: String,
+        /// unix time of the last request that succeeded
+        last_seen: u64,
+    },
+    VerifyFailed {
+        /// the host that stores the checked snapshots, locally when unset
+        host: Option<String>,
+        missing: usize,
+        corrupt: usize,
+        damaged: usize,
+    },
+    /// a peer uses more of the quota it has on us than `quota_warning`
+    QuotaNearing { peer: String, used: u64, quota: u64 },
+}
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Self::BackupSucceeded { .. } => EventKind::BackupSucceeded,
+            Self::BackupFailed { .. } => EventKind::BackupFailed,
+            Self::PeerOffline { .. } => EventKind::PeerOffline,
+            Self::VerifyFailed { .. } => EventKind::VerifyFailed,
+            Self::QuotaNearing { .. } => EventKind::QuotaNearing,
+        }
+    }
+
+    fn summary(&self) -> String {
+        match self {
+            Self::BackupSucceeded { job, .. } => format!("backup of {job} succeeded"),
+            Self::BackupFailed { job, .. } => format!("backup of {job} failed"),
+            Self::PeerOffline { peer, .. } => format!("peer {peer} is offline"),
+            Self::VerifyFailed { .. } => "verification found damaged backups".to_string(),
+            Self::QuotaNearing { peer, .. } => format!("peer {peer} nears its quota"),
+        }
+    }
+    fn body(&self) -> String {
+        let on = |x: &Option<String>| x.clone().unwrap_or_else(|| "this machine".to_string());
+        match self {
+            Self::BackupSucceeded { snapshot, host, .. } => {
+                format!("snapshot {snapshot} is stored on {}", on(host))
+            }
+            Self::BackupFailed { error, .. } => error.clone(),
+            Self::PeerOffline { last_seen, .. } => {
+                let at = UNIX_EPOCH + Duration::from_secs(*last_seen);
+                format!("last seen {}", humantime::format_rfc3339_seconds(at))
+            }
+            Self::VerifyFailed {
+                host,
+                missing,
+                corrupt,
+                damaged,
+            } => format!(
+                "{missing} missing and {corrupt} corrupt chunks on {}, {damaged} snapshots are damaged",
+                on(host)
+            ),
+            Self::QuotaNearing { used, quota, .. } => format!("{used} of {quota} bytes used"),
+        }
+    }
+    /// the environment of a command hook, every field as `BACKIT_<FIELD>`
+    fn env(&self) -> Vec<(String, String)> {
+        let mut out = vec![("BACKIT_EVENT".to_string(), self.kind().name().to_string())];
+        if let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(self) {
+            for (key, value) in fields {
+                let value = match value {
+                    serde_json::Value::Null => continue,
+                    serde_json::Value::String(x) => x,
+                    x => x.to_string(),
+                };
+                if key != "event" {
+                    out.push((format!("BACKIT_{}", key.to_uppercase()), value));
+                }
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HookAction {
+    /// run a program with the event in `BACKIT_*` environment variables
+    Command {
+        program: PathBuf,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// show a notification on the desktop of the user the daemon runs as
+    Desktop,
+    /// post the event as json
+    Webhook { url: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hook {
+    /// the events that run the hook, every event when empty
+    #[serde(default)]
+    pub on: Vec<EventKind>,
+    #[serde(flatten)]
+    pub action: HookAction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    pub hooks: Vec<Hook>,
+    #[serde(with = "humantime_serde")]
+    pub peer_offline_after: Duration,
+    /// share of its quota a peer has to use for `quota_nearing`
+    pub quota_warning: f64,
+}
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            hooks: Vec::new(),
+            peer_offline_after: Duration::from_secs(24 * 60 * 60),
+            quota_warning: 0.9,
+        }
+    }
+}
+
+/// the hooks of the daemon and what they already fired for
+#[derive(Debug)]
+pub struct Hooks {
+    config: HooksConfig,
+    /// peers that got a `quota_nearing` and have not dropped below the warning since
+    nearing: Mutex<HashSet<String>>,
+}
+impl Hooks {
+    pub fn new(config: HooksConfig) -> Self {
+        Self {
+            config,
+            nearing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// run every hook for the event in the background
+    pub fn emit(&self, event: Event) {
+        for hook in &self.config.hooks {
+            if !hook.on.is_empty() && !hook.on.contains(&event.kind()) {
+                continue;
+            }
+            let (action, event) = (hook.action.clone(), event.clone());
+            tokio::spawn(async move {
+                let result = tokio::time::timeout(HOOK_TIMEOUT, run(&action, &event)).await;
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => eprintln!("hook for {} failed: {e:#}", event.kind().name()),
+                    Err(_) => eprintln!("hook for {} timed out", event.kind().name()),
+                }
+            });
+        }
+    }
+
+    /// fire `quota_nearing` when a peer went over the warning, once until it drops below again
+    pub fn check_quota(&self, peer: &str, used: u64, quota: u64) {
+        let nearing = used as f64 >= quota as f64 * self.config.quota_warning;
+        let mut warned = self.nearing.lock().expect("not poisoned");
+        if !nearing {
+            warned.remove(peer);
+        } else if warned.insert(peer.to_string()) {
+            self.emit(Event::QuotaNearing {
+                peer: peer.to_string(),
+                used,
+                quota,
+            });
+        }
+    }
+}
+
+async fn run(action: &HookAction, event: &Event) -> eyre::Result<()> {
+    match action {
+        HookAction::Command { program, args } => {
+            let status = tokio::process::Command::new(program)
+                .args(args)
+                .envs(event.env())
+                .stdin(std::process::Stdio::null())
+                .kill_on_drop(true)
+                .status()
+                .await
+                .wrap_err_with(|| format!("could not run {}", program.display()))?;
+            if !status.success() {
+                bail!("{} exited with {status}", program.display());
+            }
+            Ok(())
+        }
+        HookAction::Desktop => notify_desktop(event).await,
+        HookAction::Webhook { url } => {
+            reqwest::Client::new()
+                .post(url)
+                .json(event)
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(())
+        }
+    }
+}
+
+/// show a notification through `org.freedesktop.Notifications` on the session bus
+#[cfg(unix)]
+async fn notify_desktop(event: &Event) -> eyre::Result<()> {
+    use std::collections::HashMap;
+
+    let connection = zbus::Connection::session().await?;
+    let hints: HashMap<&str, zbus::zvariant::Value> = HashMap::new();
+    // app name, id to replace, icon, summary, body, actions, hints and the default timeout
+    let body = (
+        "backit",
+        0u32,
+        "",
+        event.summary(),
+        event.body(),
+        Vec::<&str>::new(),
+        hints,
+        -1i32,
+    );
+    connection
+        .call_method(
+            Some("org.freedesktop.Notifications"),
+            "/org/freedesktop/Notifications",
+            Some("org.freedesktop.Notifications"),
+            "Notify",
+            &body,
+        )
+        .await?;
+    Ok(())
+}
+#[cfg(not(unix))]
+async fn notify_desktop(_event: &Event) -> eyre::Result<()> {
+    bail!("desktop notifications need d-bus")
+}
+
+impl Server {
+    /// fire `peer_offline` for peers that were not seen for too long, forever
+    pub async fn offline_loop(self) {
+        let after = self.hooks.config.peer_offline_after.as_secs();
+        let mut offline = HashSet::new();
+        loop {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            match peerset::last_seen(&self.store) {
+                Ok(peers) => {
+                    for (peer, last_seen) in peers {
+                        if now.saturating_sub(last_seen) <= after {
+                            offline.remove(&peer);
+                        } else if offline.insert(peer.clone()) {
+                            self.hooks.emit(Event::PeerOffline { peer, last_seen });
+                        }
+                    }
+                }
+                Err(e) => eprintln!("could not check for offline peers: {e:?}"),
+            }
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    use super::*;
+
+    fn succeeded() -> Event {
+        Event::BackupSucceeded {
+            job: "home".to_string(),
+            snapshot: "42".to_string(),
+            host: None,
+        }
+    }
+
+    /// answer a single http request with `status`, returns the url and the head and body of the
+    /// request
+    async fn serve(status: &'static str) -> (String, JoinHandle<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            // the head, then as much body as it announces
+            let (head, body) = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "the request ended early");
+                request.extend_from_slice(&buf[..n]);
+                let Some(end) = request.windows(4).position(|x| x == b"\r\n\r\n") else {
+                    continue;
+                };
+                let head = String::from_utf8_lossy(&request[..end]).into_owned();
+                let len: usize = head
+                    .lines()
+                    .find_map(|x| {
+                        let (key, value) = x.split_once(':')?;
+                        key.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse().unwrap())
+                    })
+                    .unwrap();
+                if request.len() >= end + 4 + len {
+                    break (head, request[end + 4..end + 4 + len].to_vec());
+                }
+            };
+            let response =
+                format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+            stream.write_all(response.as_bytes()).await.unwrap();
+            (head, body)
+        });
+        (url, task)
+    }
+
+    #[tokio::test]
+    async fn webhook() {
+        let (url, request) = serve("204 No Content").await;
+        run(&HookAction::Webhook { url }, &succeeded())
+            .await
+            .unwrap();
+        let (head, body) = request.await.unwrap();
+        assert!(head.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(head
+            .to_ascii_lowercase()
+            .contains("content-type: application/json"));
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "event": "backup_succeeded",
+                "job": "home",
+                "snapshot": "42",
+                "host": null,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn webhook_error_status() {
+        let (url, request) = serve("500 Internal Server Error").await;
+        assert!(run(&HookAction::Webhook { url }, &succeeded())
+            .await
+            .is_err());
+        request.await.unwrap();
+    }
+
+    #[test]
+    fn env() {
+        let env: BTreeMap<String, String> = succeeded().env().into_iter().collect();
+        let expected = [
+            ("BACKIT_EVENT", "backup_succeeded"),
+            ("BACKIT_JOB", "home"),
+            ("BACKIT_SNAPSHOT", "42"),
+        ];
+        assert_eq!(
+            env,
+            expected.map(|(k, v)| (k.to_string(), v.to_string())).into()
+        );
+        let event = Event::QuotaNearing {
+            peer: "peer".to_string(),
+            used: 90,
+            quota: 100,
+        };
+        let env: BTreeMap<String, String> = event.env().into_iter().collect();
+        assert_eq!(env["BACKIT_EVENT"], "quota_nearing");
+        assert_eq!(env["BACKIT_USED"], "90");
+        assert_eq!(env["BACKIT_QUOTA"], "100");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command() {
+        let out = std::env::temp_dir().join(format!("backit-hook-{}", std::process::id()));
+        let action = HookAction::Command {
+            program: "/bin/sh".into(),
+            args: vec![
+                "-c".to_string(),
+                "env > \"$0\"".to_string(),
+                out.display().to_string(),
+            ],
+        };
+        run(&action, &succeeded()).await.unwrap();
+        let env = std::fs::read_to_string(&out).unwrap();
+        let _ = std::fs::remove_file(&out);
+        let env: Vec<&str> = env.lines().filter(|x| x.starts_with("BACKIT_")).collect();
+        assert_eq!(
+            env.iter().copied().collect::<BTreeSet<_>>(),
+            BTreeSet::from([
+                "BACKIT_EVENT=backup_succeeded",
+                "BACKIT_JOB=home",
+                "BACKIT_SNAPSHOT=42",
+            ])
+        );
+
+        let action = HookAction::Command {
+            program: "/bin/sh".into(),
+            args: vec!["-c".to_string(), "exit 3".to_string()],
+        };
+        assert!(run(&action, &succeeded()).await.is_err());
+    }
+}
@@ -0,0 +1,456 @@
+//! files other peers push to us, and pushing our own files to them
+//!
+//! the data of a push is written to a staging directory while it comes in, once it is complete
+//! the push is moved into the inbox of the peer or waits there until it is accepted
+
+use std::{
+    collections::BTreeMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use backit_core::{
+    delta::{DeltaOp, Signature},
+    ipc::{
+        to_server::{AnyHost, HostId, Target},
+        FileInfo, PendingPush, PushId, PushStatus, ServerError,
+    },
+    snapshot::EntryKind,
+    tcp::{ReceivePacket, SendPacket},
+};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::{
+    backup::{check_inside, restore_path},
+    config::{self, PushPolicy},
+    delta::{apply_delta, signature_of},
+    meta,
+    progress::ProgressTracker,
+    reply::Responder,
+    share::{link_order, transfer_link, FILE_PIECE},
+    Server,
+};
+
+/// a push that is coming in or waits for approval
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Inbound {
+    peer: String,
+    time: SystemTime,
+    files: Vec<FileInfo>,
+    /// set once the sender finished the push
+    complete: bool,
+    /// bytes the peer was charged for the push, given back once it leaves the staging directory
+    #[serde(default)]
+    charged: u64,
+    /// the block size of the signature the peer got for a file, by index, its ops have to use it
+    #[serde(default)]
+    block_sizes: BTreeMap<usize, u32>,
+    /// how much of a file was received, by index, the data has to come in without gaps
+    #[serde(default)]
+    received: BTreeMap<usize, u64>,
+}
+
+impl Inbound {
+    /// note that a file got the data up to `end`, starting at `offset`
+    fn receive(&mut self, index: usize, offset: u64, end: u64) -> Result<(), ServerError> {
+        let received = self.received.entry(index).or_default();
+        if offset > *received {
+            return Err(ServerError::InvalidPacket);
+        }
+        *received = end.max(*received);
+        Ok(())
+    }
+    /// whether every file got all the data that was announced for it
+    fn is_received(&self) -> bool {
+        self.files.iter().enumerate().all(|(index, file)| {
+            file.kind != EntryKind::File
+                || self.received.get(&index).copied().unwrap_or(0) == file.size
+        })
+    }
+}
+
+/// where the files of a push are kept until it is accepted
+fn staging_dir(id: PushId) -> PathBuf {
+    config::data_dir().join("incoming").join(id.to_string())
+}
+
+fn db_err(e: impl ToString) -> ServerError {
+    ServerError::Io(e.to_string())
+}
+
+impl Server {
+    fn pushes(&self) -> Result<sled::Tree, ServerError> {
+        self.store.db().open_tree("pushes").map_err(db_err)
+    }
+    fn inbound(&self, id: PushId) -> Result<Inbound, ServerError> {
+        let bytes = self
+            .pushes()?
+            .get(id.to_be_bytes())
+            .map_err(db_err)?
+            .ok_or(ServerError::UnknownPush(id))?;
+        serde_cbor::from_slice(&bytes).map_err(db_err)
+    }
+    fn put_inbound(&self, id: PushId, inbound: &Inbound) -> Result<(), ServerError> {
+        let bytes = serde_cbor::to_vec(inbound).map_err(db_err)?;
+        self.pushes()?
+            .insert(id.to_be_bytes(), bytes)
+            .map_err(db_err)?;
+        Ok(())
+    }
+    /// a push of a peer, a push of someone else looks like one that does not exist
+    fn inbound_of(&self, peer: &PeerId, id: PushId) -> Result<Inbound, ServerError> {
+        let inbound = self.inbound(id)?;
+        if inbound.peer != peer.to_string() {
+            return Err(ServerError::UnknownPush(id));
+        }
+        Ok(inbound)
+    }
+
+    /// accept the announcement of a push, as long as the peer may push and we have room for it
+    pub fn start_push(&self, peer: &PeerId, files: Vec<FileInfo>) -> Result<PushId, ServerError> {
+        let owner = peer.to_string();
+        if self.config.inbox.policy(&owner) == PushPolicy::Reject {
+            return Err(ServerError::PermissionDenied);
+        }
+        // the paths end up below the staging directory and the inbox, they may only go down
+        for file in &files {
+            restore_path(&file.path, None)?;
+        }
+        let size = files.iter().map(|x| x.size).sum();
+        self.charge(&owner, size)?;
+        let inbound = Inbound {
+            peer: owner,
+            time: SystemTime::now(),
+            files,
+            complete: false,
+            charged: size,
+            block_sizes: BTreeMap::new(),
+            received: BTreeMap::new(),
+        };
+        let id = self
+            .store
+            .db()
+            .generate_id()
+            .map_err(db_err)
+            .and_then(|id| self.put_inbound(id, &inbound).map(|()| id));
+        if id.is_err() {
+            self.store.release(&inbound.peer, size)?;
+        }
+        id
+    }
+    pub async fn push_data(
+        &self,
+        peer: &PeerId,
+        id: PushId,
+        index: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), ServerError> {
+        let mut inbound = self.inbound_of(peer, id)?;
+        let file = inbound.files.get(index).ok_or(ServerError::InvalidPacket)?;
+        let end = offset.checked_add(data.len() as u64);
+        let Some(end) = end.filter(|x| !inbound.complete && *x <= file.size) else {
+            return Err(ServerError::InvalidPacket);
+        };
+        let file = file.clone();
+        inbound.receive(index, offset, end)?;
+        let staging = staging_dir(id);
+        let dest = restore_path(&file.path, Some(&staging))?;
+        check_inside(&staging, &dest)?;
+        let io_err = |e: std::io::Error| ServerError::Io(format!("{}: {e}", dest.display()));
+        if let Some(dir) = dest.parent() {
+            tokio::fs::create_dir_all(dir).await.map_err(io_err)?;
+        }
+        let mut out = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&dest)
+            .await
+            .map_err(io_err)?;
+        out.seek(SeekFrom::Start(offset)).await.map_err(io_err)?;
+        out.write_all(data).await.map_err(io_err)?;
+        out.flush().await.map_err(io_err)?;
+        self.put_inbound(id, &inbound)
+    }
+    /// the signature of the copy of a pushed file that is in the inbox of the peer
+    pub async fn push_signature(
+        &self,
+        peer: &PeerId,
+        id: PushId,
+        index: usize,
+    ) -> Result<Option<Signature>, ServerError> {
+        let mut inbound = self.inbound_of(peer, id)?;
+        let file = inbound.files.get(index).ok_or(ServerError::InvalidPacket)?;
+        let inbox = self.config.inbox.dir(&inbound.peer);
+        let base = restore_path(&file.path, Some(&inbox))?;
+        check_inside(&inbox, &base)?;
+        let signature = tokio::task::spawn_blocking(move || signature_of(&base))
+            .await
+            .map_err(db_err)?
+            .map_err(db_err)?;
+        if let Some(x) = &signature {
+            inbound.block_sizes.insert(index, x.block_size);
+            self.put_inbound(id, &inbound)?;
+        }
+        Ok(signature)
+    }
+    /// part of a pushed file as ops on the copy in the inbox of the peer
+    pub async fn push_delta(
+        &self,
+        peer: &PeerId,
+        id: PushId,
+        index: usize,
+        offset: u64,
+        block_size: u32,
+        ops: Vec<DeltaOp>,
+    ) -> Result<(), ServerError> {
+        let mut inbound = self.inbound_of(peer, id)?;
+        let file = inbound.files.get(index).ok_or(ServerError::InvalidPacket)?;
+        // the ops come from the peer, they may only cover what it announced
+        let len = ops
+            .iter()
+            .try_fold(0u64, |len, x| len.checked_add(x.checked_len(block_size)?));
+        let end = len.and_then(|x| x.checked_add(offset));
+        let signed = inbound.block_sizes.get(&index) == Some(&block_size);
+        let Some(end) = end.filter(|x| !inbound.complete && signed && *x <= file.size) else {
+            return Err(ServerError::InvalidPacket);
+        };
+        let file = file.clone();
+        inbound.receive(index, offset, end)?;
+        let inbox = self.config.inbox.dir(&inbound.peer);
+        let base = restore_path(&file.path, Some(&inbox))?;
+        check_inside(&inbox, &base)?;
+        let staging = staging_dir(id);
+        let dest = restore_path(&file.path, Some(&staging))?;
+        check_inside(&staging, &dest)?;
+        let io_err = |e: std::io::Error| ServerError::Io(format!("{}: {e}", dest.display()));
+        if let Some(dir) = dest.parent() {
+            tokio::fs::create_dir_all(dir).await.map_err(io_err)?;
+        }
+        tokio::task::spawn_blocking(move || apply_delta(&base, &dest, offset, block_size, &ops))
+            .await
+            .map_err(db_err)?
+            .map_err(db_err)?;
+        self.put_inbound(id, &inbound)
+    }
+    pub async fn finish_push(&self, peer: &PeerId, id: PushId) -> Result<PushStatus, ServerError> {
+        let mut inbound = self.inbound_of(peer, id)?;
+        // a push that stopped short would be accepted with truncated files
+        if !inbound.is_received() {
+            return Err(ServerError::InvalidPacket);
+        }
+        inbound.complete = true;
+        self.put_inbound(id, &inbound)?;
+        match self.config.inbox.policy(&inbound.peer) {
+            PushPolicy::Accept => {
+                self.accept(id).await?;
+                Ok(PushStatus::Accepted)
+            }
+            PushPolicy::Ask => Ok(PushStatus::Pending(id)),
+            PushPolicy::Reject => {
+                self.reject(id).await?;
+                Err(ServerError::PermissionDenied)
+            }
+        }
+    }
+
+    /// the complete pushes that wait for approval
+    pub fn pending(&self) -> Result<Vec<PendingPush>, ServerError> {
+        let mut out = Vec::new();
+        for x in self.pushes()?.iter() {
+            let (key, bytes) = x.map_err(db_err)?;
+            let Ok(key) = <[u8; 8]>::try_from(key.as_ref()) else {
+                continue;
+            };
+            let inbound: Inbound = serde_cbor::from_slice(&bytes).map_err(db_err)?;
+            if inbound.complete {
+                out.push(PendingPush {
+                    id: u64::from_be_bytes(key),
+                    host: HostId::new_id(inbound.peer),
+                    time: inbound.time,
+                    files: inbound.files,
+                });
+            }
+        }
+        Ok(out)
+    }
+    /// move the files of a push into the inbox of the peer, returns where they ended up
+    pub async fn accept(&self, id: PushId) -> Result<Vec<PathBuf>, ServerError> {
+        let inbound = self.inbound(id)?;
+        if !inbound.complete {
+            return Err(ServerError::UnknownPush(id));
+        }
+        let staging = staging_dir(id);
+        let inbox = self.config.inbox.dir(&inbound.peer);
+        let mut files: Vec<&FileInfo> = inbound.files.iter().collect();
+        files.sort_by_key(|x| link_order(&x.kind));
+        let mut out = Vec::new();
+        for file in files {
+            let to = restore_path(&file.path, Some(&inbox))?;
+            check_inside(&inbox, &to)?;
+            if file.kind == EntryKind::File {
+                let from = restore_path(&file.path, Some(&staging))?;
+                check_inside(&staging, &from)?;
+                move_file(&from, &to, file).await?;
+            } else {
+                transfer_link(file, &inbox, &to).await?;
+            }
+            out.push(to);
+        }
+        self.reject(id).await?;
+        Ok(out)
+    }
+    /// throw away a push and whatever was received of it
+    pub async fn reject(&self, id: PushId) -> Result<(), ServerError> {
+        let inbound = self.inbound(id)?;
+        match tokio::fs::remove_dir_all(staging_dir(id)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(ServerError::Io(e.to_string())),
+        }
+        // only the call that removed the push gives back its charge
+        if self
+            .pushes()?
+            .remove(id.to_be_bytes())
+            .map_err(db_err)?
+            .is_some()
+        {
+            self.store.release(&inbound.peer, inbound.charged)?;
+        }
+        Ok(())
+    }
+
+    /// push the hosted files that match the target to a host
+    ///
+    /// with `delta` a file the host has in our inbox already is sent as the differences to it
+    pub async fn push(
+        &self,
+        host: &AnyHost,
+        target: &Target,
+        delta: bool,
+        reply: &Responder,
+    ) -> Result<PushStatus, ServerError> {
+        let mut files: Vec<FileInfo> = self
+            .state
+            .lock()
+            .await
+            .catalog
+            .matching(target)
+            .map(|x| x.public_info())
+            .collect();
+        meta::for_transfer(&mut files);
+        let mut progress = ProgressTracker::new(reply.clone());
+        progress.set_totals(
+            Some(files.iter().map(|x| x.size).sum()),
+            Some(files.len() as u64),
+        );
+        let id = match self
+            .request(host, SendPacket::StartPush(files.clone()))
+            .await?
+        {
+            ReceivePacket::PushStarted(x) => x,
+            _ => return Err(ServerError::InvalidPacket),
+        };
+        for (index, file) in files.iter().enumerate() {
+            progress.start_file(file.path.display().to_string());
+            // links are made again from the file list, they have no data
+            if file.kind != EntryKind::File {
+                progress.finish_file();
+                continue;
+            }
+            let signature = match delta {
+                true => match self
+                    .request(host, SendPacket::PushSignature { id, index })
+                    .await?
+                {
+                    ReceivePacket::Signature(x) => x,
+                    _ => return Err(ServerError::InvalidPacket),
+                },
+                false => None,
+            };
+            match signature {
+                Some(signature) => {
+                    self.push_delta_file(host, id, index, file, signature, &mut progress)
+                        .await?
+                }
+                None => {
+                    self.push_file_data(host, id, index, file, &mut progress)
+                        .await?
+                }
+            }
+            progress.finish_file();
+        }
+        let status = match self.request(host, SendPacket::FinishPush(id)).await? {
+            ReceivePacket::PushFinished(x) => x,
+            _ => return Err(ServerError::InvalidPacket),
+        };
+        progress.finish();
+        Ok(status)
+    }
+    /// send the whole content of a file of a push
+    async fn push_file_data(
+        &self,
+        host: &AnyHost,
+        id: PushId,
+        index: usize,
+        file: &FileInfo,
+        progress: &mut ProgressTracker,
+    ) -> Result<(), ServerError> {
+        let io_err = |e: std::io::Error| ServerError::Io(format!("{}: {e}", file.path.display()));
+        // only send what was announced, even when the file grew since
+        let mut input =
+            tokio::fs::File::from_std(meta::open(&file.path).map_err(io_err)?).take(file.size);
+        let mut offset = 0;
+        loop {
+            let mut data = Vec::new();
+            (&mut input)
+                .take(FILE_PIECE)
+                .read_to_end(&mut data)
+                .await
+                .map_err(io_err)?;
+            if data.is_empty() {
+                break;
+            }
+            let len = data.len() as u64;
+            let request = SendPacket::PushData {
+                id,
+                index,
+                offset,
+                data,
+            };
+            match self.request(host, request).await? {
+                ReceivePacket::Ok => {}
+                _ => return Err(ServerError::InvalidPacket),
+            }
+            offset += len;
+            progress.add_bytes(len);
+        }
+        Ok(())
+    }
+}
+
+/// move a file, copying it when the inbox is on another filesystem, and give it the metadata
+/// the sender sent along
+async fn move_file(from: &Path, to: &Path, file: &FileInfo) -> Result<(), ServerError> {
+    let io_err = |e: std::io::Error| ServerError::Io(format!("{}: {e}", to.display()));
+    if let Some(dir) = to.parent() {
+        tokio::fs::create_dir_all(dir).await.map_err(io_err)?;
+    }
+    // whatever is in the way is replaced, a symlink there must not be written through
+    match tokio::fs::remove_file(to).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(io_err(e)),
+    }
+    // an empty file never got any data, so it was never created
+    if !tokio::fs::try_exists(from).await.map_err(io_err)? {
+        tokio::fs::File::create(to).await.map_err(io_err)?;
+    } else if tokio::fs::rename(from, to).await.is_err() {
+        tokio::fs::copy(from, to).await.map_err(io_err)?;
+    }
+    meta::apply(to, &file.meta, file.mtime, false).map_err(io_err)
+}
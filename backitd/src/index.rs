@@ -0,0 +1,92 @@
+//! what every file looked like at the last backup of a job, so unchanged files can be skipped
+//!
+//! a file counts as unchanged when its inode, size, mtime and ctime are the same as in the
+//! index, its entry of the last snapshot is then reused without reading the file. there is an
+//! index per job and per repo since the chunks of an entry have to be in the repo it goes to
+
+use std::{collections::BTreeMap, path::PathBuf, time::SystemTime};
+
+use backit_core::snapshot::{ChunkHash, ManifestEntry};
+use serde::{Deserialize, Serialize};
+
+use crate::store::{Store, StoreError};
+
+/// what is compared to find out if a file changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStat {
+    /// device and inode, a file that was replaced by another one is a change
+    pub inode: Option<(u64, u64)>,
+    pub size: u64,
+    pub mtime: Option<SystemTime>,
+    /// seconds and nanoseconds, this also changes with the permissions, owner and xattrs
+    pub ctime: Option<(i64, i64)>,
+}
+impl FileStat {
+    #[cfg(unix)]
+    pub fn of(metadata: &std::fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Self {
+            inode: Some((metadata.dev(), metadata.ino())),
+            size: metadata.len(),
+            mtime: metadata.modified().ok(),
+            ctime: Some((metadata.ctime(), metadata.ctime_nsec())),
+        }
+    }
+    #[cfg(not(unix))]
+    pub fn of(metadata: &std::fs::Metadata) -> Self {
+        Self {
+            inode: None,
+            size: metadata.len(),
+            mtime: metadata.modified().ok(),
+            ctime: None,
+        }
+    }
+}
+
+/// a file as it was backed up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub stat: FileStat,
+    /// blake3 hash of the whole content, unset for links
+    pub hash: Option<ChunkHash>,
+    pub entry: ManifestEntry,
+}
+
+pub type FileIndex = BTreeMap<PathBuf, IndexEntry>;
+
+fn tree(store: &Store, repo: &str, job: &str) -> Result<sled::Tree, StoreError> {
+    Ok(store.db().open_tree(format!("index/{repo}/{job}"))?)
+}
+
+/// the index of a job for a repo, empty when the job was never backed up to it, this blocks
+pub fn load(store: &Store, repo: &str, job: &str) -> Result<FileIndex, StoreError> {
+    let mut out = FileIndex::new();
+    for x in tree(store, repo, job)?.iter() {
+        let (_, bytes) = x?;
+        // an entry that no longer reads only costs a reread of the file
+        if let Ok(x) = serde_cbor::from_slice::<IndexEntry>(&bytes) {
+            out.insert(x.entry.path.clone(), x);
+        }
+    }
+    Ok(out)
+}
+
+/// replace the index of a job for a repo, this blocks
+pub fn save(
+    store: &Store,
+    repo: &str,
+    job: &str,
+    entries: &[IndexEntry],
+) -> Result<(), StoreError> {
+    let tree = tree(store, repo, job)?;
+    tree.clear()?;
+    let mut batch = sled::Batch::default();
+    for x in entries {
+        batch.insert(
+            x.entry.path.as_os_str().as_encoded_bytes(),
+            serde_cbor::to_vec(x)?,
+        );
+    }
+    tree.apply_batch(batch)?;
+    Ok(())
+}
@@ -1,108 +1,683 @@
-use std::{collections::HashMap, ffi::OsString, io, path::PathBuf, time::Duration, u64};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    io,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
+use audit::AuditLog;
+use backit_core::tcp::{ReceivePacket, SendPacket};
 use backit_core::{
-    ipc::{self, to_server::*},
-    streams::{client, client_codec, server, server_codec, ServerCodec, StreamExt},
+    ipc::{self, to_server::*, Tagged},
+    streams::{server, server_handshake, Compat, Hello, StreamExt},
     SinkExt,
 };
+use catalog::Catalog;
+use futures::channel::mpsc as futures_mpsc;
+use hooks::Hooks;
 use interprocess::local_socket::traits::tokio::Listener;
-use ipc::ServerInfo;
-use libp2p::{swarm::SwarmEvent, Multiaddr};
-use p2p::Client;
+use ipc::{Actor, Outcome, ServerError, ServerInfo};
+use libp2p::{swarm::SwarmEvent, Multiaddr, PeerId};
+use p2p::{signals::FromSwarm, Client};
+use reply::Responder;
 use tracing_subscriber::EnvFilter;
 
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    spawn, try_join,
+    spawn,
+    sync::{mpsc, Mutex},
+    try_join,
 };
 
-
+pub mod audit;
+pub mod auth;
+pub mod backup;
+pub mod catalog;
+pub mod config;
+pub mod delta;
+pub mod filter;
+pub mod hooks;
+pub mod inbox;
+pub mod index;
+pub mod meta;
+pub mod metrics;
+pub mod mount;
 pub mod p2p;
+pub mod peerset;
+pub mod progress;
+pub mod queue;
+pub mod quota;
+pub mod reply;
+pub mod retention;
+pub mod scripts;
+pub mod scrub;
+pub mod share;
+pub mod store;
+pub mod sync;
+pub mod throttle;
+pub mod watch;
 
-pub struct Config {}
+use config::Config;
+use retention::GcQueue;
+use store::Store;
+use throttle::Throttle;
 
-pub(crate) fn config_path() -> PathBuf {
-    todo!()
+/// the mutable part of the daemon, shared by every command that is running
+pub struct State {
+    catalog: Catalog,
+    /// peers we connected to, by the nickname given on connect
+    connected_clients: HashMap<HostId, PeerId>,
+    active: bool,
+    /// set once the watch loop started
+    watcher: Option<watch::Watcher>,
+    /// delta fetches peers started, by id
+    deltas: HashMap<u64, delta::DeltaSession>,
+    /// mounted snapshots and hosts by mountpoint
+    mounts: BTreeMap<PathBuf, mount::Mounted>,
+    queue: queue::Queue,
 }
+
+#[derive(Clone)]
 pub struct Server {
-    user_commands: ipc::Listener,
-    hosted_files: HashMap<FileTarget, PathBuf>,
-    connected_clients: HashMap<HostId, ()>,
-    active: bool,
-    config: Config,
-    client: Client
+    state: Arc<Mutex<State>>,
+    config: Arc<Config>,
+    client: Client,
+    store: Store,
+    audit: AuditLog,
+    hooks: Arc<Hooks>,
+    gc: Arc<GcQueue>,
 }
 impl Server {
-    pub fn new(client: Client) -> io::Result<Self> {
-        Ok(Self {
-            user_commands: server()?,
-            hosted_files: HashMap::new(),
-            active: false,
-            config: Config {},
-            connected_clients: HashMap::new(),
-            client
-        })
+    pub fn new(client: Client, config: Config, store: Store) -> Self {
+        if let Err(e) = client.metrics().persist(store.db()) {
+            eprintln!("could not open the metrics in the db: {e}");
+        }
+        Self {
+            state: Arc::new(Mutex::new(State {
+                catalog: Catalog::default(),
+                active: false,
+                connected_clients: HashMap::new(),
+                watcher: None,
+                deltas: HashMap::new(),
+                mounts: BTreeMap::new(),
+                queue: queue::Queue::default(),
+            })),
+            hooks: Arc::new(Hooks::new(config.hooks.clone())),
+            gc: Arc::new(GcQueue::default()),
+            config: Arc::new(config),
+            client,
+            audit: AuditLog::new(store.db().clone()),
+            store,
+        }
     }
 
-    pub fn server_info(&self) -> ServerInfo {
-        ServerInfo::new(self.active, self.hosted_files.len())
+    pub async fn server_info(&self) -> ServerInfo {
+        let last_scrub = self.store.last_scrub().ok().flatten();
+        let state = self.state.lock().await;
+        ServerInfo::new(state.active, state.catalog.len(), last_scrub)
     }
 
-    pub async fn handle_user_command(
-        &mut self,
-        backit: Backit,
-        codec: &mut ServerCodec<ipc::Stream>,
-    ) -> io::Result<()> {
+    /// find the peer behind a host, connecting to it first when we got credentials
+    pub async fn resolve_host(&self, host: &AnyHost) -> Result<PeerId, ServerError> {
+        match host {
+            AnyHost::HostId(id) => {
+                if let Some(peer) = self.state.lock().await.connected_clients.get(id) {
+                    return Ok(*peer);
+                }
+                id.as_str()
+                    .parse()
+                    .map_err(|_| ServerError::UnknownHost(id.as_str().to_string()))
+            }
+            AnyHost::Credentials(credentials) => self.connect(credentials).await,
+        }
+    }
+
+    /// connect to a peer, only `/ip4/../p2p/<peer id>` urls are supported for now
+    pub async fn connect(&self, credentials: &Credentials) -> Result<PeerId, ServerError> {
+        match credentials {
+            Credentials::Url(url) => {
+                let address: Multiaddr = url
+                    .parse()
+                    .map_err(|e| ServerError::Peer(format!("invalid address {url}: {e}")))?;
+                let (peer, address) = p2p::split_peer_address(address)
+                    .map_err(|e| ServerError::Peer(e.to_string()))?;
+                self.client
+                    .clone()
+                    .dial(peer, address)
+                    .await
+                    .map_err(|e| ServerError::Peer(e.to_string()))?;
+                Ok(peer)
+            }
+            Credentials::Key(_) | Credentials::Password { .. } => Err(ServerError::NotImplemented),
+        }
+    }
+
+    /// send a request to a remote host, an error answer of the host is turned into an `Err`
+    pub async fn request(
+        &self,
+        host: &AnyHost,
+        request: SendPacket,
+    ) -> Result<ReceivePacket, ServerError> {
+        let peer = self.resolve_host(host).await?;
+        let response = self.client.clone().request(peer, request).await;
+        if response.is_ok() {
+            // the answer is there either way, it must not be lost over this
+            if let Err(e) = peerset::mark_seen(&self.store, &peer) {
+                eprintln!("could not remember when {peer} was seen: {e:?}");
+            }
+        }
+        match response {
+            Ok(ReceivePacket::Error(e)) => Err(e),
+            Ok(x) => Ok(x),
+            Err(e) => Err(ServerError::Peer(e.to_string())),
+        }
+    }
+
+    /// handle a command sent over the ipc socket by the user with `uid`
+    pub async fn handle_user_command(&self, backit: Backit, uid: u32, reply: Responder) {
         use ipc::ServerError as SE;
         use ipc::ServerReply as SR;
         match backit.command() {
             Command::Start => {
-                self.active = true;
-                codec.send(SR::Started).await?;
+                self.state.lock().await.active = true;
+                reply.send(SR::Started);
             }
             Command::Stop => {
-                self.active = false;
-                codec.send(SR::Stopped).await?;
+                self.state.lock().await.active = false;
+                reply.send(SR::Stopped);
             }
             Command::Reload => {
-                todo!();
-                codec.send(SR::Error(SE::NotImplemented)).await?;
+                reply.send(SR::Error(SE::NotImplemented));
             }
 
             Command::ServerStatus(None) => {
-                codec.send(SR::Info(self.server_info())).await?;
+                let info = self.server_info().await.with_storage(self.storage().await);
+                reply.send(SR::Info(info));
             }
-            Command::ServerStatus(Some(x)) => {
-                
+            Command::ServerStatus(Some(host)) => match self.request(host, SendPacket::Info).await {
+                Ok(ReceivePacket::Info(info)) => reply.send(SR::Info(info)),
+                Ok(_) => reply.send(SR::Error(SE::InvalidPacket)),
+                Err(e) => reply.send(SR::Error(e)),
+            },
+            Command::FileList(None) => {
+                reply.send(SR::FileList(self.state.lock().await.catalog.file_infos()));
             }
-            _ => {
-                if !backit.no_confirm() {
-                    codec
-                        .send(ipc::ServerReply::Error(ipc::ServerError::InvalidPacket))
-                        .await?;
+            Command::FileList(Some(host)) => match self.request(host, SendPacket::FileList).await {
+                Ok(ReceivePacket::FileList(files)) => reply.send(SR::FileList(files)),
+                Ok(_) => reply.send(SR::Error(SE::InvalidPacket)),
+                Err(e) => reply.send(SR::Error(e)),
+            },
+
+            Command::Connect {
+                connection_type,
+                nickname,
+            } => match self.connect(connection_type).await {
+                Ok(peer) => {
+                    let nickname = nickname.clone().unwrap_or_else(|| peer.to_string());
+                    self.state
+                        .lock()
+                        .await
+                        .connected_clients
+                        .insert(HostId::new_nickname(nickname.clone()), peer);
+                    reply.send(SR::Connected(Some(nickname)));
+                }
+                Err(e) => reply.send(SR::Error(e)),
+            },
+            Command::Disconnect(host) => {
+                match self.resolve_host(&AnyHost::HostId(host.clone())).await {
+                    Ok(peer) => {
+                        let _ = self.client.clone().disconnect(peer).await;
+                        self.state
+                            .lock()
+                            .await
+                            .connected_clients
+                            .retain(|_, x| *x != peer);
+                        reply.send(SR::Disconnect);
+                    }
+                    Err(e) => reply.send(SR::Error(e)),
+                }
+            }
+
+            Command::Host {
+                target,
+                tags,
+                shares,
+            } => match self.host(target, tags, shares).await {
+                Ok(_) => reply.send(SR::HostFile(target.clone())),
+                Err(e) => reply.send(SR::Error(e)),
+            },
+            Command::Share {
+                target,
+                shares,
+                revoke,
+            } => match self.resolve_shares(shares).await {
+                Ok(shares) => {
+                    let count = self
+                        .state
+                        .lock()
+                        .await
+                        .catalog
+                        .share(target, &shares, *revoke);
+                    reply.send(SR::Shared(count));
+                }
+                Err(e) => reply.send(SR::Error(e)),
+            },
+            // the reply is sent once the transfer leaves the queue
+            Command::Fetch { priority, .. }
+            | Command::Push { priority, .. }
+            | Command::Backup { priority, .. } => {
+                let command = backit.command().clone();
+                let result = self
+                    .enqueue(command, *priority, false, Some(reply.clone()))
+                    .await;
+                match result {
+                    Ok(id) => reply.send(SR::Progress(ipc::Progress {
+                        transfer: Some(id),
+                        ..Default::default()
+                    })),
+                    Err(e) => reply.send(SR::Error(e)),
+                }
+            }
+            Command::Log { filter, follow } => self.log(filter, *follow, &reply).await,
+            Command::Jobs => match self.jobs().await {
+                Ok(x) => reply.send(SR::Jobs(x)),
+                Err(e) => reply.send(SR::Error(e)),
+            },
+            Command::Cancel(id) => match self.cancel(*id).await {
+                Ok(()) => reply.send(SR::Cancelled),
+                Err(e) => reply.send(SR::Error(e)),
+            },
+            Command::Pause(id) => match self.pause(*id).await {
+                Ok(()) => reply.send(SR::Paused),
+                Err(e) => reply.send(SR::Error(e)),
+            },
+            Command::Resume(id) => match self.resume(*id).await {
+                Ok(()) => reply.send(SR::Resumed),
+                Err(e) => reply.send(SR::Error(e)),
+            },
+            Command::Sync {
+                host,
+                dir,
+                remote,
+                policy,
+            } => {
+                let result = self
+                    .sync(host, dir, remote.as_deref(), *policy, &reply)
+                    .await;
+                match result {
+                    Ok(x) => reply.send(SR::Synced(x)),
+                    Err(e) => reply.send(SR::Error(e)),
+                }
+            }
+            Command::Pending => match self.pending() {
+                Ok(x) => reply.send(SR::Pending(x)),
+                Err(e) => reply.send(SR::Error(e)),
+            },
+            Command::Accept(id) => match self.accept(*id).await {
+                Ok(x) => reply.send(SR::Accepted(x)),
+                Err(e) => reply.send(SR::Error(e)),
+            },
+            Command::Reject(id) => match self.reject(*id).await {
+                Ok(()) => reply.send(SR::Rejected),
+                Err(e) => reply.send(SR::Error(e)),
+            },
+            Command::Unhost(targets) => {
+                let mut state = self.state.lock().await;
+                for target in targets {
+                    state.catalog.unhost(target);
+                }
+                state.sync_watches();
+                reply.send(SR::UnHostFile);
+            }
+
+            Command::Snapshots { host, job } => {
+                let result = match self.repo(host.as_ref()).await {
+                    Ok(mut repo) => repo.snapshots(job.clone()).await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(x) => reply.send(SR::Snapshots(x)),
+                    Err(e) => reply.send(SR::Error(e)),
+                }
+            }
+            Command::Restore {
+                host,
+                snapshot,
+                paths,
+                to,
+            } => {
+                let result = self
+                    .restore(host.as_ref(), *snapshot, paths, to.as_deref(), &reply)
+                    .await;
+                match result {
+                    Ok((files, bytes)) => reply.send(SR::Restored { files, bytes }),
+                    Err(e) => reply.send(SR::Error(e)),
                 }
             }
+            Command::Diff { host, from, to } => match self.diff(host.as_ref(), *from, *to).await {
+                Ok(x) => reply.send(SR::Diff(x)),
+                Err(e) => reply.send(SR::Error(e)),
+            },
+            Command::Prune { host, job, dry_run } => {
+                match self.prune(host.as_ref(), job.as_deref(), *dry_run).await {
+                    Ok(x) => reply.send(SR::Pruned(x)),
+                    Err(e) => reply.send(SR::Error(e)),
+                }
+            }
+            Command::Verify { host, full } => {
+                match self.verify(host.as_ref(), *full, &reply).await {
+                    Ok(x) => reply.send(SR::Verified(x)),
+                    Err(e) => reply.send(SR::Error(e)),
+                }
+            }
+            Command::Mount { source, mountpoint } => {
+                match self.mount(source, mountpoint, uid).await {
+                    Ok(x) => reply.send(SR::Mounted(x)),
+                    Err(e) => reply.send(SR::Error(e)),
+                }
+            }
+            Command::Unmount(mountpoint) => match self.unmount(mountpoint, uid).await {
+                Ok(()) => reply.send(SR::Unmounted),
+                Err(e) => reply.send(SR::Error(e)),
+            },
+            Command::Throttle {
+                host,
+                upload,
+                download,
+                reset,
+            } => match self
+                .throttle(host.clone(), *upload, *download, *reset)
+                .await
+            {
+                Ok(()) => reply.send(SR::Throttled),
+                Err(e) => reply.send(SR::Error(e)),
+            },
         }
-        Ok(())
     }
 
-    pub async fn handle_command(&mut self) -> io::Result<()> {
-        let connection = self.user_commands.accept().await?;
-        let mut codec = server_codec(connection);
-        while let Some(x) = codec.next().await {
+    /// answer a request of a remote daemon
+    pub async fn handle_peer_request(&self, peer: PeerId, request: SendPacket) -> ReceivePacket {
+        let audited = audit::describe_packet(&request);
+        let result = self.try_handle_peer_request(peer, request).await;
+        // a peer that is refused is recorded whatever it asked for
+        let denied = matches!(result, Err(ServerError::PermissionDenied));
+        if let Some((action, target)) = audited.or(denied.then_some(("request", None))) {
+            let outcome = match &result {
+                Ok(_) => Outcome::Success,
+                Err(e) => Outcome::Failure(format!("{e:?}")),
+            };
+            self.audit
+                .record(Actor::Peer(peer.to_string()), action, target, outcome);
+        }
+        result.unwrap_or_else(ReceivePacket::Error)
+    }
+    async fn try_handle_peer_request(
+        &self,
+        peer: PeerId,
+        request: SendPacket,
+    ) -> Result<ReceivePacket, ServerError> {
+        // snapshots a peer pushes to us are kept apart from our own and those of other peers
+        let owner = peer.to_string();
+        Ok(match request {
+            SendPacket::Info => ReceivePacket::Info(self.server_info().await),
+            SendPacket::FileList => {
+                ReceivePacket::FileList(self.allowed_files(&peer, Right::List, None).await)
+            }
+            SendPacket::FetchList(target) => {
+                ReceivePacket::FileList(self.allowed_files(&peer, Right::Read, Some(&target)).await)
+            }
+            SendPacket::ReadFile { path, offset, len } => {
+                ReceivePacket::FileData(self.read_file(&peer, &path, offset, len).await?)
+            }
+            SendPacket::StartPush(files) => {
+                ReceivePacket::PushStarted(self.start_push(&peer, files)?)
+            }
+            SendPacket::PushData {
+                id,
+                index,
+                offset,
+                data,
+            } => {
+                self.push_data(&peer, id, index, offset, &data).await?;
+                ReceivePacket::Ok
+            }
+            SendPacket::FinishPush(id) => {
+                ReceivePacket::PushFinished(self.finish_push(&peer, id).await?)
+            }
+            SendPacket::StartDelta { path, signature } => {
+                ReceivePacket::DeltaStarted(self.start_delta(&peer, path, signature).await?)
+            }
+            SendPacket::ReadDelta { id, offset } => {
+                ReceivePacket::Delta(self.read_delta(&peer, id, offset).await?)
+            }
+            SendPacket::FinishDelta(id) => {
+                self.finish_delta(&peer, id).await;
+                ReceivePacket::Ok
+            }
+            SendPacket::PushSignature { id, index } => {
+                ReceivePacket::Signature(self.push_signature(&peer, id, index).await?)
+            }
+            SendPacket::PushDelta {
+                id,
+                index,
+                offset,
+                block_size,
+                ops,
+            } => {
+                self.push_delta(&peer, id, index, offset, block_size, ops)
+                    .await?;
+                ReceivePacket::Ok
+            }
+            SendPacket::SyncIndex(dir) => {
+                ReceivePacket::SyncIndex(self.sync_index(&peer, dir).await?)
+            }
+            SendPacket::SyncRead {
+                dir,
+                path,
+                offset,
+                len,
+            } => ReceivePacket::FileData(self.sync_read(&peer, dir, &path, offset, len).await?),
+            SendPacket::SyncWrite {
+                dir,
+                path,
+                offset,
+                data,
+            } => {
+                self.sync_write(&peer, dir, &path, offset, data).await?;
+                ReceivePacket::Ok
+            }
+            SendPacket::SyncCommit { dir, path, version } => {
+                self.sync_commit(&peer, dir, path, version).await?;
+                ReceivePacket::Ok
+            }
+
+            SendPacket::HasChunks(hashes) => {
+                ReceivePacket::MissingChunks(self.store.missing_chunks(&hashes)?)
+            }
+            SendPacket::PutChunk { hash, data } => {
+                if !self.store.has_chunk(&hash)? {
+                    self.check_reserve(data.len() as u64)?;
+                }
+                let quota = self.config.quota.quota(&owner);
+                self.store.put_chunk(&owner, &hash, &data, quota)?;
+                self.charged(&owner);
+                ReceivePacket::Ok
+            }
+            SendPacket::GetChunk(hash) => ReceivePacket::Chunk(
+                self.store
+                    .get_chunk(&hash)?
+                    .ok_or(ServerError::MissingChunk(hash))?,
+            ),
+            SendPacket::PutSnapshot {
+                id,
+                offset,
+                total,
+                data,
+            } => {
+                self.check_reserve(data.len() as u64)?;
+                let quota = self.config.quota.quota(&owner);
+                if let Some(snapshot) = self
+                    .store
+                    .put_snapshot_part(&owner, &id, offset, total, &data, quota)?
+                {
+                    self.store.put_snapshot(&owner, &snapshot, quota)?;
+                }
+                self.charged(&owner);
+                ReceivePacket::Ok
+            }
+            SendPacket::Snapshots { job } => {
+                ReceivePacket::Snapshots(self.store.snapshot_infos(&owner, job.as_deref())?)
+            }
+            SendPacket::GetSnapshot { id, offset } => {
+                let manifest = self
+                    .store
+                    .snapshot_bytes(&owner, &id)?
+                    .ok_or(ServerError::UnknownSnapshot(id))?;
+                let len = manifest.len();
+                let start = usize::try_from(offset).map_or(len, |x| x.min(len));
+                let end = len.min(start + backup::MANIFEST_PIECE);
+                ReceivePacket::SnapshotPart {
+                    total: len as u64,
+                    data: manifest[start..end].to_vec(),
+                }
+            }
+            SendPacket::ForgetSnapshots { ids, dry_run } => {
+                ReceivePacket::Collected(self.peer_forget(&owner, &ids, dry_run).await?)
+            }
+            SendPacket::Verify { full } => {
+                let store = self.store.clone();
+                let report =
+                    tokio::task::spawn_blocking(move || store.verify(Some(&owner), full, |_| {}))
+                        .await
+                        .map_err(|e| ServerError::Io(e.to_string()))??;
+                ReceivePacket::Verified(report)
+            }
+            SendPacket::Usage => ReceivePacket::Usage(self.usage(&owner)?),
+        })
+    }
+
+    /// handle everything the swarm needs the daemon for, like requests of remote daemons
+    pub async fn handle_swarm_events(self, mut events: futures_mpsc::Receiver<FromSwarm>) {
+        while let Some(event) = events.next().await {
+            match event {
+                FromSwarm::InboundRequest {
+                    peer,
+                    request,
+                    channel,
+                } => {
+                    let server = self.clone();
+                    spawn(async move {
+                        let request = match server.client.received(&peer, request).await {
+                            Ok(x) => x,
+                            Err(e) => {
+                                eprintln!("invalid request from {peer}: {e}");
+                                return;
+                            }
+                        };
+                        if let Err(e) = peerset::mark_seen(&server.store, &peer) {
+                            eprintln!("could not remember when {peer} was seen: {e:?}");
+                        }
+                        let response = server.handle_peer_request(peer, request).await;
+                        let _ = server
+                            .client
+                            .clone()
+                            .respond(&peer, response, channel)
+                            .await;
+                    });
+                }
+            }
+        }
+    }
+
+    /// accept user connections forever, every connection is handled in its own task
+    pub async fn run(self, listener: ipc::Listener) -> io::Result<()> {
+        loop {
+            let connection = listener.accept().await?;
+            let server = self.clone();
+            spawn(async move {
+                if let Err(e) = server.handle_connection(connection).await {
+                    eprintln!("ipc connection failed: {e}");
+                }
+            });
+        }
+    }
+
+    /// read commands from a single user connection
+    ///
+    /// every command runs in its own task so a long running command does not block the others,
+    /// replies are funneled through a single writer task
+    pub async fn handle_connection(self, connection: ipc::Stream) -> io::Result<()> {
+        let (connection, peer) = auth::peer_credentials(connection)?;
+        let mut codec = match server_handshake(connection, Hello::new()).await {
+            Ok((codec, _)) => codec,
+            Err(e) => {
+                eprintln!("refused ipc connection: {e}");
+                return Ok(());
+            }
+        };
+        if !self.config.ipc.allows(&peer) {
+            eprintln!("refused ipc connection from uid {}", peer.uid);
+            let outcome = Outcome::Failure(format!("{:?}", ipc::ServerError::PermissionDenied));
+            self.audit
+                .record(Actor::Local(peer.uid), "ipc-connect", None, outcome);
+            codec
+                .send(Tagged::new(
+                    0,
+                    ipc::ServerReply::Error(ipc::ServerError::PermissionDenied),
+                ))
+                .await?;
+            return Ok(());
+        }
+
+        let (mut sink, mut stream) = codec.split();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let writer = spawn(async move {
+            while let Some(reply) = rx.recv().await {
+                sink.send(reply).await?;
+            }
+            io::Result::Ok(())
+        });
+
+        while let Some(x) = stream.next().await {
             println!("got request {:?}", x);
             match x {
                 Ok(x) => {
-                    self.handle_user_command(x, &mut codec).await?;
+                    let reply = Responder::new(x.id(), tx.clone());
+                    match x.into_body() {
+                        Compat::Known(backit) => {
+                            let reply = match audit::describe(backit.command()) {
+                                Some((action, target)) => reply.audited(
+                                    self.audit.clone(),
+                                    Actor::Local(peer.uid),
+                                    action,
+                                    target,
+                                ),
+                                None => reply,
+                            };
+                            let server = self.clone();
+                            spawn(async move {
+                                server.handle_user_command(backit, peer.uid, reply).await
+                            });
+                        }
+                        Compat::Unknown(e) => {
+                            eprintln!("got a command this daemon does not know: {e}");
+                            reply.send(ipc::ServerReply::Error(
+                                ipc::ServerError::UnsupportedCommand,
+                            ));
+                        }
+                    }
                 }
                 Err(e) => {
-                    codec
-                        .send(ipc::ServerReply::Error(ipc::ServerError::InvalidPacket))
-                        .await?;
+                    Responder::new(0, tx.clone())
+                        .send(ipc::ServerReply::Error(ipc::ServerError::InvalidPacket));
                 }
             };
         }
-        Ok(())
+        // the writer stops once every running command dropped its responder
+        drop(tx);
+        writer.await?
     }
 }
 
@@ -110,20 +685,34 @@ impl Server {
 async fn main() -> eyre::Result<()> {
     tracing_subscriber::fmt().init();
 
-    /*let mut server = Server::new()?;
-    loop {
-        server.handle_command().await?;
-    }*/
-    let (mut event_loop, client) = p2p::EventLoop::new()?;
+    let config = Config::load()?;
+    let key = p2p::load_or_generate_identity(&config::data_dir().join("identity.key"))?;
+    let throttle = Arc::new(Throttle::new(config.throttle.clone()));
+    let (mut event_loop, mut client, events) = p2p::EventLoop::new(key, throttle)?;
 
     spawn(async move {
         event_loop.run().await;
     });
-    let mut server = Server::new(client)?;
-    loop {
-        server.handle_command();
+    client.start_listening(config.p2p.listen.clone()).await?;
+
+    let store = Store::open(&config::data_dir().join("db"))?;
+    let listener = server(config.ipc.is_shared())?;
+    let metrics = match config.metrics.listen {
+        Some(x) => Some(tokio::net::TcpListener::bind(x).await?),
+        None => None,
+    };
+    let server = Server::new(client, config, store);
+    spawn(server.clone().handle_swarm_events(events));
+    spawn(throttle::background(server.clone().scrub_loop()));
+    spawn(throttle::background(server.clone().repair_loop()));
+    spawn(server.clone().gc_loop());
+    spawn(server.clone().watch_loop());
+    spawn(server.clone().queue_loop());
+    spawn(server.clone().offline_loop());
+    if let Some(metrics) = metrics {
+        spawn(server.clone().metrics_loop(metrics));
     }
-    client.start_listening().await;
+    server.run(listener).await?;
 
     Ok(())
 }
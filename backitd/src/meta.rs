@@ -0,0 +1,203 @@
+//! posix metadata of files, read into the snapshot manifest and transfers and applied again on
+//! restore and when a transfer arrives
+//!
+//! metadata is applied as far as the daemon is allowed to, only root can give a file to another
+//! owner, so that is skipped quietly for other users
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use backit_core::{
+    ipc::FileInfo,
+    snapshot::{EntryKind, FileMeta},
+};
+
+/// the metadata of a file, symlinks are not followed
+#[cfg(unix)]
+pub fn read(path: &Path, metadata: &std::fs::Metadata) -> FileMeta {
+    use nix::unistd::{Gid, Group, Uid, User};
+    use serde_bytes::ByteBuf;
+    use std::os::unix::fs::MetadataExt;
+
+    let xattrs = xattr::list(path)
+        .map(|names| {
+            names
+                .filter_map(|name| {
+                    let value = xattr::get(path, &name).ok()??;
+                    Some((name.into_string().ok()?, ByteBuf::from(value)))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    FileMeta {
+        mode: Some(metadata.mode() & 0o7777),
+        uid: Some(metadata.uid()),
+        gid: Some(metadata.gid()),
+        user: User::from_uid(Uid::from_raw(metadata.uid()))
+            .ok()
+            .flatten()
+            .map(|x| x.name),
+        group: Group::from_gid(Gid::from_raw(metadata.gid()))
+            .ok()
+            .flatten()
+            .map(|x| x.name),
+        atime: metadata.accessed().ok(),
+        xattrs,
+    }
+}
+#[cfg(not(unix))]
+pub fn read(_path: &Path, metadata: &std::fs::Metadata) -> FileMeta {
+    FileMeta {
+        atime: metadata.accessed().ok(),
+        ..FileMeta::default()
+    }
+}
+
+/// the device and inode of a file that has more than one name
+#[cfg(unix)]
+pub fn inode(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+#[cfg(not(unix))]
+pub fn inode(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// fill in the metadata and kind of the files of a transfer
+///
+/// a symlink is sent as its target and a file with several names in the transfer only under the
+/// first of them, neither has data to send
+pub fn for_transfer(files: &mut [FileInfo]) {
+    let mut inodes: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    for file in files.iter_mut() {
+        let Ok(metadata) = std::fs::symlink_metadata(&file.path) else {
+            continue;
+        };
+        file.meta = read(&file.path, &metadata);
+        file.kind = if metadata.is_symlink() {
+            match std::fs::read_link(&file.path) {
+                Ok(x) => EntryKind::Symlink(x),
+                Err(_) => continue,
+            }
+        } else {
+            match inode(&metadata).map(|x| inodes.entry(x)) {
+                Some(Entry::Occupied(x)) => EntryKind::Hardlink(x.get().clone()),
+                Some(Entry::Vacant(x)) => {
+                    x.insert(file.path.clone());
+                    EntryKind::File
+                }
+                None => EntryKind::File,
+            }
+        };
+        if file.kind != EntryKind::File {
+            file.size = 0;
+        }
+    }
+}
+
+/// open a file to read it, a symlink in its place is not followed
+///
+/// hosted files and copies of them are read with this, so a symlink a peer may read never hands
+/// out the file it points to
+#[cfg(unix)]
+pub fn open(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(nix::fcntl::OFlag::O_NOFOLLOW.bits())
+        .open(path)
+}
+#[cfg(not(unix))]
+pub fn open(path: &Path) -> io::Result<File> {
+    File::open(path)
+}
+
+/// give a file the metadata it had, symlinks are not followed
+///
+/// metadata that comes from a peer is not `trusted`, then setuid, setgid and sticky bits are
+/// dropped and only xattrs in the `user.` namespace are set
+#[cfg(unix)]
+pub fn apply(
+    path: &Path,
+    meta: &FileMeta,
+    mtime: Option<SystemTime>,
+    trusted: bool,
+) -> io::Result<()> {
+    use nix::{
+        sys::{
+            stat::{utimensat, UtimensatFlags},
+            time::TimeSpec,
+        },
+        unistd::{Group, User},
+    };
+    use std::os::unix::fs::PermissionsExt;
+
+    let symlink = std::fs::symlink_metadata(path)?.is_symlink();
+    // owner first, changing it clears the setuid and setgid bits
+    let uid = meta
+        .user
+        .as_ref()
+        .and_then(|x| User::from_name(x).ok().flatten())
+        .map(|x| x.uid.as_raw())
+        .or(meta.uid);
+    let gid = meta
+        .group
+        .as_ref()
+        .and_then(|x| Group::from_name(x).ok().flatten())
+        .map(|x| x.gid.as_raw())
+        .or(meta.gid);
+    match std::os::unix::fs::lchown(path, uid, gid) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {}
+        Err(e) => return Err(e),
+    }
+    // symlinks have no permissions of their own
+    if let (Some(mode), false) = (meta.mode, symlink) {
+        let mask = if trusted { 0o7777 } else { 0o777 };
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & mask))?;
+    }
+    for (name, value) in &meta.xattrs {
+        if !trusted && !name.starts_with("user.") {
+            continue;
+        }
+        // the filesystem may not support them, and most namespaces need privileges
+        let _ = xattr::set(path, name, value);
+    }
+    let time = |x: Option<SystemTime>| {
+        x.and_then(|x| x.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(TimeSpec::UTIME_OMIT, TimeSpec::from_duration)
+    };
+    utimensat(
+        None,
+        path,
+        &time(meta.atime),
+        &time(mtime),
+        UtimensatFlags::NoFollowSymlink,
+    )?;
+    Ok(())
+}
+#[cfg(not(unix))]
+pub fn apply(
+    path: &Path,
+    meta: &FileMeta,
+    mtime: Option<SystemTime>,
+    _trusted: bool,
+) -> io::Result<()> {
+    let mut times = std::fs::FileTimes::new();
+    if let Some(x) = meta.atime {
+        times = times.set_accessed(x);
+    }
+    if let Some(x) = mtime {
+        times = times.set_modified(x);
+    }
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_times(times)
+}
@@ -0,0 +1,316 @@
+//! prometheus metrics, served over http when `metrics.listen` is set
+//!
+//! the counters live in memory and start at zero with the daemon. the last successful backup of
+//! a job is kept in the db, so it survives a restart
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::Server;
+
+/// upper bounds of the transfer duration histogram in seconds
+const DURATION_BUCKETS: [f64; 8] = [1.0, 5.0, 30.0, 60.0, 300.0, 900.0, 3600.0, 14400.0];
+
+/// the largest request we read, there is nothing interesting in a larger one
+const MAX_REQUEST: usize = 8 * 1024;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// where to serve `/metrics`, like `127.0.0.1:9184`, nothing is served when unset
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// how many observations fell in each bucket, not cumulative
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+impl Histogram {
+    fn observe(&mut self, x: f64) {
+        if let Some(i) = DURATION_BUCKETS.iter().position(|bound| x <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += x;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// bytes sent and received by peer
+    peers: BTreeMap<PeerId, (u64, u64)>,
+    /// by kind of transfer and result
+    transfers: BTreeMap<(&'static str, &'static str), Histogram>,
+    /// by job and result
+    backups: BTreeMap<(String, &'static str), u64>,
+}
+
+/// what the daemon counts while it runs
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+    /// the time of the last successful backup by job, set once the store is open
+    last_backup: OnceLock<sled::Tree>,
+}
+impl Metrics {
+    /// keep the last successful backups in the db from now on
+    pub fn persist(&self, db: &sled::Db) -> sled::Result<()> {
+        let _ = self.last_backup.set(db.open_tree("last_backup")?);
+        Ok(())
+    }
+    pub fn sent(&self, peer: &PeerId, bytes: u64) {
+        let mut inner = self.inner.lock().expect("not poisoned");
+        inner.peers.entry(*peer).or_default().0 += bytes;
+    }
+    pub fn received(&self, peer: &PeerId, bytes: u64) {
+        let mut inner = self.inner.lock().expect("not poisoned");
+        inner.peers.entry(*peer).or_default().1 += bytes;
+    }
+    /// count a fetch, push or backup that finished
+    pub fn transfer(&self, kind: &'static str, ok: bool, duration: Duration) {
+        let mut inner = self.inner.lock().expect("not poisoned");
+        inner
+            .transfers
+            .entry((kind, result(ok)))
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+    /// count a backup that finished, a successful one also becomes the last backup of the job
+    pub fn backup(&self, job: &str, ok: bool) {
+        let mut inner = self.inner.lock().expect("not poisoned");
+        *inner
+            .backups
+            .entry((job.to_string(), result(ok)))
+            .or_default() += 1;
+        drop(inner);
+        if let Some(tree) = self.last_backup.get().filter(|_| ok) {
+            let secs = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            if let Err(e) = tree.insert(job, &secs.to_be_bytes()) {
+                eprintln!("could not keep the last backup of {job}: {e}");
+            }
+        }
+    }
+
+    fn render(&self, out: &mut String) {
+        let inner = self.inner.lock().expect("not poisoned");
+
+        header(
+            out,
+            "backit_peer_sent_bytes_total",
+            "counter",
+            "bytes sent to a peer",
+        );
+        for (peer, (sent, _)) in &inner.peers {
+            let _ = writeln!(
+                out,
+                "backit_peer_sent_bytes_total{{peer={}}} {sent}",
+                label(peer)
+            );
+        }
+        header(
+            out,
+            "backit_peer_received_bytes_total",
+            "counter",
+            "bytes received from a peer",
+        );
+        for (peer, (_, received)) in &inner.peers {
+            let _ = writeln!(
+                out,
+                "backit_peer_received_bytes_total{{peer={}}} {received}",
+                label(peer)
+            );
+        }
+
+        let name = "backit_transfer_duration_seconds";
+        header(
+            out,
+            name,
+            "histogram",
+            "how long fetches, pushes and backups took",
+        );
+        for ((kind, result), histogram) in &inner.transfers {
+            let labels = format!("kind={},result={}", label(kind), label(result));
+            let mut cumulative = 0;
+            for (bound, x) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += x;
+                let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+            }
+            let count = histogram.count;
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+            let _ = writeln!(out, "{name}_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+        }
+
+        header(
+            out,
+            "backit_backups_total",
+            "counter",
+            "backups that finished by job",
+        );
+        for ((job, result), x) in &inner.backups {
+            let _ = writeln!(
+                out,
+                "backit_backups_total{{job={},result={}}} {x}",
+                label(job),
+                label(result)
+            );
+        }
+        drop(inner);
+
+        let name = "backit_last_backup_success_timestamp_seconds";
+        header(
+            out,
+            name,
+            "gauge",
+            "when the last backup of a job succeeded",
+        );
+        for (job, secs) in self
+            .last_backup
+            .get()
+            .into_iter()
+            .flat_map(|x| x.iter().flatten())
+        {
+            let Ok(secs) = <[u8; 8]>::try_from(secs.as_ref()) else {
+                continue;
+            };
+            let job = String::from_utf8_lossy(&job);
+            let _ = writeln!(
+                out,
+                "{name}{{job={}}} {}",
+                label(job),
+                u64::from_be_bytes(secs)
+            );
+        }
+    }
+}
+
+fn result(ok: bool) -> &'static str {
+    match ok {
+        true => "success",
+        false => "failure",
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// a quoted label value
+fn label(x: impl ToString) -> String {
+    let x = x.to_string();
+    let x = x
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{x}\"")
+}
+
+impl Server {
+    /// every metric in the prometheus text format
+    pub async fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        self.client.metrics().render(&mut out);
+
+        let peers = self.client.clone().connected_peers().await.unwrap_or(0);
+        header(
+            &mut out,
+            "backit_connected_peers",
+            "gauge",
+            "peers with an open connection",
+        );
+        let _ = writeln!(out, "backit_connected_peers {peers}");
+
+        header(
+            &mut out,
+            "backit_store_used_bytes",
+            "gauge",
+            "chunk bytes stored by owner",
+        );
+        for owner in self.store.owners() {
+            if let Ok(x) = self.store.usage(&owner) {
+                let _ = writeln!(
+                    out,
+                    "backit_store_used_bytes{{owner={}}} {x}",
+                    label(&owner)
+                );
+            }
+        }
+        if let Ok(x) = self.store.db().size_on_disk() {
+            header(
+                &mut out,
+                "backit_store_disk_bytes",
+                "gauge",
+                "size of the store on disk",
+            );
+            let _ = writeln!(out, "backit_store_disk_bytes {x}");
+        }
+        out
+    }
+
+    /// answer http requests for `/metrics` forever
+    pub async fn metrics_loop(self, listener: TcpListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((x, _)) => x,
+                Err(e) => {
+                    eprintln!("metrics connection failed: {e}");
+                    continue;
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_metrics(stream).await {
+                    eprintln!("metrics connection failed: {e}");
+                }
+            });
+        }
+    }
+
+    /// answer a single request and close the connection
+    async fn serve_metrics(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|x| x == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        let request = String::from_utf8_lossy(&request);
+        let mut words = request.split_whitespace();
+        let (status, body) = match (words.next(), words.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render_metrics().await),
+            (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+            _ => (
+                "405 Method Not Allowed",
+                "only GET is supported\n".to_string(),
+            ),
+        };
+        let response = format!(
+            "HTTP/1.1 {status}\r\ncontent-type: text/plain; version=0.0.4\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
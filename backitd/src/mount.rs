@@ -0,0 +1,546 @@
+//! snapshots and the hosted files of peers as read only filesystems
+//!
+//! the tree is built when the filesystem is mounted, file data is only fetched once it is
+//! read, from the repo the snapshot is in or with read requests to the host. fuse calls come in
+//! on a thread of their own that blocks on the runtime for that
+//!
+//! the chunk sizes of a file are not in the manifest, so the first read far into a file also
+//! fetches the chunks before it
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ffi::{OsStr, OsString},
+    io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use backit_core::{
+    ipc::{
+        to_server::{AnyHost, MountSource, Target},
+        FileInfo, ServerError,
+    },
+    snapshot::{ChunkHash, EntryKind, Snapshot},
+    tcp::{ReceivePacket, SendPacket},
+};
+
+use crate::{backup::Repo, share::FILE_PIECE, Server};
+
+/// how many chunks are kept around, reads of the kernel are much smaller than a chunk
+const CACHED_CHUNKS: usize = 8;
+/// the inode of the root directory
+const ROOT: u64 = 1;
+
+/// where the data of a file comes from
+enum Content {
+    /// the chunks of a snapshot entry, with the end offsets of those that were fetched
+    Chunks {
+        chunks: Vec<ChunkHash>,
+        ends: Vec<u64>,
+    },
+    /// a hosted file of the host, by its path there
+    Hosted(PathBuf),
+}
+
+enum Kind {
+    /// children by name
+    Dir(BTreeMap<OsString, u64>),
+    File(Content),
+    Symlink(PathBuf),
+}
+
+struct Node {
+    parent: u64,
+    kind: Kind,
+    size: u64,
+    mtime: SystemTime,
+    mode: u32,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+/// every file of a mount by inode, inode `n` is at `n - 1`
+struct Tree {
+    nodes: Vec<Node>,
+    /// the mtime of directories
+    time: SystemTime,
+}
+impl Tree {
+    fn new(time: SystemTime) -> Self {
+        let mut out = Self {
+            nodes: Vec::new(),
+            time,
+        };
+        out.push(ROOT, Kind::Dir(BTreeMap::new()), 0o755);
+        out
+    }
+    fn push(&mut self, parent: u64, kind: Kind, mode: u32) -> u64 {
+        self.nodes.push(Node {
+            parent,
+            kind,
+            size: 0,
+            mtime: self.time,
+            mode,
+            uid: None,
+            gid: None,
+        });
+        self.nodes.len() as u64
+    }
+    fn get(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(ino.checked_sub(1)? as usize)
+    }
+    fn get_mut(&mut self, ino: u64) -> Option<&mut Node> {
+        self.nodes.get_mut(ino.checked_sub(1)? as usize)
+    }
+    fn child(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        match &self.get(parent)?.kind {
+            Kind::Dir(x) => x.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    /// add a file below the root at its absolute path, the directories it is in are made on
+    /// the way, a path that clashes with one that is there already is left out
+    fn add(&mut self, path: &Path, kind: Kind) -> Option<&mut Node> {
+        let names: Vec<OsString> = path
+            .components()
+            .filter_map(|x| match x {
+                Component::Normal(x) => Some(x.to_os_string()),
+                _ => None,
+            })
+            .collect();
+        let (name, dirs) = names.split_last()?;
+        let mut parent = ROOT;
+        for dir in dirs {
+            parent = match self.child(parent, dir) {
+                Some(x) if matches!(self.get(x)?.kind, Kind::Dir(_)) => x,
+                Some(_) => return None,
+                None => {
+                    let ino = self.push(parent, Kind::Dir(BTreeMap::new()), 0o755);
+                    self.link(parent, dir.clone(), ino);
+                    ino
+                }
+            };
+        }
+        if self.child(parent, name).is_some() {
+            return None;
+        }
+        let ino = self.push(parent, kind, 0o644);
+        self.link(parent, name.clone(), ino);
+        self.get_mut(ino)
+    }
+    fn link(&mut self, parent: u64, name: OsString, ino: u64) {
+        if let Some(Node {
+            kind: Kind::Dir(children),
+            ..
+        }) = self.get_mut(parent)
+        {
+            children.insert(name, ino);
+        }
+    }
+
+    fn of_snapshot(snapshot: &Snapshot) -> Self {
+        let mut out = Self::new(snapshot.time);
+        for entry in &snapshot.files {
+            let kind = match &entry.kind {
+                EntryKind::File => Kind::File(Content::Chunks {
+                    chunks: entry.chunks.clone(),
+                    ends: Vec::new(),
+                }),
+                EntryKind::Symlink(target) => Kind::Symlink(target.clone()),
+                // shown as a copy of the first name
+                EntryKind::Hardlink(first) => Kind::File(Content::Chunks {
+                    chunks: snapshot
+                        .entry(first)
+                        .map(|x| x.chunks.clone())
+                        .unwrap_or_default(),
+                    ends: Vec::new(),
+                }),
+            };
+            let size = match &entry.kind {
+                EntryKind::Hardlink(first) => snapshot.entry(first).map_or(0, |x| x.size),
+                _ => entry.size,
+            };
+            if let Some(node) = out.add(&entry.path, kind) {
+                node.size = size;
+                node.mtime = entry.mtime.unwrap_or(snapshot.time);
+                node.mode = entry.meta.mode.unwrap_or(node.mode);
+                node.uid = entry.meta.uid;
+                node.gid = entry.meta.gid;
+            }
+        }
+        out
+    }
+    fn of_files(files: &[FileInfo]) -> Self {
+        let now = SystemTime::now();
+        let mut out = Self::new(now);
+        for file in files {
+            let (kind, size) = match &file.kind {
+                EntryKind::File => (Kind::File(Content::Hosted(file.path.clone())), file.size),
+                EntryKind::Symlink(target) => (Kind::Symlink(target.clone()), file.size),
+                // shown as a copy of the first name, which the host reads for us
+                EntryKind::Hardlink(first) => (
+                    Kind::File(Content::Hosted(first.clone())),
+                    files
+                        .iter()
+                        .find(|x| x.path == *first)
+                        .map_or(0, |x| x.size),
+                ),
+            };
+            if let Some(node) = out.add(&file.path, kind) {
+                node.size = size;
+                node.mtime = file.mtime.unwrap_or(now);
+                node.mode = file.meta.mode.unwrap_or(node.mode);
+            }
+        }
+        out
+    }
+}
+
+/// chunks that were read last, so reading a chunk in small pieces fetches it once
+#[derive(Default)]
+struct ChunkCache(VecDeque<(ChunkHash, Arc<Vec<u8>>)>);
+impl ChunkCache {
+    async fn get(&mut self, repo: &mut Repo, hash: ChunkHash) -> Result<Arc<Vec<u8>>, ServerError> {
+        if let Some((_, data)) = self.0.iter().find(|(x, _)| *x == hash) {
+            return Ok(data.clone());
+        }
+        let data = Arc::new(repo.get_chunk(hash).await?);
+        if self.0.len() == CACHED_CHUNKS {
+            self.0.pop_front();
+        }
+        self.0.push_back((hash, data.clone()));
+        Ok(data)
+    }
+}
+
+/// where the data of a mount is read from
+enum Source {
+    Repo { repo: Repo, cache: ChunkCache },
+    Host { server: Server, host: AnyHost },
+}
+impl Source {
+    /// at most `size` bytes from `offset` on, shorter at the end of the file
+    async fn read(
+        &mut self,
+        content: &mut Content,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, ServerError> {
+        match (self, content) {
+            (Self::Repo { repo, cache }, Content::Chunks { chunks, ends }) => {
+                let end = offset + size;
+                let mut out = Vec::new();
+                // the first chunk that ends after the offset, as far as the ends are known
+                let mut i = ends.partition_point(|x| *x <= offset);
+                while i < chunks.len() && (out.len() as u64) < size {
+                    let start = if i == 0 { 0 } else { ends[i - 1] };
+                    if start >= end {
+                        break;
+                    }
+                    let data = cache.get(repo, chunks[i]).await?;
+                    if i == ends.len() {
+                        ends.push(start + data.len() as u64);
+                    }
+                    if ends[i] > offset {
+                        let from = offset.saturating_sub(start) as usize;
+                        let to = (end.min(ends[i]) - start) as usize;
+                        out.extend_from_slice(&data[from..to]);
+                    }
+                    i += 1;
+                }
+                Ok(out)
+            }
+            (Self::Host { server, host }, Content::Hosted(path)) => {
+                let request = SendPacket::ReadFile {
+                    path: path.clone(),
+                    offset,
+                    len: size.min(FILE_PIECE),
+                };
+                match server.request(host, request).await? {
+                    ReceivePacket::FileData(x) => Ok(x),
+                    _ => Err(ServerError::InvalidPacket),
+                }
+            }
+            _ => Err(ServerError::InvalidPacket),
+        }
+    }
+}
+
+/// a mounted snapshot or host
+pub struct BackitFs {
+    tree: Tree,
+    source: Source,
+    runtime: tokio::runtime::Handle,
+}
+
+#[cfg(unix)]
+mod fuse {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt, time::Duration};
+
+    use fuser::{
+        FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+    };
+    use nix::libc;
+
+    use super::{BackitFs, Kind, Node};
+
+    /// how long the kernel may cache what it was told, hosted files can change
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn file_type(kind: &Kind) -> FileType {
+        match kind {
+            Kind::Dir(_) => FileType::Directory,
+            Kind::File(_) => FileType::RegularFile,
+            Kind::Symlink(_) => FileType::Symlink,
+        }
+    }
+    fn attr(ino: u64, node: &Node) -> FileAttr {
+        let size = match &node.kind {
+            Kind::Symlink(x) => x.as_os_str().len() as u64,
+            _ => node.size,
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: node.mtime,
+            mtime: node.mtime,
+            ctime: node.mtime,
+            crtime: node.mtime,
+            kind: file_type(&node.kind),
+            perm: (node.mode & 0o777) as u16,
+            nlink: 1,
+            uid: node.uid.unwrap_or_else(|| nix::unistd::getuid().as_raw()),
+            gid: node.gid.unwrap_or_else(|| nix::unistd::getgid().as_raw()),
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    impl Filesystem for BackitFs {
+        fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            let found = self
+                .tree
+                .child(parent, name)
+                .and_then(|ino| Some((ino, self.tree.get(ino)?)));
+            match found {
+                Some((ino, node)) => reply.entry(&TTL, &attr(ino, node), 0),
+                None => reply.error(libc::ENOENT),
+            }
+        }
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            match self.tree.get(ino) {
+                Some(node) => reply.attr(&TTL, &attr(ino, node)),
+                None => reply.error(libc::ENOENT),
+            }
+        }
+        fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+            match self.tree.get(ino).map(|x| &x.kind) {
+                Some(Kind::Symlink(target)) => reply.data(target.as_os_str().as_bytes()),
+                Some(_) => reply.error(libc::EINVAL),
+                None => reply.error(libc::ENOENT),
+            }
+        }
+        fn read(
+            &mut self,
+            _req: &Request<'_>,
+            ino: u64,
+            _fh: u64,
+            offset: i64,
+            size: u32,
+            _flags: i32,
+            _lock_owner: Option<u64>,
+            reply: ReplyData,
+        ) {
+            let Some(node) = self.tree.get_mut(ino) else {
+                return reply.error(libc::ENOENT);
+            };
+            let Kind::File(content) = &mut node.kind else {
+                return reply.error(libc::EISDIR);
+            };
+            let read = self.source.read(content, offset.max(0) as u64, size as u64);
+            match self.runtime.block_on(read) {
+                Ok(data) => reply.data(&data),
+                Err(e) => {
+                    eprintln!("reading a mounted file failed: {e:?}");
+                    reply.error(libc::EIO)
+                }
+            }
+        }
+        fn readdir(
+            &mut self,
+            _req: &Request<'_>,
+            ino: u64,
+            _fh: u64,
+            offset: i64,
+            mut reply: ReplyDirectory,
+        ) {
+            let Some(node) = self.tree.get(ino) else {
+                return reply.error(libc::ENOENT);
+            };
+            let Kind::Dir(children) = &node.kind else {
+                return reply.error(libc::ENOTDIR);
+            };
+            let entries = [
+                (ino, FileType::Directory, OsStr::new(".")),
+                (node.parent, FileType::Directory, OsStr::new("..")),
+            ]
+            .into_iter()
+            .chain(children.iter().filter_map(|(name, ino)| {
+                let kind = file_type(&self.tree.get(*ino)?.kind);
+                Some((*ino, kind, name.as_os_str()))
+            }));
+            for (i, (ino, kind, name)) in entries.enumerate().skip(offset.max(0) as usize) {
+                // the offset is that of the next entry
+                if reply.add(ino, i as i64 + 1, kind, name) {
+                    break;
+                }
+            }
+            reply.ok();
+        }
+    }
+}
+
+/// a mounted filesystem, it is unmounted when this is dropped
+pub struct Mounted {
+    /// the user that mounted it, only they may unmount it
+    uid: u32,
+    #[cfg(unix)]
+    _session: fuser::BackgroundSession,
+}
+
+#[cfg(unix)]
+fn spawn(fs: BackitFs, mountpoint: &Path, uid: u32) -> io::Result<Mounted> {
+    use fuser::MountOption;
+
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("backit".to_string()),
+        MountOption::NoExec,
+        MountOption::NoSuid,
+        MountOption::NoDev,
+    ];
+    Ok(Mounted {
+        uid,
+        _session: fuser::spawn_mount2(fs, mountpoint, &options)?,
+    })
+}
+#[cfg(not(unix))]
+fn spawn(_fs: BackitFs, _mountpoint: &Path, _uid: u32) -> io::Result<Mounted> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "mounting is only supported on unix",
+    ))
+}
+
+/// the daemon mounts for other users, so it only mounts over an empty directory of theirs
+#[cfg(unix)]
+async fn check_mountpoint(mountpoint: &Path, uid: u32) -> Result<(), ServerError> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = tokio::fs::symlink_metadata(mountpoint)
+        .await
+        .map_err(|e| ServerError::Io(format!("{}: {e}", mountpoint.display())))?;
+    if !metadata.is_dir() || metadata.uid() != uid {
+        return Err(ServerError::PermissionDenied);
+    }
+    let mut entries = tokio::fs::read_dir(mountpoint)
+        .await
+        .map_err(|e| ServerError::Io(format!("{}: {e}", mountpoint.display())))?;
+    match entries.next_entry().await {
+        Ok(None) => Ok(()),
+        Ok(Some(_)) => Err(ServerError::Io(format!(
+            "{} is not empty",
+            mountpoint.display()
+        ))),
+        Err(e) => Err(ServerError::Io(format!("{}: {e}", mountpoint.display()))),
+    }
+}
+#[cfg(not(unix))]
+async fn check_mountpoint(_mountpoint: &Path, _uid: u32) -> Result<(), ServerError> {
+    Ok(())
+}
+
+impl Server {
+    /// mount a snapshot or the files of a host for the user with `uid`, returns where it was
+    /// mounted
+    pub async fn mount(
+        &self,
+        source: &MountSource,
+        mountpoint: &Path,
+        uid: u32,
+    ) -> Result<PathBuf, ServerError> {
+        let io_err = |e: io::Error| ServerError::Io(format!("{}: {e}", mountpoint.display()));
+        let mountpoint = tokio::fs::canonicalize(mountpoint).await.map_err(io_err)?;
+        check_mountpoint(&mountpoint, uid).await?;
+        if self.state.lock().await.mounts.contains_key(&mountpoint) {
+            return Err(io_err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "something is mounted there already",
+            )));
+        }
+        let (tree, source) = match source {
+            MountSource::Snapshot { host, snapshot } => {
+                let mut repo = self.repo(host.as_ref()).await?;
+                let snapshot = repo.snapshot(*snapshot).await?;
+                let cache = ChunkCache::default();
+                (Tree::of_snapshot(&snapshot), Source::Repo { repo, cache })
+            }
+            MountSource::Host(host) => {
+                // an empty tag list matches every file
+                let request = SendPacket::FetchList(Target::Tags(Vec::new()));
+                let files = match self.request(host, request).await? {
+                    ReceivePacket::FileList(x) => x,
+                    _ => return Err(ServerError::InvalidPacket),
+                };
+                let source = Source::Host {
+                    server: self.clone(),
+                    host: host.clone(),
+                };
+                (Tree::of_files(&files), source)
+            }
+        };
+        let fs = BackitFs {
+            tree,
+            source,
+            runtime: tokio::runtime::Handle::current(),
+        };
+        let at = mountpoint.clone();
+        let mounted = tokio::task::spawn_blocking(move || spawn(fs, &at, uid))
+            .await
+            .map_err(|e| ServerError::Io(e.to_string()))?
+            .map_err(io_err)?;
+        self.state
+            .lock()
+            .await
+            .mounts
+            .insert(mountpoint.clone(), mounted);
+        Ok(mountpoint)
+    }
+
+    /// unmount what the user with `uid` mounted at `mountpoint`
+    pub async fn unmount(&self, mountpoint: &Path, uid: u32) -> Result<(), ServerError> {
+        let canonical = tokio::fs::canonicalize(mountpoint).await.ok();
+        let mut state = self.state.lock().await;
+        let key = [Some(mountpoint), canonical.as_deref()]
+            .into_iter()
+            .flatten()
+            .find(|x| state.mounts.contains_key(*x))
+            .map(Path::to_path_buf)
+            .ok_or_else(|| {
+                ServerError::Io(format!("nothing is mounted at {}", mountpoint.display()))
+            })?;
+        if state.mounts[&key].uid != uid {
+            return Err(ServerError::PermissionDenied);
+        }
+        let mounted = state.mounts.remove(&key).expect("checked above");
+        drop(state);
+        // unmounting waits for the fuse thread to finish
+        tokio::task::spawn_blocking(move || drop(mounted))
+            .await
+            .map_err(|e| ServerError::Io(e.to_string()))
+    }
+}
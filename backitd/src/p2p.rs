@@ -1,61 +1,219 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, io, path::Path, sync::Arc, time::Duration};
 
+use async_trait::async_trait;
 use eyre::eyre;
 use futures::{
     channel::{mpsc, oneshot},
-    SinkExt, StreamExt,
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, SinkExt, StreamExt,
 };
 use libp2p::{
-    identity, kad, noise,
-    request_response::{self, ProtocolSupport},
+    identity, kad,
+    multiaddr::Protocol,
+    noise,
+    request_response::{self, OutboundRequestId, ProtocolSupport, ResponseChannel},
     swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux, PeerId, StreamProtocol, Swarm, SwarmBuilder,
+    tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm, SwarmBuilder,
 };
 
 use backit_core::tcp::*;
-use signals::{ToSwarm};
+use serde::{de::DeserializeOwned, Serialize};
+use signals::{FromSwarm, ToSwarm};
+
+use crate::{metrics::Metrics, throttle::Throttle};
 
 pub mod signals {
     use std::fmt::Display;
 
     use futures::channel::{mpsc::SendError, oneshot};
+    use libp2p::{request_response::ResponseChannel, Multiaddr, PeerId};
+
+    use super::Frame;
 
     #[derive(Debug)]
     pub enum ToSwarm {
-        StartListening { tx: oneshot::Sender<bool> },
+        StartListening {
+            addresses: Vec<Multiaddr>,
+            tx: oneshot::Sender<bool>,
+        },
+        Dial {
+            peer: PeerId,
+            address: Multiaddr,
+            tx: oneshot::Sender<eyre::Result<()>>,
+        },
+        Disconnect {
+            peer: PeerId,
+            tx: oneshot::Sender<bool>,
+        },
+        Request {
+            peer: PeerId,
+            request: Frame,
+            tx: oneshot::Sender<eyre::Result<Frame>>,
+        },
+        Respond {
+            response: Frame,
+            channel: ResponseChannel<Frame>,
+        },
+        ConnectedPeers {
+            tx: oneshot::Sender<usize>,
+        },
+    }
+
+    /// things that happened in the swarm the daemon has to act on
+    #[derive(Debug)]
+    pub enum FromSwarm {
+        InboundRequest {
+            peer: PeerId,
+            request: Frame,
+            channel: ResponseChannel<Frame>,
+        },
     }
-    
 }
 
+#[derive(Clone)]
 pub struct Client {
     tx: mpsc::Sender<ToSwarm>,
+    local_peer: PeerId,
+    throttle: Arc<Throttle>,
+    metrics: Arc<Metrics>,
 }
 impl Client {
-    pub fn new(tx: mpsc::Sender<ToSwarm>) -> Self {
-        Self { tx }
+    pub fn new(tx: mpsc::Sender<ToSwarm>, local_peer: PeerId, throttle: Arc<Throttle>) -> Self {
+        Self {
+            tx,
+            local_peer,
+            throttle,
+            metrics: Arc::new(Metrics::default()),
+        }
+    }
+    /// the peer id of this daemon
+    pub fn local_peer(&self) -> PeerId {
+        self.local_peer
+    }
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
     }
-    pub async fn start_listening(&mut self) -> bool {
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+    pub async fn start_listening(&mut self, addresses: Vec<Multiaddr>) -> eyre::Result<bool> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(ToSwarm::StartListening { addresses, tx })
+            .await
+            .map_err(gone)?;
+        rx.await.map_err(gone)
+    }
+    /// connect to a peer, this returns once the connection is established
+    pub async fn dial(&mut self, peer: PeerId, address: Multiaddr) -> eyre::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(ToSwarm::Dial { peer, address, tx })
+            .await
+            .map_err(gone)?;
+        rx.await.map_err(gone)?
+    }
+    /// close every connection to a peer, returns false if we were not connected
+    pub async fn disconnect(&mut self, peer: PeerId) -> eyre::Result<bool> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(ToSwarm::Disconnect { peer, tx })
+            .await
+            .map_err(gone)?;
+        rx.await.map_err(gone)
+    }
+    /// how many peers we have a connection with
+    pub async fn connected_peers(&mut self) -> eyre::Result<usize> {
         let (tx, rx) = oneshot::channel();
-        self.tx.send(ToSwarm::StartListening { tx }).await.expect("receiver not to be dropped");
-        let result = rx.await.expect("sender not be dropped");
-        result
+        self.tx
+            .send(ToSwarm::ConnectedPeers { tx })
+            .await
+            .map_err(gone)?;
+        rx.await.map_err(gone)
     }
+    /// send a request to a peer and wait for its answer, both count against the rate limits
+    pub async fn request(
+        &mut self,
+        peer: PeerId,
+        request: SendPacket,
+    ) -> eyre::Result<ReceivePacket> {
+        let request = Frame::encode(&request)?;
+        let size = request.size();
+        self.throttle.upload(&peer, size).await;
+        self.metrics.sent(&peer, size);
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(ToSwarm::Request { peer, request, tx })
+            .await
+            .map_err(gone)?;
+        let response = rx.await.map_err(gone)??;
+        let size = response.size();
+        self.metrics.received(&peer, size);
+        self.throttle.download(&peer, size).await;
+        response
+            .decode()
+            .map_err(|e| eyre!("invalid response from {peer}: {e}"))
+    }
+    /// count a request we got through [`FromSwarm::InboundRequest`] against the rate limits and
+    /// decode it, this waits when the peer sent more than allowed
+    pub async fn received(&self, peer: &PeerId, request: Frame) -> io::Result<SendPacket> {
+        let size = request.size();
+        self.metrics.received(peer, size);
+        self.throttle.download(peer, size).await;
+        request.decode()
+    }
+    /// answer a request we got through [`FromSwarm::InboundRequest`]
+    pub async fn respond(
+        &mut self,
+        peer: &PeerId,
+        response: ReceivePacket,
+        channel: ResponseChannel<Frame>,
+    ) -> eyre::Result<()> {
+        let response = Frame::encode(&response)?;
+        let size = response.size();
+        self.throttle.upload(peer, size).await;
+        self.metrics.sent(peer, size);
+        self.tx
+            .send(ToSwarm::Respond { response, channel })
+            .await
+            .map_err(gone)
+    }
+}
+
+/// the event loop stopped, which only happens while the daemon shuts down
+fn gone(_: impl std::fmt::Display) -> eyre::Report {
+    eyre!("the p2p event loop is not running")
 }
 
 pub struct EventLoop {
     swarm: Swarm<Behaviour>,
     command_queue: mpsc::Receiver<ToSwarm>,
+    events: mpsc::Sender<FromSwarm>,
+    /// everyone waiting for a connection to a peer, several dials of the same peer can be running
+    pending_dial: HashMap<PeerId, Vec<oneshot::Sender<eyre::Result<()>>>>,
+    pending_requests: HashMap<OutboundRequestId, oneshot::Sender<eyre::Result<Frame>>>,
 }
 impl EventLoop {
-    pub fn new() -> eyre::Result<(Self, Client)> {
+    pub fn new(
+        key: identity::Keypair,
+        throttle: Arc<Throttle>,
+    ) -> eyre::Result<(Self, Client, mpsc::Receiver<FromSwarm>)> {
         let (to_swarm_tx, to_swarm_rx) = mpsc::channel(16);
+        let (events_tx, events_rx) = mpsc::channel(16);
 
-        let swarm = new()?;
+        let swarm = new(key)?;
+        let local_peer = *swarm.local_peer_id();
         let out = Self {
             swarm,
             command_queue: to_swarm_rx,
+            events: events_tx,
+            pending_dial: HashMap::new(),
+            pending_requests: HashMap::new(),
         };
-        Ok((out, Client::new(to_swarm_tx)))
+        Ok((
+            out,
+            Client::new(to_swarm_tx, local_peer, throttle),
+            events_rx,
+        ))
     }
     pub async fn run(&mut self) {
         loop {
@@ -63,24 +221,278 @@ impl EventLoop {
                 event = self.swarm.select_next_some() => self.handle_event(event).await,
                 command = self.command_queue.next() => match command {
                     Some(x) => self.handle_command(x),
-                    None => {}
+                    // the daemon is gone, so nobody can use the swarm anymore
+                    None => return,
                 }
             };
         }
     }
     pub async fn handle_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
-        dbg!(event);
+        match event {
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                for tx in self.pending_dial.remove(&peer_id).unwrap_or_default() {
+                    let _ = tx.send(Ok(()));
+                }
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error,
+                ..
+            } => {
+                for tx in self.pending_dial.remove(&peer_id).unwrap_or_default() {
+                    let _ = tx.send(Err(eyre!("could not connect to {peer_id}: {error}")));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(event)) => {
+                self.handle_request_response(event).await
+            }
+            SwarmEvent::NewListenAddr { address, .. } => {
+                let peer = *self.swarm.local_peer_id();
+                println!("listening on {}", address.with(Protocol::P2p(peer)));
+            }
+            event => {
+                dbg!(event);
+            }
+        }
+    }
+    async fn handle_request_response(&mut self, event: request_response::Event<Frame, Frame>) {
+        use request_response::{Event, Message};
+        match event {
+            Event::Message {
+                peer,
+                message:
+                    Message::Request {
+                        request, channel, ..
+                    },
+                ..
+            } => {
+                self.events
+                    .send(FromSwarm::InboundRequest {
+                        peer,
+                        request,
+                        channel,
+                    })
+                    .await
+                    .expect("event receiver not to be dropped");
+            }
+            Event::Message {
+                message:
+                    Message::Response {
+                        request_id,
+                        response,
+                        ..
+                    },
+                ..
+            } => {
+                if let Some(tx) = self.pending_requests.remove(&request_id) {
+                    let _ = tx.send(Ok(response));
+                }
+            }
+            Event::OutboundFailure {
+                request_id,
+                error,
+                peer,
+                ..
+            } => {
+                if let Some(tx) = self.pending_requests.remove(&request_id) {
+                    let _ = tx.send(Err(eyre!("request to {peer} failed: {error}")));
+                }
+            }
+            event => {
+                dbg!(event);
+            }
+        }
     }
     pub fn handle_command(&mut self, command: ToSwarm) {
-        dbg!(command);
+        match command {
+            ToSwarm::StartListening { addresses, tx } => {
+                let mut ok = true;
+                for address in addresses {
+                    if let Err(e) = self.swarm.listen_on(address.clone()) {
+                        eprintln!("could not listen on {address}: {e}");
+                        ok = false;
+                    }
+                }
+                let _ = tx.send(ok);
+            }
+            ToSwarm::Dial { peer, address, tx } => {
+                if self.swarm.is_connected(&peer) {
+                    let _ = tx.send(Ok(()));
+                    return;
+                }
+                self.swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(&peer, address.clone());
+                match self
+                    .swarm
+                    .dial(address.with_p2p(peer).unwrap_or_else(|x| x))
+                {
+                    Ok(()) => {
+                        self.pending_dial.entry(peer).or_default().push(tx);
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(eyre!("could not dial {peer}: {e}")));
+                    }
+                }
+            }
+            ToSwarm::Disconnect { peer, tx } => {
+                let _ = tx.send(self.swarm.disconnect_peer_id(peer).is_ok());
+            }
+            ToSwarm::Request { peer, request, tx } => {
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_request(&peer, request);
+                self.pending_requests.insert(request_id, tx);
+            }
+            ToSwarm::Respond { response, channel } => {
+                if self
+                    .swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_response(channel, response)
+                    .is_err()
+                {
+                    eprintln!("could not respond, the peer closed the connection");
+                }
+            }
+            ToSwarm::ConnectedPeers { tx } => {
+                let _ = tx.send(self.swarm.connected_peers().count());
+            }
+        }
+    }
+}
+
+/// read the keypair of this daemon, a new one is generated on first start
+///
+/// the peer id is derived from this key so it has to survive restarts, otherwise peers would no
+/// longer recognize us
+pub fn load_or_generate_identity(path: &Path) -> eyre::Result<identity::Keypair> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(identity::Keypair::from_protobuf_encoding(&bytes)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key = identity::Keypair::generate_ed25519();
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(path, key.to_protobuf_encoding()?)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+            }
+            Ok(key)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// split a `/ip4/../p2p/<peer id>` address into the peer and the address to dial
+pub fn split_peer_address(mut address: Multiaddr) -> eyre::Result<(PeerId, Multiaddr)> {
+    match address.pop() {
+        Some(Protocol::P2p(peer)) => Ok((peer, address)),
+        _ => Err(eyre!("address {address} does not end in /p2p/<peer id>")),
+    }
+}
+
+/// the largest request or response we accept from a peer
+const MAX_PACKET_SIZE: u64 = 4 * 1024 * 1024;
+
+/// a cbor packet as it goes over the wire, it is encoded once so its size can be counted
+/// against the rate limits
+#[derive(Debug, Clone)]
+pub struct Frame(Vec<u8>);
+impl Frame {
+    fn encode<P: Serialize>(packet: &P) -> eyre::Result<Self> {
+        Ok(Self(serde_cbor::to_vec(packet)?))
+    }
+    fn decode<P: DeserializeOwned>(&self) -> io::Result<P> {
+        serde_cbor::from_slice(&self.0).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+    fn size(&self) -> u64 {
+        self.0.len() as u64
+    }
+}
+
+/// frames of at most [`MAX_PACKET_SIZE`], the cbor codec of libp2p caps requests at 1 MiB
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PacketCodec;
+
+/// read a whole frame, failing when the peer sends more than we accept
+async fn read_frame<T>(io: &mut T) -> io::Result<Frame>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut data = Vec::new();
+    io.take(MAX_PACKET_SIZE + 1).read_to_end(&mut data).await?;
+    if data.len() as u64 > MAX_PACKET_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "packet is too large",
+        ));
+    }
+    Ok(Frame(data))
+}
+
+async fn write_frame<T>(io: &mut T, frame: &Frame) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    io.write_all(&frame.0).await?;
+    io.close().await
+}
+
+#[async_trait]
+impl request_response::Codec for PacketCodec {
+    type Protocol = StreamProtocol;
+    type Request = Frame;
+    type Response = Frame;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Frame>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_frame(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Frame>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_frame(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: Frame,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frame(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: Frame,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frame(io, &response).await
     }
 }
 
-fn new() -> eyre::Result<Swarm<Behaviour>> {
-    let key = identity::Keypair::generate_ed25519();
+fn new(key: identity::Keypair) -> eyre::Result<Swarm<Behaviour>> {
     let peer_id = key.public().to_peer_id();
 
-    let mut swarm = SwarmBuilder::with_new_identity()
+    let mut swarm = SwarmBuilder::with_existing_identity(key)
         .with_tokio()
         .with_tcp(
             tcp::Config::default(),
@@ -92,9 +504,11 @@ fn new() -> eyre::Result<Swarm<Behaviour>> {
                 peer_id,
                 kad::store::MemoryStore::new(key.public().to_peer_id()),
             ),
-            request_response: request_response::cbor::Behaviour::new(
+            // chunks are sent as a single request, so allow a bit more than the largest chunk
+            request_response: request_response::Behaviour::with_codec(
+                PacketCodec,
                 [(StreamProtocol::new("/backit"), ProtocolSupport::Full)],
-                request_response::Config::default(),
+                request_response::Config::default().with_request_timeout(Duration::from_secs(60)),
             ),
         })?
         .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
@@ -110,6 +524,6 @@ fn new() -> eyre::Result<Swarm<Behaviour>> {
 
 #[derive(NetworkBehaviour)]
 pub struct Behaviour {
-    request_response: request_response::cbor::Behaviour<SendPacket, ReceivePacket>,
+    request_response: request_response::Behaviour<PacketCodec>,
     kademlia: kad::Behaviour<kad::store::MemoryStore>,
 }
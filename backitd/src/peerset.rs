@@ -0,0 +1,824 @@
+//! backups spread over a set of peers, as full copies or reed-solomon erasure coded
+//!
+//! a chunk is stored as one or more pieces: a replicated chunk is a single piece that several
+//! peers hold, an erasure coded chunk is cut into shards that each live on a different peer.
+//! which peer holds which piece is tracked in the local database, every peer gets a copy of the
+//! manifest that lists the pieces it holds so its garbage collection keeps them. the copies also
+//! carry where every piece is, so a daemon that lost its database can still read the set
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use backit_core::{
+    ipc::ServerError,
+    snapshot::{ChunkHash, GcStats, Placement, Snapshot, SnapshotId, SnapshotInfo, VerifyReport},
+    tcp::{ReceivePacket, SendPacket},
+};
+use libp2p::{Multiaddr, PeerId};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backup::{snapshot_packets, ManifestParts},
+    p2p::{self, Client},
+    store::Store,
+    Server,
+};
+
+/// how often peers are checked and the pieces of lost peers are moved
+const REPAIR_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// the tree of [`PeerSet::seen`], peers outside of sets are tracked as well
+fn seen_tree(store: &Store) -> Result<sled::Tree, ServerError> {
+    store
+        .db()
+        .open_tree("peers_seen")
+        .map_err(|e| ServerError::Io(e.to_string()))
+}
+
+/// remember that a request to or from a peer succeeded just now
+pub fn mark_seen(store: &Store, peer: &PeerId) -> Result<(), ServerError> {
+    seen_tree(store)?
+        .insert(peer.to_string(), &unix_now().to_be_bytes()[..])
+        .map_err(|e| ServerError::Io(e.to_string()))?;
+    Ok(())
+}
+
+/// every peer we talked to with the unix time we last did
+pub fn last_seen(store: &Store) -> Result<Vec<(String, u64)>, ServerError> {
+    let mut out = Vec::new();
+    for x in seen_tree(store)?.iter() {
+        let (peer, time) = x.map_err(|e| ServerError::Io(e.to_string()))?;
+        let Ok(time) = <[u8; 8]>::try_from(time.as_ref()) else {
+            continue;
+        };
+        out.push((
+            String::from_utf8_lossy(&peer).into_owned(),
+            u64::from_be_bytes(time),
+        ));
+    }
+    Ok(out)
+}
+
+/// how the chunks of a backup are spread over the peers of a set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "mode")]
+pub enum Redundancy {
+    /// every chunk is stored in full on `copies` peers
+    Replicate { copies: usize },
+    /// every chunk is cut into `data` shards and `parity` extra shards that each go to a
+    /// different peer, any `data` of them give back the chunk
+    Erasure { data: usize, parity: usize },
+}
+
+/// a named set of peers that can be used wherever a host is expected
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerSetConfig {
+    /// `/ip4/../p2p/<peer id>` addresses of the peers
+    pub peers: Vec<Multiaddr>,
+    pub redundancy: Redundancy,
+    /// a peer that was unreachable for this long is considered gone and its pieces are moved to
+    /// the other peers
+    #[serde(with = "humantime_serde", default = "default_lost_after")]
+    pub lost_after: Duration,
+}
+fn default_lost_after() -> Duration {
+    Duration::from_secs(3 * 24 * 60 * 60)
+}
+impl Redundancy {
+    /// every piece of a chunk goes to a different peer, so there have to be enough of them
+    fn check(&self, peers: usize) -> Result<(), String> {
+        match *self {
+            Self::Replicate { copies } if copies == 0 || copies > peers => {
+                Err(format!("{copies} copies need between 1 and {peers} peers"))
+            }
+            Self::Erasure { data, parity } if data == 0 || parity == 0 => {
+                Err("erasure coding needs at least one data and one parity shard".to_string())
+            }
+            Self::Erasure { data, parity } if data + parity > peers => Err(format!(
+                "{data} data and {parity} parity shards need {} peers, there are {peers}",
+                data + parity
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn coding_error(e: reed_solomon_erasure::Error) -> ServerError {
+    ServerError::Io(format!("erasure coding failed: {e:?}"))
+}
+
+/// cut a chunk into `data` equally sized shards and add `parity` shards
+fn encode(chunk: &[u8], data: usize, parity: usize) -> Result<Vec<Vec<u8>>, ServerError> {
+    let rs = ReedSolomon::new(data, parity).map_err(coding_error)?;
+    let shard_len = chunk.len().div_ceil(data).max(1);
+    let mut shards: Vec<Vec<u8>> = (0..data + parity)
+        .map(|i| {
+            let start = (i * shard_len).min(chunk.len());
+            let end = ((i + 1) * shard_len).min(chunk.len());
+            let mut shard = if i < data {
+                chunk[start..end].to_vec()
+            } else {
+                Vec::new()
+            };
+            shard.resize(shard_len, 0);
+            shard
+        })
+        .collect();
+    rs.encode(&mut shards).map_err(coding_error)?;
+    Ok(shards)
+}
+
+/// a piece of a chunk and how many peers should hold it
+type Piece = (Vec<u8>, usize);
+
+/// the pieces of a chunk, and how many of them it takes to read it or 0 for copies
+fn pieces(chunk: &[u8], redundancy: &Redundancy) -> Result<(Vec<Piece>, usize), ServerError> {
+    match redundancy {
+        Redundancy::Replicate { copies } => Ok((vec![(chunk.to_vec(), *copies)], 0)),
+        Redundancy::Erasure { data, parity } => Ok((
+            encode(chunk, *data, *parity)?
+                .into_iter()
+                .map(|x| (x, 1))
+                .collect(),
+            *data,
+        )),
+    }
+}
+
+pub struct PeerSet {
+    name: String,
+    config: PeerSetConfig,
+    client: Client,
+    peers: Vec<(PeerId, Multiaddr)>,
+    /// [`Placement`] by chunk hash
+    placements: sled::Tree,
+    /// when a request to a peer last succeeded, by peer id
+    seen: sled::Tree,
+}
+impl PeerSet {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn open(
+        name: &str,
+        config: PeerSetConfig,
+        client: Client,
+        store: &Store,
+    ) -> Result<Self, ServerError> {
+        let db_err = |e: sled::Error| ServerError::Io(e.to_string());
+        let peers = config
+            .peers
+            .iter()
+            .map(|x| p2p::split_peer_address(x.clone()))
+            .collect::<eyre::Result<Vec<_>>>()
+            .map_err(|e| ServerError::Peer(format!("invalid peer in set {name}: {e}")))?;
+        config
+            .redundancy
+            .check(peers.len())
+            .map_err(|e| ServerError::Peer(format!("invalid redundancy of set {name}: {e}")))?;
+        let placements = store
+            .db()
+            .open_tree(format!("set/{name}/placements"))
+            .map_err(db_err)?;
+        let seen = seen_tree(store)?;
+        // a peer we never reached counts as seen from the moment it was added to a set
+        for (peer, _) in &peers {
+            seen.compare_and_swap(
+                peer.to_string(),
+                None as Option<&[u8]>,
+                Some(&unix_now().to_be_bytes()[..]),
+            )
+            .map_err(db_err)?
+            .ok();
+        }
+        Ok(Self {
+            name: name.to_string(),
+            config,
+            client,
+            peers,
+            placements,
+            seen,
+        })
+    }
+
+    fn is_lost(&self, peer: &PeerId) -> bool {
+        let seen = self
+            .seen
+            .get(peer.to_string())
+            .ok()
+            .flatten()
+            .and_then(|x| <[u8; 8]>::try_from(x.as_ref()).ok())
+            .map_or(0, u64::from_be_bytes);
+        unix_now().saturating_sub(seen) > self.config.lost_after.as_secs()
+    }
+    /// the peers in the order pieces of a chunk are placed on them, peers that are gone last
+    fn ranked(&self, hash: &ChunkHash) -> Vec<PeerId> {
+        let mut peers: Vec<(bool, [u8; 32], PeerId)> = self
+            .peers
+            .iter()
+            .map(|(peer, _)| {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(hash.as_bytes());
+                hasher.update(&peer.to_bytes());
+                (self.is_lost(peer), *hasher.finalize().as_bytes(), *peer)
+            })
+            .collect();
+        peers.sort();
+        peers.into_iter().map(|(_, _, peer)| peer).collect()
+    }
+
+    /// send a request to a peer of the set, dialing it first when we are not connected
+    async fn request(
+        &mut self,
+        peer: PeerId,
+        request: SendPacket,
+    ) -> Result<ReceivePacket, ServerError> {
+        let address = self
+            .peers
+            .iter()
+            .find(|(x, _)| *x == peer)
+            .map(|(_, address)| address.clone())
+            .ok_or_else(|| ServerError::UnknownHost(peer.to_string()))?;
+        if let Err(e) = self.client.dial(peer, address).await {
+            return Err(ServerError::Peer(e.to_string()));
+        }
+        let response = self
+            .client
+            .request(peer, request)
+            .await
+            .map_err(|e| ServerError::Peer(e.to_string()))?;
+        self.seen
+            .insert(peer.to_string(), &unix_now().to_be_bytes()[..])
+            .map_err(|e| ServerError::Io(e.to_string()))?;
+        match response {
+            ReceivePacket::Error(e) => Err(e),
+            x => Ok(x),
+        }
+    }
+    /// ask every peer, peers that can not be reached are skipped as long as one answers
+    async fn request_all(
+        &mut self,
+        request: SendPacket,
+    ) -> Result<Vec<(PeerId, ReceivePacket)>, ServerError> {
+        let mut out = Vec::new();
+        let mut last_error = None;
+        for peer in self.peers.iter().map(|(x, _)| *x).collect::<Vec<_>>() {
+            match self.request(peer, request.clone()).await {
+                Ok(x) => out.push((peer, x)),
+                Err(e) => {
+                    eprintln!("peer {peer} of set {} failed: {e:?}", self.name);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match (out.is_empty(), last_error) {
+            (true, Some(e)) => Err(e),
+            _ => Ok(out),
+        }
+    }
+
+    fn placement(&self, hash: &ChunkHash) -> Result<Option<Placement>, ServerError> {
+        match self
+            .placements
+            .get(hash.as_bytes())
+            .map_err(|e| ServerError::Io(e.to_string()))?
+        {
+            Some(x) => {
+                let placement: Placement =
+                    serde_cbor::from_slice(&x).map_err(|e| ServerError::Io(e.to_string()))?;
+                match placement.is_valid() {
+                    true => Ok(Some(placement)),
+                    false => Err(ServerError::Io(format!(
+                        "placement of chunk {hash} is invalid"
+                    ))),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+    fn set_placement(&self, hash: &ChunkHash, placement: &Placement) -> Result<(), ServerError> {
+        if !placement.is_valid() {
+            return Err(ServerError::InvalidPacket);
+        }
+        let bytes = serde_cbor::to_vec(placement).map_err(|e| ServerError::Io(e.to_string()))?;
+        self.placements
+            .insert(hash.as_bytes(), bytes)
+            .map_err(|e| ServerError::Io(e.to_string()))?;
+        Ok(())
+    }
+
+    /// every chunk that is placed on the set
+    fn placed(&self) -> Result<Vec<ChunkHash>, ServerError> {
+        let mut out = Vec::new();
+        for x in self.placements.iter() {
+            let (key, _) = x.map_err(|e| ServerError::Io(e.to_string()))?;
+            if let Ok(bytes) = <[u8; 32]>::try_from(key.as_ref()) {
+                out.push(ChunkHash::from_bytes(bytes));
+            }
+        }
+        Ok(out)
+    }
+    /// the chunks that are not placed on the set yet, or that a holder lost a piece of
+    ///
+    /// the holders are asked for every piece, which marks the pieces as in use so their garbage
+    /// collection keeps them until the manifest arrives. the pieces of a backup that did not
+    /// finish have no manifest and are collected after the grace period, so a placement alone
+    /// does not say the chunk is there. a holder that can not be reached counts as not having
+    /// its pieces, the chunk is placed again on the others
+    pub async fn missing_chunks(
+        &mut self,
+        hashes: &[ChunkHash],
+    ) -> Result<Vec<ChunkHash>, ServerError> {
+        let mut out = Vec::new();
+        let mut placed = Vec::new();
+        let mut asked: HashMap<String, Vec<ChunkHash>> = HashMap::new();
+        for hash in hashes {
+            let Some(placement) = self.placement(hash)? else {
+                out.push(*hash);
+                continue;
+            };
+            for (piece, holders) in &placement.pieces {
+                for holder in holders {
+                    asked.entry(holder.clone()).or_default().push(*piece);
+                }
+            }
+            placed.push((*hash, placement));
+        }
+        let mut gone: HashSet<(String, ChunkHash)> = HashSet::new();
+        for (holder, pieces) in asked {
+            let missing = match holder.parse::<PeerId>() {
+                Ok(peer) => match self
+                    .request(peer, SendPacket::HasChunks(pieces.clone()))
+                    .await
+                {
+                    Ok(ReceivePacket::MissingChunks(x)) => x,
+                    Ok(_) => return Err(ServerError::InvalidPacket),
+                    Err(e) => {
+                        eprintln!("peer {peer} of set {} failed: {e:?}", self.name);
+                        pieces
+                    }
+                },
+                Err(_) => pieces,
+            };
+            gone.extend(missing.into_iter().map(|x| (holder.clone(), x)));
+        }
+        for (hash, placement) in placed {
+            let lost = placement.pieces.iter().any(|(piece, holders)| {
+                holders.iter().any(|x| gone.contains(&(x.clone(), *piece)))
+            });
+            if lost {
+                out.push(hash);
+            }
+        }
+        Ok(out)
+    }
+
+    async fn put_piece(
+        &mut self,
+        peer: PeerId,
+        hash: ChunkHash,
+        data: Vec<u8>,
+    ) -> Result<(), ServerError> {
+        match self
+            .request(peer, SendPacket::HasChunks(vec![hash]))
+            .await?
+        {
+            ReceivePacket::MissingChunks(x) if x.is_empty() => return Ok(()),
+            ReceivePacket::MissingChunks(_) => {}
+            _ => return Err(ServerError::InvalidPacket),
+        }
+        match self
+            .request(peer, SendPacket::PutChunk { hash, data })
+            .await?
+        {
+            ReceivePacket::Ok => Ok(()),
+            _ => Err(ServerError::InvalidPacket),
+        }
+    }
+    /// store a piece on the next peer that takes it, peers that can not be reached are skipped
+    async fn place_piece(
+        &mut self,
+        hash: ChunkHash,
+        data: &[u8],
+        candidates: &mut impl Iterator<Item = PeerId>,
+    ) -> Result<PeerId, ServerError> {
+        for peer in candidates {
+            match self.put_piece(peer, hash, data.to_vec()).await {
+                Ok(()) => return Ok(peer),
+                // a peer that is unreachable or full, try the next one
+                Err(
+                    e @ (ServerError::Peer(_)
+                    | ServerError::QuotaExceeded { .. }
+                    | ServerError::DiskFull { .. }),
+                ) => eprintln!("peer {peer} of set {} refused a piece: {e:?}", self.name),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(ServerError::Peer(format!(
+            "not enough peers of set {} can be reached",
+            self.name
+        )))
+    }
+    pub async fn put_chunk(&mut self, hash: ChunkHash, data: Vec<u8>) -> Result<(), ServerError> {
+        let (pieces, data_shards) = pieces(&data, &self.config.redundancy)?;
+        let mut candidates = self.ranked(&hash).into_iter();
+        let mut placement = Placement {
+            len: data.len() as u64,
+            pieces: Vec::new(),
+            data_shards,
+        };
+        for (piece, copies) in pieces {
+            let piece_hash = ChunkHash::of(&piece);
+            let mut holders = Vec::new();
+            for _ in 0..copies {
+                let peer = self
+                    .place_piece(piece_hash, &piece, &mut candidates)
+                    .await?;
+                holders.push(peer.to_string());
+            }
+            placement.pieces.push((piece_hash, holders));
+        }
+        self.set_placement(&hash, &placement)
+    }
+
+    /// fetch a piece from any of the peers holding it
+    async fn get_piece(&mut self, hash: ChunkHash, holders: &[String]) -> Option<Vec<u8>> {
+        for peer in holders.iter().filter_map(|x| x.parse::<PeerId>().ok()) {
+            match self.request(peer, SendPacket::GetChunk(hash)).await {
+                Ok(ReceivePacket::Chunk(x)) if ChunkHash::of(&x) == hash => return Some(x),
+                Ok(_) => eprintln!("peer {peer} of set {} lost piece {hash}", self.name),
+                Err(e) => eprintln!("peer {peer} of set {} failed: {e:?}", self.name),
+            }
+        }
+        None
+    }
+    pub async fn get_chunk(&mut self, hash: ChunkHash) -> Result<Vec<u8>, ServerError> {
+        let placement = self
+            .placement(&hash)?
+            .ok_or(ServerError::MissingChunk(hash))?;
+        let data = if placement.data_shards == 0 {
+            let (piece, holders) = &placement.pieces[0];
+            self.get_piece(*piece, holders)
+                .await
+                .ok_or(ServerError::MissingChunk(hash))?
+        } else {
+            let data = placement.data_shards;
+            let mut shards: Vec<Option<Vec<u8>>> = vec![None; placement.pieces.len()];
+            let mut found = 0;
+            for (i, (piece, holders)) in placement.pieces.iter().enumerate() {
+                if found == data {
+                    break;
+                }
+                shards[i] = self.get_piece(*piece, holders).await;
+                found += shards[i].is_some() as usize;
+            }
+            if found < data {
+                return Err(ServerError::MissingChunk(hash));
+            }
+            if shards[..data].iter().any(Option::is_none) {
+                ReedSolomon::new(data, placement.pieces.len() - data)
+                    .map_err(coding_error)?
+                    .reconstruct_data(&mut shards)
+                    .map_err(coding_error)?;
+            }
+            let mut chunk: Vec<u8> = shards.into_iter().take(data).flatten().flatten().collect();
+            chunk.truncate(placement.len as usize);
+            chunk
+        };
+        if ChunkHash::of(&data) != hash {
+            return Err(ServerError::MissingChunk(hash));
+        }
+        Ok(data)
+    }
+
+    /// give every peer a copy of the manifest with the pieces it holds
+    ///
+    /// every peer holding pieces of the snapshot has to take it, otherwise its garbage collection
+    /// would remove them. the other peers only get it so any peer can list the snapshots
+    pub async fn put_snapshot(&mut self, snapshot: Snapshot) -> Result<(), ServerError> {
+        let mut held: HashMap<String, Vec<ChunkHash>> = HashMap::new();
+        let chunks: HashSet<ChunkHash> = snapshot.chunks().copied().collect();
+        let mut placements = Vec::new();
+        for hash in &chunks {
+            let placement = self
+                .placement(hash)?
+                .ok_or(ServerError::MissingChunk(*hash))?;
+            for (piece, holders) in &placement.pieces {
+                for peer in holders {
+                    held.entry(peer.clone()).or_default().push(*piece);
+                }
+            }
+            placements.push((*hash, placement));
+        }
+        for peer in self.peers.iter().map(|(x, _)| *x).collect::<Vec<_>>() {
+            let pieces = held.remove(&peer.to_string()).unwrap_or_default();
+            let required = !pieces.is_empty();
+            let copy = Snapshot {
+                pieces: Some(pieces),
+                placements: Some(placements.clone()),
+                ..snapshot.clone()
+            };
+            match self.send_snapshot(peer, &copy).await {
+                Ok(()) => {}
+                Err(e) if required => return Err(e),
+                Err(e) => eprintln!("peer {peer} of set {} failed: {e:?}", self.name),
+            }
+        }
+        Ok(())
+    }
+    /// store a manifest on a peer of the set, in parts
+    async fn send_snapshot(
+        &mut self,
+        peer: PeerId,
+        snapshot: &Snapshot,
+    ) -> Result<(), ServerError> {
+        for request in snapshot_packets(snapshot)? {
+            match self.request(peer, request).await? {
+                ReceivePacket::Ok => {}
+                _ => return Err(ServerError::InvalidPacket),
+            }
+        }
+        Ok(())
+    }
+    async fn fetch_snapshot(
+        &mut self,
+        peer: PeerId,
+        id: SnapshotId,
+    ) -> Result<Snapshot, ServerError> {
+        let mut parts = ManifestParts::new(id);
+        while let Some(request) = parts.next_request() {
+            let response = self.request(peer, request).await?;
+            parts.add(response)?;
+        }
+        parts.finish()
+    }
+    pub async fn snapshots(
+        &mut self,
+        job: Option<String>,
+    ) -> Result<Vec<SnapshotInfo>, ServerError> {
+        let mut out = BTreeMap::new();
+        for (_, response) in self.request_all(SendPacket::Snapshots { job }).await? {
+            let ReceivePacket::Snapshots(x) = response else {
+                return Err(ServerError::InvalidPacket);
+            };
+            out.extend(x.into_iter().map(|x| (x.id, x)));
+        }
+        let mut out: Vec<SnapshotInfo> = out.into_values().collect();
+        out.sort_by_key(|x| x.time);
+        Ok(out)
+    }
+    /// fetch a manifest from any peer, placements we do not know yet are taken from its copy
+    pub async fn snapshot(&mut self, id: SnapshotId) -> Result<Snapshot, ServerError> {
+        for peer in self.peers.iter().map(|(x, _)| *x).collect::<Vec<_>>() {
+            let copy = match self.fetch_snapshot(peer, id).await {
+                Ok(x) => x,
+                Err(e) => {
+                    eprintln!("peer {peer} of set {} failed: {e:?}", self.name);
+                    continue;
+                }
+            };
+            let placements = copy.placements.iter().flatten();
+            if let Some((hash, _)) = placements.clone().find(|(_, x)| !x.is_valid()) {
+                eprintln!(
+                    "peer {peer} of set {} sent an invalid placement of chunk {hash}",
+                    self.name
+                );
+                continue;
+            }
+            for (hash, placement) in placements {
+                if self.placement(hash)?.is_none() {
+                    self.set_placement(hash, placement)?;
+                }
+            }
+            return Ok(Snapshot {
+                pieces: None,
+                placements: None,
+                ..copy
+            });
+        }
+        Err(ServerError::UnknownSnapshot(id))
+    }
+    /// every manifest stored on the set
+    async fn manifests(&mut self) -> Result<Vec<Snapshot>, ServerError> {
+        let mut out = Vec::new();
+        for info in self.snapshots(None).await? {
+            out.push(self.snapshot(info.id).await?);
+        }
+        Ok(out)
+    }
+
+    /// forget snapshots on every peer, chunks no remaining snapshot uses are dropped from the
+    /// placements so the next backup stores them again
+    pub async fn forget(
+        &mut self,
+        ids: Vec<SnapshotId>,
+        dry_run: bool,
+    ) -> Result<GcStats, ServerError> {
+        let mut stats = GcStats::default();
+        for (_, response) in self
+            .request_all(SendPacket::ForgetSnapshots { ids, dry_run })
+            .await?
+        {
+            let ReceivePacket::Collected(x) = response else {
+                return Err(ServerError::InvalidPacket);
+            };
+            stats.chunks_removed += x.chunks_removed;
+            stats.bytes_freed += x.bytes_freed;
+            stats.deferred |= x.deferred;
+        }
+        if !dry_run {
+            let used: HashSet<ChunkHash> = self
+                .manifests()
+                .await?
+                .iter()
+                .flat_map(|x| x.chunks().copied().collect::<Vec<_>>())
+                .collect();
+            for hash in self.placed()? {
+                if !used.contains(&hash) {
+                    self.placements
+                        .remove(hash.as_bytes())
+                        .map_err(|e| ServerError::Io(e.to_string()))?;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// check the pieces on every peer, a snapshot is reported as damaged when a peer lost one of
+    /// its pieces even if the redundancy still allows restoring it
+    pub async fn verify(&mut self, full: bool) -> Result<VerifyReport, ServerError> {
+        let mut report = VerifyReport::new(full);
+        let mut damaged = HashSet::new();
+        for (_, response) in self.request_all(SendPacket::Verify { full }).await? {
+            let ReceivePacket::Verified(x) = response else {
+                return Err(ServerError::InvalidPacket);
+            };
+            report.snapshots = report.snapshots.max(x.snapshots);
+            report.chunks += x.chunks;
+            report.bytes += x.bytes;
+            report.missing.extend(x.missing);
+            report.corrupt.extend(x.corrupt);
+            damaged.extend(x.damaged);
+        }
+        report.damaged = damaged.into_iter().collect();
+        report.time = SystemTime::now();
+        Ok(report)
+    }
+
+    /// move the pieces of peers that are gone to the other peers, returns the number of pieces
+    /// that were moved
+    ///
+    /// a chunk or manifest that can not be moved does not stop the others, the repair fails at
+    /// the end when any of them failed
+    pub async fn repair(&mut self) -> Result<usize, ServerError> {
+        // talk to every peer once so those that are still there count as seen
+        let _ = self.request_all(SendPacket::Info).await;
+        let lost: HashSet<String> = self
+            .peers
+            .iter()
+            .filter(|(x, _)| self.is_lost(x))
+            .map(|(x, _)| x.to_string())
+            .collect();
+        if lost.is_empty() {
+            return Ok(0);
+        }
+
+        let mut moved = HashSet::new();
+        let mut count = 0;
+        let mut failed = 0;
+        for hash in self.placed()? {
+            match self.repair_chunk(hash, &lost).await {
+                Ok(0) => {}
+                Ok(x) => {
+                    moved.insert(hash);
+                    count += x;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "chunk {hash} of set {} could not be moved: {e:?}",
+                        self.name
+                    );
+                    failed += 1;
+                }
+            }
+        }
+
+        // the manifests on the new holders have to list the pieces, or they would be collected,
+        // and every copy has to know where the pieces are now
+        if !moved.is_empty() {
+            for snapshot in self.manifests().await? {
+                if !snapshot.chunks().any(|x| moved.contains(x)) {
+                    continue;
+                }
+                let id = snapshot.id;
+                if let Err(e) = self.put_snapshot(snapshot).await {
+                    eprintln!(
+                        "manifest {id} of set {} could not be updated: {e:?}",
+                        self.name
+                    );
+                    failed += 1;
+                }
+            }
+        }
+        match failed {
+            0 => Ok(count),
+            _ => Err(ServerError::Peer(format!(
+                "moved {count} pieces of set {}, {failed} chunks or manifests failed",
+                self.name
+            ))),
+        }
+    }
+    /// move the pieces of a chunk that lost peers hold, returns the number of pieces moved
+    async fn repair_chunk(
+        &mut self,
+        hash: ChunkHash,
+        lost: &HashSet<String>,
+    ) -> Result<usize, ServerError> {
+        let Some(mut placement) = self.placement(&hash)? else {
+            return Ok(0);
+        };
+        if !lost.iter().any(|x| placement.holds(x)) {
+            return Ok(0);
+        }
+        let chunk = self.get_chunk(hash).await?;
+        // the redundancy the chunk was stored with, the config may have changed since
+        let redundancy = match placement.data_shards {
+            0 => Redundancy::Replicate {
+                copies: placement.pieces[0].1.len(),
+            },
+            data => Redundancy::Erasure {
+                data,
+                parity: placement.pieces.len() - data,
+            },
+        };
+        let (fresh, _) = pieces(&chunk, &redundancy)?;
+        let mut candidates = self
+            .ranked(&hash)
+            .into_iter()
+            .filter(|x| !lost.contains(&x.to_string()) && !placement.holds(&x.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter();
+        let mut count = 0;
+        for ((piece, copies), (hash_of_piece, holders)) in
+            fresh.into_iter().zip(&mut placement.pieces)
+        {
+            if ChunkHash::of(&piece) != *hash_of_piece {
+                return Err(ServerError::Io(format!(
+                    "placement of chunk {hash} does not match it"
+                )));
+            }
+            holders.retain(|x| !lost.contains(x));
+            while holders.len() < copies {
+                let peer = self
+                    .place_piece(*hash_of_piece, &piece, &mut candidates)
+                    .await?;
+                holders.push(peer.to_string());
+                count += 1;
+            }
+        }
+        // only kept once every piece moved, so a later repair tries the rest again
+        self.set_placement(&hash, &placement)?;
+        Ok(count)
+    }
+}
+
+impl Server {
+    pub fn peer_set(&self, name: &str) -> Option<Result<PeerSet, ServerError>> {
+        let config = self.config.sets.get(name)?;
+        Some(PeerSet::open(
+            name,
+            config.clone(),
+            self.client.clone(),
+            &self.store,
+        ))
+    }
+
+    /// move the pieces of lost peers of every set, forever
+    pub async fn repair_loop(self) {
+        loop {
+            for name in self.config.sets.keys() {
+                let result = match self.peer_set(name) {
+                    Some(Ok(mut set)) => set.repair().await,
+                    Some(Err(e)) => Err(e),
+                    None => continue,
+                };
+                match result {
+                    Ok(0) => {}
+                    Ok(x) => eprintln!("moved {x} pieces of set {name} away from lost peers"),
+                    Err(e) => eprintln!("repair of set {name} failed: {e:?}"),
+                }
+            }
+            tokio::time::sleep(REPAIR_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::time::{Duration, Instant};
+
+use backit_core::ipc::{Progress, ServerReply};
+
+use crate::reply::Responder;
+
+/// how often progress is sent to the client at most, the last update is always sent
+const UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// keeps track of a long running command and streams [`ServerReply::Progress`] to the client
+///
+/// the rate is averaged over the whole transfer, which is stable enough for an eta and does not
+/// jump around on short stalls
+pub struct ProgressTracker {
+    reply: Responder,
+    progress: Progress,
+    started: Instant,
+    last_sent: Option<Instant>,
+}
+impl ProgressTracker {
+    pub fn new(reply: Responder) -> Self {
+        Self {
+            reply,
+            progress: Progress::default(),
+            started: Instant::now(),
+            last_sent: None,
+        }
+    }
+    /// set the totals once they are known, this can happen after the transfer started
+    pub fn set_totals(&mut self, bytes: Option<u64>, files: Option<u64>) {
+        self.progress.bytes_total = bytes;
+        self.progress.files_total = files;
+        self.update(false);
+    }
+    pub fn start_file(&mut self, name: impl Into<String>) {
+        self.progress.current = Some(name.into());
+        self.update(false);
+    }
+    pub fn add_bytes(&mut self, bytes: u64) {
+        self.progress.bytes_done += bytes;
+        self.update(false);
+    }
+    pub fn finish_file(&mut self) {
+        self.progress.files_done += 1;
+        self.update(false);
+    }
+    /// send the current state regardless of when the last update was sent
+    pub fn finish(&mut self) {
+        self.progress.current = None;
+        self.update(true);
+    }
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    fn update(&mut self, force: bool) {
+        let now = Instant::now();
+        if !force && matches!(self.last_sent, Some(x) if now - x < UPDATE_INTERVAL) {
+            return;
+        }
+        self.last_sent = Some(now);
+
+        let elapsed = (now - self.started).as_secs_f64();
+        let progress = &mut self.progress;
+        progress.bytes_per_sec = if elapsed > 0.0 {
+            (progress.bytes_done as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        progress.eta_secs = match progress.bytes_total {
+            Some(total) if progress.bytes_per_sec > 0 => {
+                Some(total.saturating_sub(progress.bytes_done) / progress.bytes_per_sec)
+            }
+            _ => None,
+        };
+        self.reply.send(ServerReply::Progress(progress.clone()));
+    }
+}
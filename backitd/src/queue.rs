@@ -0,0 +1,484 @@
+//! the transfer queue, fetches, pushes and backups wait here for a free slot
+//!
+//! transfers are kept in the db until they succeed, fail for good or are cancelled, so the queue
+//! survives a restart, what was running then starts over. a transfer that fails talking to a
+//! peer or to the disk is tried again after a backoff that doubles with every attempt. pausing a
+//! running transfer stops it, once it is resumed it starts over and picks up the partial files it
+//! left where it can
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use backit_core::ipc::{
+    to_server::{AnyHost, Command, Credentials},
+    Actor, ServerError, ServerReply, TransferId, TransferInfo, TransferState,
+};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, task::AbortHandle};
+
+use crate::{audit, hooks::Event, p2p, reply::Responder, throttle, Server, State};
+
+/// how long the queue sleeps when nothing wakes it up
+const IDLE: Duration = Duration::from_secs(60);
+
+/// priority of automatic backups, below what a user queues by default
+pub const AUTO_PRIORITY: i32 = -1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// transfers that run at the same time
+    pub max_transfers: usize,
+    /// transfers with the same host that run at the same time
+    pub max_per_host: usize,
+    /// how often a failed transfer is tried again before it is given up
+    pub retries: u32,
+    /// wait before the first retry, it doubles with every retry after it
+    #[serde(with = "humantime_serde")]
+    pub backoff: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+}
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            max_transfers: 4,
+            max_per_host: 2,
+            retries: 5,
+            backoff: Duration::from_secs(30),
+            max_backoff: Duration::from_secs(60 * 60),
+        }
+    }
+}
+impl QueueConfig {
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 1u32 << attempts.saturating_sub(1).min(16);
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// a transfer as it is stored in the db
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    command: Command,
+    priority: i32,
+    /// its traffic waits for interactive transfers, see [`throttle`]
+    background: bool,
+    added: SystemTime,
+    attempts: u32,
+    paused: bool,
+    retry_at: Option<SystemTime>,
+    error: Option<String>,
+}
+
+struct Running {
+    peer: Option<PeerId>,
+    task: AbortHandle,
+}
+
+/// the part of the queue that does not survive a restart
+#[derive(Default)]
+pub struct Queue {
+    running: HashMap<TransferId, Running>,
+    /// who waits for the result of a transfer, transfers queued before a restart have no one
+    waiting: HashMap<TransferId, Responder>,
+    wake: Arc<Notify>,
+}
+
+fn host(command: &Command) -> Option<&AnyHost> {
+    match command {
+        Command::Fetch { host, .. } | Command::Push { host, .. } => Some(host),
+        Command::Backup { host, .. } => host.as_ref(),
+        _ => None,
+    }
+}
+/// the host of a transfer as it was given
+fn host_of(command: &Command) -> Option<String> {
+    host(command).map(|x| match x {
+        AnyHost::HostId(x) => x.as_str().to_string(),
+        AnyHost::Credentials(Credentials::Key(x) | Credentials::Url(x)) => x.clone(),
+        AnyHost::Credentials(Credentials::Password { id, .. }) => id.clone(),
+    })
+}
+
+/// the peer the per host limit counts a transfer against, local backups are not counted and
+/// neither is a host that can not be told without connecting to it, it fails right away
+fn peer_of(state: &State, command: &Command) -> Option<PeerId> {
+    match host(command)? {
+        AnyHost::HostId(id) => match state.connected_clients.get(id) {
+            Some(x) => Some(*x),
+            None => id.as_str().parse().ok(),
+        },
+        AnyHost::Credentials(Credentials::Url(url)) => url
+            .parse()
+            .ok()
+            .and_then(|x| p2p::split_peer_address(x).ok())
+            .map(|(peer, _)| peer),
+        AnyHost::Credentials(Credentials::Key(_) | Credentials::Password { .. }) => None,
+    }
+}
+
+/// whether trying again later can make a difference
+fn retryable(e: &ServerError) -> bool {
+    matches!(
+        e,
+        ServerError::Peer(_) | ServerError::Io(_) | ServerError::DiskFull { .. }
+    )
+}
+
+fn db_err(e: impl ToString) -> ServerError {
+    ServerError::Io(e.to_string())
+}
+
+impl Server {
+    fn transfers(&self) -> Result<sled::Tree, ServerError> {
+        self.store.db().open_tree("transfers").map_err(db_err)
+    }
+    /// every queued transfer in the order they leave the queue
+    ///
+    /// a transfer that no longer reads, like one queued by an older build, is skipped so it does
+    /// not hold up the rest of the queue
+    fn entries(&self) -> Result<Vec<(TransferId, Entry)>, ServerError> {
+        let mut out = Vec::new();
+        for x in self.transfers()?.iter() {
+            let (key, bytes) = x.map_err(db_err)?;
+            let Ok(id) = <[u8; 8]>::try_from(key.as_ref()).map(TransferId::from_be_bytes) else {
+                eprintln!("skipping a queued transfer with a broken key");
+                continue;
+            };
+            match serde_cbor::from_slice(&bytes) {
+                Ok(x) => out.push((id, x)),
+                Err(e) => eprintln!("skipping queued transfer {id}, it does not read: {e}"),
+            }
+        }
+        out.sort_by_key(|(id, x): &(TransferId, Entry)| (Reverse(x.priority), *id));
+        Ok(out)
+    }
+    fn entry(&self, id: TransferId) -> Result<Entry, ServerError> {
+        let bytes = self
+            .transfers()?
+            .get(id.to_be_bytes())
+            .map_err(db_err)?
+            .ok_or(ServerError::UnknownTransfer(id))?;
+        serde_cbor::from_slice(&bytes).map_err(db_err)
+    }
+    fn put_entry(&self, id: TransferId, entry: &Entry) -> Result<(), ServerError> {
+        let bytes = serde_cbor::to_vec(entry).map_err(db_err)?;
+        self.transfers()?
+            .insert(id.to_be_bytes(), bytes)
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    /// add a fetch, push or backup to the queue, `reply` gets its result once it is done
+    ///
+    /// a background transfer that is already waiting in the queue is not added again
+    pub async fn enqueue(
+        &self,
+        command: Command,
+        priority: i32,
+        background: bool,
+        reply: Option<Responder>,
+    ) -> Result<TransferId, ServerError> {
+        // the queue is kept in the db and listed by `jobs`, a password must not end up in either
+        if let Some(AnyHost::Credentials(Credentials::Password { .. })) = host(&command) {
+            return Err(ServerError::PermissionDenied);
+        }
+        let mut state = self.state.lock().await;
+        if background {
+            let queued = self
+                .entries()?
+                .into_iter()
+                .find(|(id, x)| x.command == command && !state.queue.running.contains_key(id));
+            if let Some((id, _)) = queued {
+                return Ok(id);
+            }
+        }
+        let id = self.store.db().generate_id().map_err(db_err)?;
+        let entry = Entry {
+            command,
+            priority,
+            background,
+            added: SystemTime::now(),
+            attempts: 0,
+            paused: false,
+            retry_at: None,
+            error: None,
+        };
+        self.put_entry(id, &entry)?;
+        if let Some(reply) = reply {
+            state.queue.waiting.insert(id, reply);
+        }
+        state.queue.wake.notify_one();
+        Ok(id)
+    }
+
+    pub async fn jobs(&self) -> Result<Vec<TransferInfo>, ServerError> {
+        let state = self.state.lock().await;
+        let now = SystemTime::now();
+        let jobs = self.entries()?.into_iter().map(|(id, x)| {
+            let status = if state.queue.running.contains_key(&id) {
+                TransferState::Running
+            } else if x.paused {
+                TransferState::Paused
+            } else {
+                match x.retry_at {
+                    Some(at) if at > now => TransferState::Retrying(at),
+                    _ => TransferState::Queued,
+                }
+            };
+            TransferInfo {
+                id,
+                command: x.command,
+                priority: x.priority,
+                state: status,
+                added: x.added,
+                attempts: x.attempts,
+                error: x.error,
+            }
+        });
+        Ok(jobs.collect())
+    }
+
+    pub async fn cancel(&self, id: TransferId) -> Result<(), ServerError> {
+        let mut state = self.state.lock().await;
+        let removed = self.transfers()?.remove(id.to_be_bytes()).map_err(db_err)?;
+        if removed.is_none() {
+            return Err(ServerError::UnknownTransfer(id));
+        }
+        if let Some(running) = state.queue.running.remove(&id) {
+            running.task.abort();
+        }
+        if let Some(reply) = state.queue.waiting.remove(&id) {
+            reply.send(ServerReply::Error(ServerError::Cancelled));
+        }
+        state.queue.wake.notify_one();
+        Ok(())
+    }
+    pub async fn pause(&self, id: TransferId) -> Result<(), ServerError> {
+        let mut state = self.state.lock().await;
+        let mut entry = self.entry(id)?;
+        entry.paused = true;
+        self.put_entry(id, &entry)?;
+        if let Some(running) = state.queue.running.remove(&id) {
+            running.task.abort();
+        }
+        state.queue.wake.notify_one();
+        Ok(())
+    }
+    pub async fn resume(&self, id: TransferId) -> Result<(), ServerError> {
+        let state = self.state.lock().await;
+        let mut entry = self.entry(id)?;
+        entry.paused = false;
+        entry.retry_at = None;
+        self.put_entry(id, &entry)?;
+        state.queue.wake.notify_one();
+        Ok(())
+    }
+
+    /// start the transfers there is room for, returns when the next retry is due
+    async fn dispatch(&self) -> Result<Option<SystemTime>, ServerError> {
+        let mut state = self.state.lock().await;
+        let config = &self.config.queue;
+        let now = SystemTime::now();
+        let mut next = None;
+        for (id, entry) in self.entries()? {
+            if entry.paused || state.queue.running.contains_key(&id) {
+                continue;
+            }
+            if let Some(at) = entry.retry_at.filter(|x| *x > now) {
+                next = Some(next.map_or(at, |x: SystemTime| x.min(at)));
+                continue;
+            }
+            if state.queue.running.len() >= config.max_transfers {
+                break;
+            }
+            // a transfer to a busy host lets those to other hosts go first
+            let peer = peer_of(&state, &entry.command);
+            let busy = peer.is_some()
+                && state
+                    .queue
+                    .running
+                    .values()
+                    .filter(|x| x.peer == peer)
+                    .count()
+                    >= config.max_per_host;
+            if busy {
+                continue;
+            }
+            let reply = state
+                .queue
+                .waiting
+                .get(&id)
+                .cloned()
+                .unwrap_or_else(Responder::detached);
+            let server = self.clone();
+            let task = tokio::spawn(async move {
+                let run = server.run_transfer(&entry.command, &reply);
+                let result = match entry.background {
+                    true => throttle::background(run).await,
+                    false => run.await,
+                };
+                server.finish_transfer(id, result).await;
+            });
+            let running = Running {
+                peer,
+                task: task.abort_handle(),
+            };
+            state.queue.running.insert(id, running);
+        }
+        Ok(next)
+    }
+
+    async fn run_transfer(
+        &self,
+        command: &Command,
+        reply: &Responder,
+    ) -> Result<ServerReply, ServerError> {
+        let started = Instant::now();
+        let (kind, result) = match command {
+            Command::Fetch {
+                host,
+                target,
+                delta,
+                ..
+            } => {
+                let result = self.fetch(host, target, *delta, reply).await;
+                ("fetch", result.map(ServerReply::Fetched))
+            }
+            Command::Push {
+                host,
+                target,
+                delta,
+                ..
+            } => {
+                let result = self.push(host, target, *delta, reply).await;
+                ("push", result.map(ServerReply::Pushed))
+            }
+            Command::Backup {
+                job,
+                host,
+                target,
+                full,
+                ..
+            } => {
+                let result = self.backup(job, host.as_ref(), target, *full, reply).await;
+                self.client.metrics().backup(job, result.is_ok());
+                let (job, host) = (job.clone(), host_of(command));
+                self.hooks.emit(match &result {
+                    Ok(x) => Event::BackupSucceeded {
+                        job,
+                        snapshot: x.id.to_string(),
+                        host,
+                    },
+                    Err(e) => Event::BackupFailed {
+                        job,
+                        host,
+                        error: format!("{e:?}"),
+                    },
+                });
+                ("backup", result.map(ServerReply::Backuped))
+            }
+            _ => return Err(ServerError::UnsupportedCommand),
+        };
+        let metrics = self.client.metrics();
+        metrics.transfer(kind, result.is_ok(), started.elapsed());
+        result
+    }
+
+    /// take a transfer out of the queue once it is done, or put it back for a retry
+    async fn finish_transfer(&self, id: TransferId, result: Result<ServerReply, ServerError>) {
+        let mut state = self.state.lock().await;
+        // it was cancelled or paused in the meantime, and maybe started again
+        let ours = state
+            .queue
+            .running
+            .get(&id)
+            .is_some_and(|x| x.task.id() == tokio::task::id());
+        // a failure is left for the resume, but what is done stays done
+        if !ours && result.is_err() {
+            return;
+        }
+        if let Some(running) = state.queue.running.remove(&id) {
+            if !ours {
+                running.task.abort();
+            }
+        }
+        state.queue.wake.notify_one();
+        let mut entry = match self.entry(id) {
+            Ok(x) => x,
+            // cancelled, whoever waited for it was told already
+            Err(_) if !ours => return,
+            Err(e) => {
+                eprintln!("transfer {id} is gone from the queue: {e:?}");
+                return;
+            }
+        };
+        let result = match result {
+            Err(e) if retryable(&e) && entry.attempts < self.config.queue.retries => {
+                entry.attempts += 1;
+                entry.retry_at =
+                    Some(SystemTime::now() + self.config.queue.backoff(entry.attempts));
+                entry.error = Some(format!("{e:?}"));
+                match self.put_entry(id, &entry) {
+                    Ok(()) => return,
+                    Err(e) => Err(e),
+                }
+            }
+            x => x,
+        };
+        if let Err(e) = self
+            .transfers()
+            .and_then(|x| x.remove(id.to_be_bytes()).map_err(db_err))
+        {
+            eprintln!("could not take transfer {id} out of the queue: {e:?}");
+        }
+        match state.queue.waiting.remove(&id) {
+            Some(reply) => reply.send(match result {
+                Ok(x) => x,
+                Err(e) => ServerReply::Error(e),
+            }),
+            // automatic backups and what was queued before a restart
+            None => {
+                let reply = match result {
+                    Ok(x) => x,
+                    Err(e) => {
+                        eprintln!("transfer {id} failed: {e:?}");
+                        ServerReply::Error(e)
+                    }
+                };
+                if let Some((action, target)) = audit::describe(&entry.command) {
+                    let outcome = audit::outcome(&reply);
+                    self.audit.record(Actor::Daemon, action, target, outcome);
+                }
+            }
+        }
+    }
+
+    /// run queued transfers as slots free up, forever
+    pub async fn queue_loop(self) {
+        let wake = self.state.lock().await.queue.wake.clone();
+        loop {
+            let next = match self.dispatch().await {
+                Ok(x) => x,
+                Err(e) => {
+                    eprintln!("transfer queue failed: {e:?}");
+                    None
+                }
+            };
+            let wait = match next {
+                Some(x) => x.duration_since(SystemTime::now()).unwrap_or_default(),
+                None => IDLE,
+            };
+            tokio::select! {
+                _ = wake.notified() => {}
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    }
+}
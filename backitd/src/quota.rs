@@ -0,0 +1,83 @@
+//! accounting and limits for what peers store on us
+
+use std::path::Path;
+
+use backit_core::ipc::{HostStorage, ServerError, StorageUsage};
+use backit_core::tcp::{ReceivePacket, SendPacket};
+
+use crate::{config, Server};
+
+/// free bytes on the filesystem of `path`
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    let stat = nix::sys::statvfs::statvfs(path).ok()?;
+    Some(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+impl Server {
+    /// what a peer stores here
+    pub fn usage(&self, owner: &str) -> Result<StorageUsage, ServerError> {
+        Ok(StorageUsage {
+            used: self.store.usage(owner)?,
+            quota: self.config.quota.quota(owner),
+        })
+    }
+
+    /// refuse to take `bytes` more from a peer when that would eat into the disk reserve
+    ///
+    /// the quota of the peer is checked by the store when it charges the peer, so concurrent
+    /// requests can not get past it together
+    pub fn check_reserve(&self, bytes: u64) -> Result<(), ServerError> {
+        let reserve = self.config.quota.reserve.0;
+        if let Some(free) = free_space(&config::data_dir()) {
+            if free < reserve.saturating_add(bytes) {
+                return Err(ServerError::DiskFull { free, reserve });
+            }
+        }
+        Ok(())
+    }
+    /// charge a peer for `bytes` it keeps here outside of the chunk store, like a push
+    pub fn charge(&self, owner: &str, bytes: u64) -> Result<(), ServerError> {
+        self.check_reserve(bytes)?;
+        self.store
+            .charge(owner, bytes, self.config.quota.quota(owner))?;
+        self.charged(owner);
+        Ok(())
+    }
+    /// tell the hooks when what a peer stores got near its quota
+    pub fn charged(&self, owner: &str) {
+        if let Ok(StorageUsage {
+            used,
+            quota: Some(quota),
+        }) = self.usage(owner)
+        {
+            self.hooks.check_quota(owner, used, quota);
+        }
+    }
+
+    /// what every connected host stores here and what we store on it
+    pub async fn storage(&self) -> Vec<HostStorage> {
+        let hosts: Vec<_> = self
+            .state
+            .lock()
+            .await
+            .connected_clients
+            .iter()
+            .map(|(host, peer)| (host.clone(), *peer))
+            .collect();
+        let mut out = Vec::new();
+        for (host, peer) in hosts {
+            let here = self.usage(&peer.to_string()).unwrap_or_default();
+            let there = match self.client.clone().request(peer, SendPacket::Usage).await {
+                Ok(ReceivePacket::Usage(x)) => Some(x),
+                _ => None,
+            };
+            out.push(HostStorage { host, here, there });
+        }
+        out
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::{Arc, Mutex};
+
+use backit_core::ipc::{Actor, RequestId, ServerReply, Tagged};
+use tokio::sync::mpsc;
+
+use crate::audit::{self, AuditLog};
+
+/// sends replies for a single command back to the ipc connection it came from
+///
+/// every command gets its own responder so several commands can run at the same time on one
+/// connection, the replies are tagged with the [`RequestId`] of the command
+#[derive(Debug, Clone)]
+pub struct Responder {
+    id: RequestId,
+    tx: mpsc::UnboundedSender<Tagged<ServerReply>>,
+    /// taken once the final reply is sent, clones share it so the command is recorded once
+    audit: Option<Arc<Mutex<Option<Audited>>>>,
+}
+
+/// a command that goes in the audit log once it is done
+#[derive(Debug)]
+struct Audited {
+    log: AuditLog,
+    actor: Actor,
+    action: &'static str,
+    target: Option<String>,
+}
+
+impl Responder {
+    pub fn new(id: RequestId, tx: mpsc::UnboundedSender<Tagged<ServerReply>>) -> Self {
+        Self {
+            id,
+            tx,
+            audit: None,
+        }
+    }
+    /// a responder for work nobody waits on, every reply is dropped
+    pub fn detached() -> Self {
+        let (tx, _) = mpsc::unbounded_channel();
+        Self::new(0, tx)
+    }
+    /// record the command in the audit log with the result of its final reply
+    pub fn audited(
+        mut self,
+        log: AuditLog,
+        actor: Actor,
+        action: &'static str,
+        target: Option<String>,
+    ) -> Self {
+        let audited = Audited {
+            log,
+            actor,
+            action,
+            target,
+        };
+        self.audit = Some(Arc::new(Mutex::new(Some(audited))));
+        self
+    }
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+    /// queue a reply, this does nothing when the client already disconnected
+    pub fn send(&self, reply: ServerReply) {
+        if reply.is_final() {
+            let audited = self
+                .audit
+                .as_ref()
+                .and_then(|x| x.lock().expect("not poisoned").take());
+            if let Some(x) = audited {
+                x.log
+                    .record(x.actor, x.action, x.target, audit::outcome(&reply));
+            }
+        }
+        let _ = self.tx.send(Tagged::new(self.id, reply));
+    }
+    /// whether the connection is gone, it only notices once it failed to send a reply
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
@@ -0,0 +1,192 @@
+//! deciding which snapshots of a backup job to keep, and collecting the chunks of the others
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashSet},
+    time::{Duration, SystemTime},
+};
+
+use backit_core::{
+    ipc::{to_server::AnyHost, ServerError},
+    snapshot::{GcStats, PruneReport, SnapshotId, SnapshotInfo},
+};
+use chrono::{DateTime, Datelike, Local, Timelike};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+
+use crate::Server;
+
+/// the least time between two collections peers asked for, a collection reads the whole store
+const GC_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// the period of the day, week, month or year a snapshot was taken in
+type Bucket = fn(&DateTime<Local>) -> (i32, u32);
+
+/// collections of the store that peers asked for by forgetting snapshots
+///
+/// any peer can forget its snapshots, so they do not collect right away. the collection runs in
+/// the background at most once every [`GC_INTERVAL`] however many peers ask, and dry runs wait
+/// for each other so only one of them reads the store at a time
+#[derive(Default)]
+pub struct GcQueue {
+    wanted: Notify,
+    running: Mutex<()>,
+}
+
+/// retention rules of a backup job
+///
+/// a snapshot is kept when any rule keeps it, a policy without rules keeps everything. the
+/// calendar rules keep the newest snapshot of each of the last n hours, days, ... that have a
+/// snapshot at all
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    pub keep_last: Option<usize>,
+    pub keep_hourly: Option<usize>,
+    pub keep_daily: Option<usize>,
+    pub keep_weekly: Option<usize>,
+    pub keep_monthly: Option<usize>,
+    pub keep_yearly: Option<usize>,
+    /// keep every snapshot younger than this, like `"14d"`
+    #[serde(with = "humantime_serde")]
+    pub keep_within: Option<Duration>,
+}
+
+impl Retention {
+    pub fn is_empty(&self) -> bool {
+        self.keep_last.is_none()
+            && self.keep_hourly.is_none()
+            && self.keep_daily.is_none()
+            && self.keep_weekly.is_none()
+            && self.keep_monthly.is_none()
+            && self.keep_yearly.is_none()
+            && self.keep_within.is_none()
+    }
+
+    /// split snapshots into those to keep and those to remove
+    pub fn apply(
+        &self,
+        snapshots: Vec<SnapshotInfo>,
+        now: SystemTime,
+    ) -> (Vec<SnapshotInfo>, Vec<SnapshotInfo>) {
+        if self.is_empty() {
+            return (snapshots, Vec::new());
+        }
+        let mut newest_first = snapshots;
+        newest_first.sort_by_key(|x| Reverse(x.time));
+
+        let mut keep: HashSet<SnapshotId> = HashSet::new();
+        if let Some(n) = self.keep_last {
+            keep.extend(newest_first.iter().take(n).map(|x| x.id));
+        }
+        if let Some(within) = self.keep_within {
+            keep.extend(
+                newest_first
+                    .iter()
+                    .filter(|x| now.duration_since(x.time).unwrap_or_default() <= within)
+                    .map(|x| x.id),
+            );
+        }
+        let buckets: [(Option<usize>, Bucket); 5] = [
+            (self.keep_hourly, |x| {
+                (x.year(), x.ordinal() * 24 + x.hour())
+            }),
+            (self.keep_daily, |x| (x.year(), x.ordinal())),
+            (self.keep_weekly, |x| {
+                (x.iso_week().year(), x.iso_week().week())
+            }),
+            (self.keep_monthly, |x| (x.year(), x.month())),
+            (self.keep_yearly, |x| (x.year(), 0)),
+        ];
+        for (count, bucket) in buckets {
+            let Some(count) = count else { continue };
+            let mut seen = BTreeSet::new();
+            for snapshot in &newest_first {
+                if seen.len() >= count {
+                    break;
+                }
+                // the first snapshot we see in a bucket is the newest one of it
+                if seen.insert(bucket(&DateTime::<Local>::from(snapshot.time))) {
+                    keep.insert(snapshot.id);
+                }
+            }
+        }
+        newest_first.into_iter().partition(|x| keep.contains(&x.id))
+    }
+}
+
+impl Server {
+    /// apply the retention rules of a job, or of every job that has rules, to the local store or
+    /// the snapshots we stored on a host
+    pub async fn prune(
+        &self,
+        host: Option<&AnyHost>,
+        job: Option<&str>,
+        dry_run: bool,
+    ) -> Result<PruneReport, ServerError> {
+        let mut repo = self.repo(host).await?;
+        let mut report = PruneReport {
+            dry_run,
+            ..PruneReport::default()
+        };
+        let now = SystemTime::now();
+        for (name, config) in &self.config.jobs {
+            if job.is_some_and(|x| x != name) || config.retention.is_empty() {
+                continue;
+            }
+            let snapshots = repo.snapshots(Some(name.clone())).await?;
+            let (kept, removed) = config.retention.apply(snapshots, now);
+            report.kept.extend(kept);
+            report.removed.extend(removed);
+        }
+        let ids = report.removed.iter().map(|x| x.id).collect();
+        report.gc = repo
+            .forget(ids, dry_run, self.config.store.gc_grace)
+            .await?;
+        Ok(report)
+    }
+
+    /// forget snapshots a peer stored with us, the chunks are collected later by the gc loop
+    pub async fn peer_forget(
+        &self,
+        owner: &str,
+        ids: &[SnapshotId],
+        dry_run: bool,
+    ) -> Result<GcStats, ServerError> {
+        if dry_run {
+            let _running = self.gc.running.lock().await;
+            let store = self.store.clone();
+            let grace = self.config.store.gc_grace;
+            return tokio::task::spawn_blocking(move || store.gc(grace, true))
+                .await
+                .map_err(|e| ServerError::Io(e.to_string()))?
+                .map_err(ServerError::from);
+        }
+        for id in ids {
+            self.store.delete_snapshot(owner, id)?;
+        }
+        self.gc.wanted.notify_one();
+        Ok(GcStats {
+            deferred: true,
+            ..GcStats::default()
+        })
+    }
+
+    /// collect the store whenever a peer forgot snapshots, forever
+    pub async fn gc_loop(self) {
+        loop {
+            self.gc.wanted.notified().await;
+            let running = self.gc.running.lock().await;
+            let store = self.store.clone();
+            let grace = self.config.store.gc_grace;
+            match tokio::task::spawn_blocking(move || store.gc(grace, false)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => eprintln!("garbage collection failed: {e}"),
+                Err(e) => eprintln!("garbage collection failed: {e}"),
+            }
+            drop(running);
+            // peers that forget snapshots in the meantime share the next collection
+            tokio::time::sleep(GC_INTERVAL).await;
+        }
+    }
+}
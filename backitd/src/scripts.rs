@@ -0,0 +1,234 @@
+//! commands that run before and after the backup of a job, to dump a database or freeze a
+//! filesystem so the snapshot is consistent
+//!
+//! the post command runs after every backup of the job, also one that failed, was cancelled or
+//! that the pre command aborted, so whatever the pre command set up is undone. what the commands
+//! print ends up in the audit log next to how they went, also when they time out
+//!
+//! a command runs in a process group of its own, one that times out or whose backup is cancelled
+//! is killed with everything it started
+
+use std::{
+    path::PathBuf,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use backit_core::ipc::{Actor, Outcome, ServerError};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Child,
+    task::JoinHandle,
+};
+
+use crate::Server;
+
+/// the most of the output of a command that is kept, the end of it when there is more
+const MAX_OUTPUT: usize = 64 * 1024;
+
+/// how long the output of a command is still read once it exited, a process it started in the
+/// background may keep the pipes open
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFailure {
+    /// fail the backup
+    #[default]
+    Abort,
+    /// go on with the backup and only warn
+    Continue,
+}
+
+/// a command run around the backup of a job, with the job in `BACKIT_*` environment variables
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobScript {
+    pub program: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// how long the command may run before it is killed and counts as failed
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    #[serde(default)]
+    pub on_failure: OnFailure,
+}
+fn default_timeout() -> Duration {
+    Duration::from_secs(30 * 60)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Pre,
+    Post,
+}
+impl Phase {
+    /// the action of the command in the audit log
+    fn action(self) -> &'static str {
+        match self {
+            Self::Pre => "pre-backup",
+            Self::Post => "post-backup",
+        }
+    }
+}
+
+/// what a command printed, stdout before stderr
+fn output(stdout: &[u8], stderr: &[u8]) -> String {
+    let mut out = String::from_utf8_lossy(stdout).into_owned();
+    out.push_str(&String::from_utf8_lossy(stderr));
+    if out.len() > MAX_OUTPUT {
+        let mut start = out.len() - MAX_OUTPUT;
+        while !out.is_char_boundary(start) {
+            start += 1;
+        }
+        out.drain(..start);
+    }
+    out
+}
+
+/// read a pipe of a command as it comes, so what it printed is there when it is killed
+fn capture(
+    pipe: Option<impl AsyncRead + Unpin + Send + 'static>,
+) -> (Arc<Mutex<Vec<u8>>>, JoinHandle<()>) {
+    let out = Arc::new(Mutex::new(Vec::new()));
+    let task = tokio::spawn({
+        let out = out.clone();
+        async move {
+            let Some(mut pipe) = pipe else {
+                return;
+            };
+            let mut buf = [0; 8192];
+            while let Ok(n @ 1..) = pipe.read(&mut buf).await {
+                let mut out = out.lock().expect("not poisoned");
+                out.extend_from_slice(&buf[..n]);
+                // only the end is kept
+                if out.len() > 2 * MAX_OUTPUT {
+                    let start = out.len() - MAX_OUTPUT;
+                    out.drain(..start);
+                }
+            }
+        }
+    });
+    (out, task)
+}
+
+/// a running command, it is killed with its process group when it is dropped
+struct Running(Child);
+impl Running {
+    #[cfg(unix)]
+    fn kill(&mut self) {
+        use nix::{
+            sys::signal::{killpg, Signal},
+            unistd::Pid,
+        };
+
+        if let Some(pid) = self.0.id() {
+            let _ = killpg(Pid::from_raw(pid as i32), Signal::SIGKILL);
+        }
+        let _ = self.0.start_kill();
+    }
+    #[cfg(not(unix))]
+    fn kill(&mut self) {
+        let _ = self.0.start_kill();
+    }
+}
+impl Drop for Running {
+    fn drop(&mut self) {
+        // nothing is killed once the command exited and was reaped, what it left running in
+        // the background stays
+        self.kill();
+    }
+}
+
+/// run the command to its end, returns what it printed and why it failed
+async fn run(script: &JobScript, env: &[(&str, String)]) -> (String, Result<(), String>) {
+    let mut command = tokio::process::Command::new(&script.program);
+    command
+        .args(&script.args)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    command.process_group(0);
+    let mut child = match command.spawn() {
+        Ok(x) => Running(x),
+        Err(e) => {
+            let e = format!("could not run {}: {e}", script.program.display());
+            return (String::new(), Err(e));
+        }
+    };
+    let (stdout, mut stdout_task) = capture(child.0.stdout.take());
+    let (stderr, mut stderr_task) = capture(child.0.stderr.take());
+    let status = tokio::time::timeout(script.timeout, child.0.wait()).await;
+    if status.is_err() {
+        child.kill();
+        let _ = child.0.wait().await;
+    }
+    // the pipes close once every process that has them is gone
+    let read = async {
+        let _ = (&mut stdout_task).await;
+        let _ = (&mut stderr_task).await;
+    };
+    let _ = tokio::time::timeout(OUTPUT_GRACE, read).await;
+    stdout_task.abort();
+    stderr_task.abort();
+    let printed = output(
+        &stdout.lock().expect("not poisoned"),
+        &stderr.lock().expect("not poisoned"),
+    );
+    let program = script.program.display();
+    let result = match status {
+        Ok(Ok(x)) if x.success() => Ok(()),
+        Ok(Ok(x)) => Err(format!("{program} exited with {x}")),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!(
+            "{program} timed out after {}",
+            humantime::format_duration(script.timeout)
+        )),
+    };
+    (printed, result)
+}
+
+impl Server {
+    /// run the pre or post command of a job and log it, fails only when the command failed and
+    /// should abort the backup
+    pub async fn run_script(
+        &self,
+        job: &str,
+        phase: Phase,
+        script: &JobScript,
+        env: &[(&str, String)],
+    ) -> Result<(), ServerError> {
+        let (printed, result) = run(script, env).await;
+        let outcome = match &result {
+            Ok(()) => Outcome::Success,
+            Err(e) => Outcome::Failure(e.clone()),
+        };
+        let printed = (!printed.is_empty()).then_some(printed);
+        self.audit.record_output(
+            Actor::Daemon,
+            phase.action(),
+            Some(job.to_string()),
+            outcome,
+            printed,
+        );
+        let Err(error) = result else {
+            return Ok(());
+        };
+        match script.on_failure {
+            OnFailure::Abort => Err(ServerError::ScriptFailed {
+                job: job.to_string(),
+                error,
+            }),
+            OnFailure::Continue => {
+                eprintln!(
+                    "{} command of {job} failed, going on: {error}",
+                    phase.action()
+                );
+                Ok(())
+            }
+        }
+    }
+}
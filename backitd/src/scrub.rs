@@ -0,0 +1,80 @@
+//! checking stored backups for missing and corrupt chunks, on request and in the background
+
+use std::time::{Duration, SystemTime};
+
+use backit_core::{
+    ipc::{to_server::AnyHost, ServerError},
+    snapshot::VerifyReport,
+};
+
+use crate::{
+    audit, hooks::Event, progress::ProgressTracker, reply::Responder, store::Store, Server,
+};
+
+/// check the whole store, this blocks
+fn scrub(store: &Store) -> Result<VerifyReport, ServerError> {
+    let report = store.verify(None, true, |_| {})?;
+    store.put_last_scrub(&report)?;
+    Ok(report)
+}
+
+fn verify_failed(host: Option<String>, report: &VerifyReport) -> Event {
+    Event::VerifyFailed {
+        host,
+        missing: report.missing.len(),
+        corrupt: report.corrupt.len(),
+        damaged: report.damaged.len(),
+    }
+}
+
+impl Server {
+    pub async fn verify(
+        &self,
+        host: Option<&AnyHost>,
+        full: bool,
+        reply: &Responder,
+    ) -> Result<VerifyReport, ServerError> {
+        let mut repo = self.repo(host).await?;
+        let report = repo
+            .verify(full, ProgressTracker::new(reply.clone()))
+            .await?;
+        if !report.is_ok() {
+            self.hooks
+                .emit(verify_failed(host.map(audit::host), &report));
+        }
+        Ok(report)
+    }
+
+    /// run a full check of the store every `scrub_interval`, counted from the last scrub so a
+    /// restart does not push it back
+    pub async fn scrub_loop(self) {
+        let Some(interval) = self.config.store.scrub_interval else {
+            return;
+        };
+        loop {
+            let last = self.store.last_scrub().ok().flatten().map(|x| x.time);
+            let wait = last
+                .and_then(|x| (x + interval).duration_since(SystemTime::now()).ok())
+                .unwrap_or(Duration::ZERO);
+            tokio::time::sleep(wait).await;
+
+            let store = self.store.clone();
+            match tokio::task::spawn_blocking(move || scrub(&store)).await {
+                Ok(Ok(report)) if report.is_ok() => {}
+                Ok(Ok(report)) => {
+                    eprintln!(
+                        "scrub found {} missing and {} corrupt chunks, {} snapshots are damaged",
+                        report.missing.len(),
+                        report.corrupt.len(),
+                        report.damaged.len()
+                    );
+                    self.hooks.emit(verify_failed(None, &report));
+                }
+                Ok(Err(e)) => eprintln!("scrub failed: {e:?}"),
+                Err(e) => eprintln!("scrub failed: {e}"),
+            }
+            // do not spin when the result could not be recorded
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }
+    }
+}
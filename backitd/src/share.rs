@@ -0,0 +1,266 @@
+//! who may see and fetch hosted files, and fetching them from other hosts
+
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use backit_core::{
+    ipc::{
+        to_server::{AnyHost, Grantee, HostId, Right, Share, Target},
+        FileInfo, ServerError,
+    },
+    snapshot::EntryKind,
+    tcp::{ReceivePacket, SendPacket},
+};
+use libp2p::PeerId;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::{
+    backup::{check_inside, restore_path, symlink},
+    config,
+    delta::signature_of,
+    meta,
+    progress::ProgressTracker,
+    reply::Responder,
+    Server,
+};
+
+/// how much of a file is sent in a single response
+pub const FILE_PIECE: u64 = 1024 * 1024;
+
+/// the order the files of a transfer are written in, hardlinks after the file they link to and
+/// symlinks last so nothing is written through one of them
+pub fn link_order(kind: &EntryKind) -> u8 {
+    match kind {
+        EntryKind::File => 0,
+        EntryKind::Hardlink(_) => 1,
+        EntryKind::Symlink(_) => 2,
+    }
+}
+
+/// make a symlink or further name of a transferred file at `dest`, the first name of a hardlink
+/// was written below `dir` as well
+pub async fn transfer_link(file: &FileInfo, dir: &Path, dest: &Path) -> Result<(), ServerError> {
+    let io_err = |e: std::io::Error| ServerError::Io(format!("{}: {e}", dest.display()));
+    if let Some(parent) = dest.parent() {
+        tokio::fs::create_dir_all(parent).await.map_err(io_err)?;
+    }
+    match tokio::fs::remove_file(dest).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(io_err(e)),
+    }
+    match &file.kind {
+        EntryKind::Symlink(target) => symlink(target, dest).await.map_err(io_err)?,
+        EntryKind::Hardlink(first) => {
+            let first = restore_path(first, Some(dir))?;
+            check_inside(dir, &first)?;
+            tokio::fs::hard_link(&first, dest).await.map_err(io_err)?;
+        }
+        EntryKind::File => return Err(ServerError::InvalidPacket),
+    }
+    meta::apply(dest, &file.meta, file.mtime, false).map_err(io_err)
+}
+
+impl Server {
+    /// turn the hosts in shares into peer ids, a share can only be given to a known host or group
+    pub async fn resolve_shares(&self, shares: &[Share]) -> Result<Vec<Share>, ServerError> {
+        let state = self.state.lock().await;
+        let mut out = Vec::new();
+        for share in shares {
+            let grantee = match &share.grantee {
+                Grantee::Host(id) => {
+                    let peer = match state.connected_clients.get(id) {
+                        Some(x) => *x,
+                        None => id
+                            .as_str()
+                            .parse::<PeerId>()
+                            .map_err(|_| ServerError::UnknownHost(id.as_str().to_string()))?,
+                    };
+                    Grantee::Host(HostId::new_id(peer.to_string()))
+                }
+                Grantee::Group(x) if self.config.groups.contains_key(x) => share.grantee.clone(),
+                Grantee::Group(x) => return Err(ServerError::UnknownHost(format!("@{x}"))),
+            };
+            out.push(Share {
+                grantee,
+                rights: share.rights.clone(),
+            });
+        }
+        Ok(out)
+    }
+
+    /// the hosted files a peer has a right on
+    pub async fn allowed_files(
+        &self,
+        peer: &PeerId,
+        right: Right,
+        target: Option<&Target>,
+    ) -> Vec<FileInfo> {
+        let peer = peer.to_string();
+        let mut files: Vec<FileInfo> = self
+            .state
+            .lock()
+            .await
+            .catalog
+            .allowed(&peer, &self.config.groups, right)
+            .filter(|x| target.is_none_or(|target| x.matches(target)))
+            .map(|x| x.public_info())
+            .collect();
+        meta::for_transfer(&mut files);
+        files
+    }
+
+    /// read part of a hosted file for a peer, a file that is not hosted looks the same as one
+    /// the peer may not read
+    pub async fn read_file(
+        &self,
+        peer: &PeerId,
+        path: &Path,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, ServerError> {
+        let allowed = self
+            .state
+            .lock()
+            .await
+            .catalog
+            .get(path)
+            .is_some_and(|x| x.allows(&peer.to_string(), &self.config.groups, Right::Read));
+        if !allowed {
+            return Err(ServerError::PermissionDenied);
+        }
+        let io_err = |e: std::io::Error| ServerError::Io(format!("{}: {e}", path.display()));
+        let mut file = tokio::fs::File::from_std(meta::open(path).map_err(io_err)?);
+        file.seek(SeekFrom::Start(offset)).await.map_err(io_err)?;
+        let mut data = Vec::new();
+        file.take(len.min(FILE_PIECE))
+            .read_to_end(&mut data)
+            .await
+            .map_err(io_err)?;
+        Ok(data)
+    }
+
+    /// fetch the files of a host that match the target and that we may read, they are written
+    /// below `fetched/<peer id>` in the data directory
+    ///
+    /// with `delta` a file that was fetched before is sent as the differences to that copy
+    pub async fn fetch(
+        &self,
+        host: &AnyHost,
+        target: &Target,
+        delta: bool,
+        reply: &Responder,
+    ) -> Result<Vec<PathBuf>, ServerError> {
+        let peer = self.resolve_host(host).await?;
+        let mut files = match self
+            .request(host, SendPacket::FetchList(target.clone()))
+            .await?
+        {
+            ReceivePacket::FileList(x) => x,
+            _ => return Err(ServerError::InvalidPacket),
+        };
+        files.sort_by_key(|x| link_order(&x.kind));
+        let dir = config::data_dir().join("fetched").join(peer.to_string());
+        let mut progress = ProgressTracker::new(reply.clone());
+        progress.set_totals(
+            Some(files.iter().map(|x| x.size).sum()),
+            Some(files.len() as u64),
+        );
+        let mut out = Vec::new();
+        for file in files {
+            progress.start_file(file.path.display().to_string());
+            let dest = restore_path(&file.path, Some(&dir))?;
+            check_inside(&dir, &dest)?;
+            match file.kind {
+                EntryKind::File => {
+                    self.fetch_file(host, &file, &dest, delta, &mut progress)
+                        .await?
+                }
+                _ => transfer_link(&file, &dir, &dest).await?,
+            }
+            progress.finish_file();
+            out.push(dest);
+        }
+        progress.finish();
+        Ok(out)
+    }
+    async fn fetch_file(
+        &self,
+        host: &AnyHost,
+        file: &FileInfo,
+        dest: &Path,
+        delta: bool,
+        progress: &mut ProgressTracker,
+    ) -> Result<(), ServerError> {
+        let io_err = |e: std::io::Error| ServerError::Io(format!("{}: {e}", dest.display()));
+        if let Some(dir) = dest.parent() {
+            tokio::fs::create_dir_all(dir).await.map_err(io_err)?;
+        }
+        let name = dest.file_name().unwrap_or_default().to_string_lossy();
+        let partial = dest.with_file_name(format!(".{name}.backit-fetch"));
+        // the host names the files, so this may be one of them and must not be written through
+        match tokio::fs::remove_file(&partial).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(io_err(e)),
+        }
+        let signature = match delta {
+            true => {
+                let base = dest.to_path_buf();
+                tokio::task::spawn_blocking(move || signature_of(&base))
+                    .await
+                    .map_err(|e| ServerError::Io(e.to_string()))?
+                    .map_err(io_err)?
+            }
+            false => None,
+        };
+        match signature {
+            Some(signature) => {
+                self.fetch_delta(host, file, dest, &partial, signature, progress)
+                    .await?
+            }
+            None => self.fetch_data(host, file, &partial, progress).await?,
+        }
+        meta::apply(&partial, &file.meta, file.mtime, false).map_err(io_err)?;
+        tokio::fs::rename(&partial, dest).await.map_err(io_err)?;
+        Ok(())
+    }
+    /// fetch the whole content of a file into `dest`
+    async fn fetch_data(
+        &self,
+        host: &AnyHost,
+        file: &FileInfo,
+        dest: &Path,
+        progress: &mut ProgressTracker,
+    ) -> Result<(), ServerError> {
+        let io_err = |e: std::io::Error| ServerError::Io(format!("{}: {e}", dest.display()));
+        let mut out = tokio::fs::File::create(dest).await.map_err(io_err)?;
+        let mut offset = 0;
+        // only what was listed is fetched, a host sending more could fill up the disk
+        while offset < file.size {
+            let len = FILE_PIECE.min(file.size - offset);
+            let request = SendPacket::ReadFile {
+                path: file.path.clone(),
+                offset,
+                len,
+            };
+            let data = match self.request(host, request).await? {
+                ReceivePacket::FileData(x) => x,
+                _ => return Err(ServerError::InvalidPacket),
+            };
+            if data.is_empty() {
+                break;
+            }
+            if data.len() as u64 > len {
+                return Err(ServerError::InvalidPacket);
+            }
+            out.write_all(&data).await.map_err(io_err)?;
+            offset += data.len() as u64;
+            progress.add_bytes(data.len() as u64);
+        }
+        out.flush().await.map_err(io_err)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,508 @@
+//! the chunk store backups end up in, both our own and those peers push to us
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use backit_core::snapshot::{ChunkHash, GcStats, Snapshot, SnapshotId, SnapshotInfo, VerifyReport};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError, TransactionalTree},
+    Transactional,
+};
+
+/// owner of the snapshots made by this daemon, snapshots pushed by a peer are owned by its peer id
+pub const LOCAL_OWNER: &str = "local";
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("database error: {0}")]
+    Db(#[from] sled::Error),
+    #[error("corrupt snapshot in the database: {0}")]
+    Corrupt(#[from] serde_cbor::Error),
+    #[error("chunk does not match its hash {0}")]
+    HashMismatch(ChunkHash),
+    #[error("part of manifest {0} does not follow the parts before it")]
+    ManifestPart(SnapshotId),
+    #[error("quota of {quota} bytes exceeded, {used} bytes are used")]
+    QuotaExceeded { used: u64, quota: u64 },
+}
+impl From<TransactionError<StoreError>> for StoreError {
+    fn from(value: TransactionError<StoreError>) -> Self {
+        match value {
+            TransactionError::Abort(x) => x,
+            TransactionError::Storage(x) => Self::Db(x),
+        }
+    }
+}
+
+const SNAPSHOT_PREFIX: &str = "snapshots/";
+/// the largest manifest a peer may send us
+pub const MAX_MANIFEST: u64 = 1024 * 1024 * 1024;
+/// key of the last scrub result in the default tree
+const LAST_SCRUB: &[u8] = b"last_scrub";
+
+type TxResult<T> = Result<T, ConflictableTransactionError<StoreError>>;
+
+/// a big endian timestamp or byte count of the db, 0 when there is none
+fn be_u64(value: Option<sled::IVec>) -> u64 {
+    value
+        .and_then(|x| <[u8; 8]>::try_from(x.as_ref()).ok())
+        .map_or(0, u64::from_be_bytes)
+}
+
+/// add to the bytes charged to an owner, inside a transaction over the usage tree
+///
+/// a charge that would take the owner over `quota` aborts the transaction
+fn charge(usage: &TransactionalTree, owner: &str, bytes: i64, quota: Option<u64>) -> TxResult<u64> {
+    let used = be_u64(usage.get(owner)?);
+    let new = used.saturating_add_signed(bytes);
+    if let Some(quota) = quota.filter(|quota| bytes > 0 && new > *quota) {
+        return Err(ConflictableTransactionError::Abort(
+            StoreError::QuotaExceeded { used, quota },
+        ));
+    }
+    usage.insert(owner, &new.to_be_bytes()[..])?;
+    Ok(new)
+}
+fn abort(e: impl Into<StoreError>) -> ConflictableTransactionError<StoreError> {
+    ConflictableTransactionError::Abort(e.into())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// content addressed chunks and snapshot manifests, kept in a sled database
+///
+/// chunks are shared between every owner so data that is backed up twice is only stored once
+#[derive(Clone)]
+pub struct Store {
+    db: sled::Db,
+    chunks: sled::Tree,
+    /// when a backup last stored or reused a chunk, see [`Store::gc`]
+    touched: sled::Tree,
+    /// the owner that first stored a chunk, it is charged for the chunk until it is collected
+    chunk_owners: sled::Tree,
+    /// bytes charged to every owner
+    usage: sled::Tree,
+    /// bytes the manifest of every snapshot is charged with, by `<owner>/<snapshot id>`
+    manifest_sizes: sled::Tree,
+}
+impl Store {
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        let db = sled::open(path)?;
+        let chunks = db.open_tree("chunks")?;
+        let touched = db.open_tree("chunks_touched")?;
+        let chunk_owners = db.open_tree("chunk_owners")?;
+        let usage = db.open_tree("usage")?;
+        let manifest_sizes = db.open_tree("manifest_sizes")?;
+        Ok(Self {
+            db,
+            chunks,
+            touched,
+            chunk_owners,
+            usage,
+            manifest_sizes,
+        })
+    }
+    pub fn db(&self) -> &sled::Db {
+        &self.db
+    }
+
+    fn snapshots(&self, owner: &str) -> Result<sled::Tree, StoreError> {
+        Ok(self.db.open_tree(format!("{SNAPSHOT_PREFIX}{owner}"))?)
+    }
+    /// everyone that has snapshots in this store
+    pub fn owners(&self) -> Vec<String> {
+        self.db
+            .tree_names()
+            .iter()
+            .filter_map(|x| std::str::from_utf8(x).ok()?.strip_prefix(SNAPSHOT_PREFIX))
+            .map(str::to_string)
+            .collect()
+    }
+    pub fn has_chunk(&self, hash: &ChunkHash) -> Result<bool, StoreError> {
+        Ok(self.chunks.contains_key(hash.as_bytes())?)
+    }
+    /// the hashes of this list we do not have yet
+    ///
+    /// the chunks we do have are about to be referenced by a new snapshot, so they are marked as
+    /// in use
+    pub fn missing_chunks(&self, hashes: &[ChunkHash]) -> Result<Vec<ChunkHash>, StoreError> {
+        let now = unix_now().to_be_bytes();
+        let mut out = Vec::new();
+        for hash in hashes {
+            // looked at and touched at once, so a gc can not remove the chunk in between
+            let have = (&self.chunks, &self.touched).transaction(
+                |(chunks, touched)| -> TxResult<bool> {
+                    let have = chunks.get(hash.as_bytes())?.is_some();
+                    if have {
+                        touched.insert(hash.as_bytes(), &now[..])?;
+                    }
+                    Ok(have)
+                },
+            )?;
+            if !have {
+                out.push(*hash);
+            }
+        }
+        Ok(out)
+    }
+    /// store a chunk, the data is checked against the hash so a peer can not poison the store
+    ///
+    /// a chunk that is new is charged to `owner`, it is refused when that takes the owner over
+    /// `quota`
+    pub fn put_chunk(
+        &self,
+        owner: &str,
+        hash: &ChunkHash,
+        data: &[u8],
+        quota: Option<u64>,
+    ) -> Result<(), StoreError> {
+        if ChunkHash::of(data) != *hash {
+            return Err(StoreError::HashMismatch(*hash));
+        }
+        let now = unix_now().to_be_bytes();
+        let trees = (&self.chunks, &self.touched, &self.chunk_owners, &self.usage);
+        trees.transaction(|(chunks, touched, owners, usage)| -> TxResult<()> {
+            if chunks.insert(hash.as_bytes(), data)?.is_none() {
+                owners.insert(hash.as_bytes(), owner.as_bytes())?;
+                charge(usage, owner, data.len() as i64, quota)?;
+            }
+            touched.insert(hash.as_bytes(), &now[..])?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+    /// bytes of the chunks, manifests and pushes an owner stored that were not removed yet
+    pub fn usage(&self, owner: &str) -> Result<u64, StoreError> {
+        Ok(be_u64(self.usage.get(owner)?))
+    }
+    /// charge an owner for bytes it keeps outside of the store, fails without charging anything
+    /// when that takes it over `quota`
+    pub fn charge(&self, owner: &str, bytes: u64, quota: Option<u64>) -> Result<u64, StoreError> {
+        Ok(self
+            .usage
+            .transaction(|usage| charge(usage, owner, bytes as i64, quota))?)
+    }
+    /// give back what [`Store::charge`] charged
+    pub fn release(&self, owner: &str, bytes: u64) -> Result<(), StoreError> {
+        self.usage
+            .transaction(|usage| charge(usage, owner, -(bytes as i64), None))?;
+        Ok(())
+    }
+    pub fn get_chunk(&self, hash: &ChunkHash) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self.chunks.get(hash.as_bytes())?.map(|x| x.to_vec()))
+    }
+
+    /// store a snapshot, snapshots are immutable so storing the same id twice overwrites nothing
+    ///
+    /// the only exception are the copies of a snapshot a peer of a set keeps, pieces that moved
+    /// to this store are added to them and the placements are replaced by the newer ones. the
+    /// stored manifest is charged to `owner`, up to `quota`
+    pub fn put_snapshot(
+        &self,
+        owner: &str,
+        snapshot: &Snapshot,
+        quota: Option<u64>,
+    ) -> Result<(), StoreError> {
+        let tree = self.snapshots(owner)?;
+        let key = snapshot.id.as_bytes();
+        let size_key = format!("{owner}/{}", snapshot.id);
+        let trees = (&tree, &self.usage, &self.manifest_sizes);
+        trees.transaction(|(tree, usage, sizes)| -> TxResult<()> {
+            let new = match tree.get(key)? {
+                None => snapshot.clone(),
+                Some(bytes) => {
+                    let mut existing: Snapshot = serde_cbor::from_slice(&bytes).map_err(abort)?;
+                    let (Some(have), Some(add)) = (&mut existing.pieces, &snapshot.pieces) else {
+                        return Ok(());
+                    };
+                    let new: Vec<ChunkHash> =
+                        add.iter().filter(|x| !have.contains(x)).copied().collect();
+                    let placements = snapshot.placements.clone().or(existing.placements.clone());
+                    if new.is_empty() && placements == existing.placements {
+                        return Ok(());
+                    }
+                    have.extend(new);
+                    existing.placements = placements;
+                    existing
+                }
+            };
+            let bytes = serde_cbor::to_vec(&new).map_err(abort)?;
+            let old = be_u64(sizes.get(size_key.as_bytes())?);
+            charge(usage, owner, bytes.len() as i64 - old as i64, quota)?;
+            sizes.insert(size_key.as_bytes(), &(bytes.len() as u64).to_be_bytes()[..])?;
+            tree.insert(key, bytes)?;
+            Ok(())
+        })?;
+        self.db.flush()?;
+        Ok(())
+    }
+    /// add part of a manifest a peer sends in parts, returns the snapshot once all of it is in
+    ///
+    /// parts have to come in order, a part at offset 0 starts the manifest over. the parts are
+    /// charged to `owner` up to `quota` until the manifest is complete
+    pub fn put_snapshot_part(
+        &self,
+        owner: &str,
+        id: &SnapshotId,
+        offset: u64,
+        total: u64,
+        data: &[u8],
+        quota: Option<u64>,
+    ) -> Result<Option<Snapshot>, StoreError> {
+        let uploads = self.db.open_tree("snapshot_uploads")?;
+        let key = format!("{owner}/{id}");
+        let trees = (&uploads, &self.usage);
+        let manifest = trees.transaction(
+            |(uploads, usage)| -> TxResult<Result<Option<Vec<u8>>, StoreError>> {
+                let old = uploads.get(key.as_bytes())?;
+                let held = old.as_ref().map_or(0, |x| x.len() as i64);
+                let mut manifest = match offset {
+                    0 => Vec::new(),
+                    _ => old.map(|x| x.to_vec()).unwrap_or_default(),
+                };
+                let end = offset.saturating_add(data.len() as u64);
+                if manifest.len() as u64 != offset || end > total.min(MAX_MANIFEST) {
+                    // the upload is dropped, not only this part, so this does not abort
+                    uploads.remove(key.as_bytes())?;
+                    charge(usage, owner, -held, None)?;
+                    return Ok(Err(StoreError::ManifestPart(*id)));
+                }
+                manifest.extend_from_slice(data);
+                let len = manifest.len() as i64;
+                charge(usage, owner, len - held, quota)?;
+                if (manifest.len() as u64) < total {
+                    uploads.insert(key.as_bytes(), manifest)?;
+                    return Ok(Ok(None));
+                }
+                uploads.remove(key.as_bytes())?;
+                charge(usage, owner, -len, None)?;
+                Ok(Ok(Some(manifest)))
+            },
+        )??;
+        let Some(manifest) = manifest else {
+            return Ok(None);
+        };
+        let snapshot: Snapshot = serde_cbor::from_slice(&manifest)?;
+        if snapshot.id != *id {
+            return Err(StoreError::ManifestPart(*id));
+        }
+        Ok(Some(snapshot))
+    }
+    /// the manifest of a snapshot as it is stored, to send it in parts
+    pub fn snapshot_bytes(
+        &self,
+        owner: &str,
+        id: &SnapshotId,
+    ) -> Result<Option<sled::IVec>, StoreError> {
+        Ok(self.snapshots(owner)?.get(id.as_bytes())?)
+    }
+    pub fn get_snapshot(
+        &self,
+        owner: &str,
+        id: &SnapshotId,
+    ) -> Result<Option<Snapshot>, StoreError> {
+        match self.snapshots(owner)?.get(id.as_bytes())? {
+            Some(x) => Ok(Some(serde_cbor::from_slice(&x)?)),
+            None => Ok(None),
+        }
+    }
+    /// every snapshot of an owner, oldest first
+    pub fn list_snapshots(
+        &self,
+        owner: &str,
+        job: Option<&str>,
+    ) -> Result<Vec<Snapshot>, StoreError> {
+        let mut out = Vec::new();
+        for x in self.snapshots(owner)?.iter() {
+            let (_, bytes) = x?;
+            let snapshot: Snapshot = serde_cbor::from_slice(&bytes)?;
+            if job.is_none_or(|job| snapshot.job == job) {
+                out.push(snapshot);
+            }
+        }
+        out.sort_by_key(|x| x.time);
+        Ok(out)
+    }
+    /// remove a snapshot, its chunks stay until the next [`Store::gc`]
+    pub fn delete_snapshot(&self, owner: &str, id: &SnapshotId) -> Result<bool, StoreError> {
+        let tree = self.snapshots(owner)?;
+        let size_key = format!("{owner}/{id}");
+        let trees = (&tree, &self.usage, &self.manifest_sizes);
+        let removed = trees.transaction(|(tree, usage, sizes)| -> TxResult<bool> {
+            let removed = tree.remove(id.as_bytes())?.is_some();
+            let size = be_u64(sizes.remove(size_key.as_bytes())?);
+            charge(usage, owner, -(size as i64), None)?;
+            Ok(removed)
+        })?;
+        self.db.flush()?;
+        Ok(removed)
+    }
+
+    /// remove every chunk no snapshot of any owner refers to
+    ///
+    /// a running backup stores or reuses chunks before the snapshot that refers to them exists,
+    /// so chunks that were touched less than `grace` ago are never removed. this keeps the gc safe
+    /// without having to lock out backups, as long as no backup takes longer than `grace`
+    pub fn gc(&self, grace: Duration, dry_run: bool) -> Result<GcStats, StoreError> {
+        let mut referenced = HashSet::new();
+        for owner in self.owners() {
+            for snapshot in self.list_snapshots(&owner, None)? {
+                referenced.extend(snapshot.chunks().copied());
+            }
+        }
+        let cutoff = unix_now().saturating_sub(grace.as_secs());
+        let mut stats = GcStats::default();
+        for x in self.chunks.iter() {
+            let (key, data) = x?;
+            let Ok(bytes) = <[u8; 32]>::try_from(key.as_ref()) else {
+                continue;
+            };
+            let hash = ChunkHash::from_bytes(bytes);
+            if referenced.contains(&hash) {
+                continue;
+            }
+            if be_u64(self.touched.get(hash.as_bytes())?) > cutoff {
+                continue;
+            }
+            if !dry_run {
+                let trees = (&self.chunks, &self.touched, &self.chunk_owners, &self.usage);
+                let removed =
+                    trees.transaction(|(chunks, touched, owners, usage)| -> TxResult<bool> {
+                        // a backup may have reused the chunk since it was looked at
+                        if be_u64(touched.get(hash.as_bytes())?) > cutoff {
+                            return Ok(false);
+                        }
+                        chunks.remove(hash.as_bytes())?;
+                        touched.remove(hash.as_bytes())?;
+                        if let Some(owner) = owners.remove(hash.as_bytes())? {
+                            let owner = String::from_utf8_lossy(&owner);
+                            charge(usage, &owner, -(data.len() as i64), None)?;
+                        }
+                        Ok(true)
+                    })?;
+                if !removed {
+                    continue;
+                }
+            }
+            stats.chunks_removed += 1;
+            stats.bytes_freed += data.len() as u64;
+        }
+        self.db.flush()?;
+        Ok(stats)
+    }
+
+    /// check the snapshots of an owner, or of every owner, against the stored chunks
+    ///
+    /// every chunk is checked once no matter how many snapshots refer to it, `on_chunk` is called
+    /// with the size of every chunk that was read back
+    pub fn verify(
+        &self,
+        owner: Option<&str>,
+        full: bool,
+        mut on_chunk: impl FnMut(u64),
+    ) -> Result<VerifyReport, StoreError> {
+        let owners = match owner {
+            Some(x) => vec![x.to_string()],
+            None => self.owners(),
+        };
+        let mut report = VerifyReport::new(full);
+        // the size of every intact chunk, `None` for missing and corrupt ones
+        let mut checked: HashMap<ChunkHash, Option<u64>> = HashMap::new();
+        for owner in owners {
+            for snapshot in self.list_snapshots(&owner, None)? {
+                report.snapshots += 1;
+                let mut check = |hash: &ChunkHash| -> Result<Option<u64>, StoreError> {
+                    if let Some(x) = checked.get(hash) {
+                        return Ok(*x);
+                    }
+                    let len = self.check_chunk(hash, full, &mut report)?;
+                    on_chunk(len.unwrap_or_default());
+                    checked.insert(*hash, len);
+                    Ok(len)
+                };
+                let mut damaged = false;
+                if let Some(pieces) = &snapshot.pieces {
+                    // only the pieces of a set snapshot are expected to be here
+                    for hash in pieces {
+                        damaged |= check(hash)?.is_none();
+                    }
+                } else {
+                    for entry in &snapshot.files {
+                        let mut size = 0;
+                        for hash in &entry.chunks {
+                            match check(hash)? {
+                                Some(x) => size += x,
+                                None => damaged = true,
+                            }
+                        }
+                        // a quick check does not know the size of a chunk
+                        if full && size != entry.size {
+                            damaged = true;
+                        }
+                    }
+                }
+                if damaged {
+                    report.damaged.push(snapshot.id);
+                }
+            }
+        }
+        report.time = SystemTime::now();
+        Ok(report)
+    }
+    fn check_chunk(
+        &self,
+        hash: &ChunkHash,
+        full: bool,
+        report: &mut VerifyReport,
+    ) -> Result<Option<u64>, StoreError> {
+        report.chunks += 1;
+        if !full {
+            if self.has_chunk(hash)? {
+                return Ok(Some(0));
+            }
+            report.missing.push(*hash);
+            return Ok(None);
+        }
+        let Some(data) = self.chunks.get(hash.as_bytes())? else {
+            report.missing.push(*hash);
+            return Ok(None);
+        };
+        report.bytes += data.len() as u64;
+        if ChunkHash::of(&data) != *hash {
+            report.corrupt.push(*hash);
+            return Ok(None);
+        }
+        Ok(Some(data.len() as u64))
+    }
+    pub fn put_last_scrub(&self, report: &VerifyReport) -> Result<(), StoreError> {
+        self.db.insert(LAST_SCRUB, serde_cbor::to_vec(report)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+    pub fn last_scrub(&self) -> Result<Option<VerifyReport>, StoreError> {
+        match self.db.get(LAST_SCRUB)? {
+            Some(x) => Ok(Some(serde_cbor::from_slice(&x)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn snapshot_infos(
+        &self,
+        owner: &str,
+        job: Option<&str>,
+    ) -> Result<Vec<SnapshotInfo>, StoreError> {
+        Ok(self
+            .list_snapshots(owner, job)?
+            .iter()
+            .map(Snapshot::info)
+            .collect())
+    }
+}
@@ -0,0 +1,862 @@
+//! syncing a hosted directory with a peer in both directions, see [`backit_core::sync`]
+//!
+//! each daemon keeps the version of every file below a directory it syncs in the
+//! `sync/<dir>` tree, looking at a file bumps our counter when it changed on disk since the
+//! last look. the peer that runs the sync compares both sides and moves every file the way its
+//! newer version goes. a side only takes a new version when its own file did not change since
+//! it was compared, otherwise the file is left for the next sync. removed files stay in the
+//! tree so the removal can be passed on
+//!
+//! only the content and mtime of regular files are synced, symlinks and what is below them are
+//! left alone on both sides. the versions of a large directory have to fit in a single packet
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use backit_core::{
+    ipc::{
+        to_server::{AnyHost, Right},
+        ServerError,
+    },
+    snapshot::{ChunkHash, FileMeta},
+    sync::{Causality, ConflictPolicy, FileVersion, SyncReport},
+    tcp::{ReceivePacket, SendPacket},
+};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::{
+    filter::Filter,
+    index::FileStat,
+    meta,
+    progress::ProgressTracker,
+    reply::Responder,
+    share::FILE_PIECE,
+    store::{Store, StoreError},
+    Server,
+};
+
+/// how directories are synced
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyncConfig {
+    /// how files both sides changed are resolved when the sync command does not say
+    pub policy: ConflictPolicy,
+}
+
+/// a file as we last looked at it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SyncEntry {
+    /// relative to the synced directory
+    path: PathBuf,
+    /// unset when the file is removed
+    stat: Option<FileStat>,
+    version: FileVersion,
+}
+
+fn io_err(path: &Path) -> impl Fn(io::Error) -> ServerError + '_ {
+    move |e| ServerError::Io(format!("{}: {e}", path.display()))
+}
+
+fn hash_file(path: &Path) -> io::Result<ChunkHash> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(ChunkHash::from_bytes(*hasher.finalize().as_bytes()))
+}
+
+/// where the new content of a file is written before it replaces the file
+fn staging_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.backit-sync"))
+}
+fn is_staging(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| x.starts_with('.') && x.ends_with(".backit-sync"))
+}
+
+/// a path a peer sent, it has to stay inside the synced directory
+fn relative(path: &Path) -> Result<&Path, ServerError> {
+    let valid = path.components().next().is_some()
+        && path.components().all(|x| matches!(x, Component::Normal(_)));
+    match valid {
+        true => Ok(path),
+        false => Err(ServerError::InvalidPacket),
+    }
+}
+
+/// the first part of a path below the synced directory that is a symlink, nothing through a
+/// symlink is synced so a write can not leave the directory
+fn symlink_in(root: &Path, path: &Path) -> Result<Option<PathBuf>, ServerError> {
+    let mut at = PathBuf::new();
+    for part in path.components() {
+        at.push(part);
+        let full = root.join(&at);
+        match std::fs::symlink_metadata(&full) {
+            Ok(x) if x.file_type().is_symlink() => return Ok(Some(at)),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_err(&full)(e)),
+        }
+    }
+    Ok(None)
+}
+fn check_symlink(root: &Path, path: &Path) -> Result<(), ServerError> {
+    match symlink_in(root, path)? {
+        Some(x) => Err(ServerError::Io(format!(
+            "{} is a symlink",
+            root.join(x).display()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// the conflict copy of a file, next to it and not overwriting an older copy
+fn conflict_path(path: &Path, peer: &PeerId) -> PathBuf {
+    let peer = peer.to_string();
+    let tag = &peer[peer.len().saturating_sub(8)..];
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = match path.extension() {
+        Some(x) => format!(".{}", x.to_string_lossy()),
+        None => String::new(),
+    };
+    let mut n = 0;
+    loop {
+        let suffix = if n == 0 {
+            String::new()
+        } else {
+            format!("-{n}")
+        };
+        let out = path.with_file_name(format!("{stem}.conflict-{tag}{suffix}{ext}"));
+        if !out.exists() {
+            return out;
+        }
+        n += 1;
+    }
+}
+
+/// the symlinks below a synced directory, relative to it
+type Symlinks = BTreeSet<PathBuf>;
+
+/// the versions of the files below one synced directory, every method blocks
+struct Versions {
+    tree: sled::Tree,
+    root: PathBuf,
+    /// our peer id, the counter we bump
+    me: String,
+}
+impl Versions {
+    fn open(store: &Store, root: &Path, me: &PeerId) -> Result<Self, StoreError> {
+        Ok(Self {
+            tree: store.db().open_tree(format!("sync/{}", root.display()))?,
+            root: root.to_path_buf(),
+            me: me.to_string(),
+        })
+    }
+    fn get(&self, path: &Path) -> Result<Option<SyncEntry>, StoreError> {
+        match self.tree.get(path.as_os_str().as_encoded_bytes())? {
+            Some(x) => Ok(Some(serde_cbor::from_slice(&x)?)),
+            None => Ok(None),
+        }
+    }
+    fn put(&self, entry: &SyncEntry) -> Result<(), StoreError> {
+        self.tree.insert(
+            entry.path.as_os_str().as_encoded_bytes(),
+            serde_cbor::to_vec(entry)?,
+        )?;
+        Ok(())
+    }
+
+    /// the version of a file as it is on disk, a file that was never seen has an empty version
+    fn look(&self, path: &Path) -> Result<FileVersion, ServerError> {
+        let full = self.root.join(path);
+        let old = self.get(path)?;
+        let mut version = old.as_ref().map(|x| x.version.clone()).unwrap_or_default();
+        let metadata = match std::fs::symlink_metadata(&full) {
+            Ok(x) if x.is_file() => Some(x),
+            Ok(_) => None,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(io_err(&full)(e)),
+        };
+        let Some(metadata) = metadata else {
+            if !version.is_removed() {
+                version.version.bump(&self.me);
+                version.hash = None;
+                version.size = 0;
+                version.mtime = Some(SystemTime::now());
+                self.put(&SyncEntry {
+                    path: path.to_path_buf(),
+                    stat: None,
+                    version: version.clone(),
+                })?;
+            }
+            return Ok(version);
+        };
+        let stat = FileStat::of(&metadata);
+        if old.is_some_and(|x| x.stat == Some(stat)) {
+            return Ok(version);
+        }
+        let hash = hash_file(&full).map_err(io_err(&full))?;
+        if version.hash != Some(hash) {
+            version.version.bump(&self.me);
+            version.hash = Some(hash);
+        }
+        version.size = stat.size;
+        version.mtime = stat.mtime;
+        self.put(&SyncEntry {
+            path: path.to_path_buf(),
+            stat: Some(stat),
+            version: version.clone(),
+        })?;
+        Ok(version)
+    }
+    /// look at every file, removed ones included, and find the symlinks
+    ///
+    /// files through a symlink are left out and keep their version, so a file that was
+    /// replaced by a symlink is not passed on as removed
+    fn scan(
+        &self,
+        filter: &Filter,
+    ) -> Result<(BTreeMap<PathBuf, FileVersion>, Symlinks), ServerError> {
+        let mut out = BTreeMap::new();
+        let mut symlinks = Symlinks::new();
+        for full in filter.walk(&self.root).map_err(io_err(&self.root))? {
+            let Ok(path) = full.strip_prefix(&self.root) else {
+                continue;
+            };
+            if is_staging(path) {
+                continue;
+            }
+            match symlink_in(&self.root, path)? {
+                Some(x) => {
+                    symlinks.insert(x);
+                }
+                None => {
+                    out.insert(path.to_path_buf(), self.look(path)?);
+                }
+            }
+        }
+        for x in self.tree.iter() {
+            let (_, bytes) = x.map_err(StoreError::from)?;
+            let entry: SyncEntry = serde_cbor::from_slice(&bytes).map_err(StoreError::from)?;
+            if out.contains_key(&entry.path) {
+                continue;
+            }
+            match symlink_in(&self.root, &entry.path)? {
+                Some(x) => {
+                    symlinks.insert(x);
+                }
+                None => {
+                    let version = self.look(&entry.path)?;
+                    out.insert(entry.path, version);
+                }
+            }
+        }
+        Ok((out, symlinks))
+    }
+
+    /// put a new version of a file in place, `staged` holds its content unless it is a removal
+    /// or the content is already there
+    ///
+    /// this fails with [`ServerError::Changed`] when the file changed since it was compared,
+    /// the new version then does not descend from what is on disk
+    fn take(
+        &self,
+        path: &Path,
+        new: &FileVersion,
+        staged: Option<&Path>,
+    ) -> Result<(), ServerError> {
+        check_symlink(&self.root, path)?;
+        let full = self.root.join(path);
+        let changed = || ServerError::Changed(path.to_path_buf());
+        let current = self.look(path)?;
+        if !matches!(
+            current.version.compare(&new.version),
+            Causality::Before | Causality::Equal
+        ) {
+            return Err(changed());
+        }
+        match (new.hash, staged) {
+            (None, _) => match std::fs::remove_file(&full) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(io_err(&full)(e)),
+                _ => {}
+            },
+            (Some(_), _) if current.hash == new.hash => {}
+            (Some(hash), Some(staged)) => {
+                // the sender changed the file while it was read
+                if hash_file(staged).map_err(io_err(staged))? != hash {
+                    return Err(changed());
+                }
+                meta::apply(staged, &FileMeta::default(), new.mtime, false)
+                    .map_err(io_err(staged))?;
+                std::fs::rename(staged, &full).map_err(io_err(&full))?;
+            }
+            (Some(_), None) => return Err(ServerError::InvalidPacket),
+        }
+        let stat = std::fs::symlink_metadata(&full)
+            .ok()
+            .filter(|x| x.is_file())
+            .map(|x| FileStat::of(&x));
+        self.put(&SyncEntry {
+            path: path.to_path_buf(),
+            stat,
+            version: new.clone(),
+        })?;
+        Ok(())
+    }
+}
+
+/// what happens to a single file
+#[derive(Debug)]
+enum Step {
+    /// the peer takes our version
+    Send(FileVersion),
+    /// we take the version of the peer
+    Take(FileVersion),
+    /// both have the same content, only the version vectors are joined
+    Merge(FileVersion),
+    /// keep the version of the peer as a conflict copy
+    Copy(FileVersion),
+}
+
+/// what has to happen to make both sides the same, and which files were in conflict
+fn plan(
+    local: &BTreeMap<PathBuf, FileVersion>,
+    remote: &BTreeMap<PathBuf, FileVersion>,
+    policy: ConflictPolicy,
+) -> (Vec<(PathBuf, Step)>, Vec<PathBuf>) {
+    let empty = FileVersion::default();
+    let mut steps = Vec::new();
+    let mut conflicts = Vec::new();
+    let paths: BTreeSet<&PathBuf> = local.keys().chain(remote.keys()).collect();
+    for path in paths {
+        let ours = local.get(path).unwrap_or(&empty);
+        let theirs = remote.get(path).unwrap_or(&empty);
+        let causality = ours.version.compare(&theirs.version);
+        if causality == Causality::Equal && ours.hash == theirs.hash {
+            continue;
+        }
+        // joining the vectors of concurrent versions gives one that descends from both
+        let merged = ours.version.merge(&theirs.version);
+        if ours.hash == theirs.hash {
+            let version = FileVersion {
+                version: merged,
+                ..ours.clone()
+            };
+            steps.push((path.clone(), Step::Merge(version)));
+            continue;
+        }
+        let ours_wins = match causality {
+            Causality::After => true,
+            Causality::Before => false,
+            Causality::Equal | Causality::Concurrent => {
+                conflicts.push(path.clone());
+                match policy {
+                    ConflictPolicy::Newest => ours.mtime >= theirs.mtime,
+                    ConflictPolicy::KeepBoth => {
+                        if !ours.is_removed() && !theirs.is_removed() {
+                            steps.push((path.clone(), Step::Copy(theirs.clone())));
+                        }
+                        !ours.is_removed() || theirs.is_removed()
+                    }
+                    ConflictPolicy::Local => true,
+                    ConflictPolicy::Remote => false,
+                }
+            }
+        };
+        let step = match ours_wins {
+            true => Step::Send(FileVersion {
+                version: merged,
+                ..ours.clone()
+            }),
+            false => Step::Take(FileVersion {
+                version: merged,
+                ..theirs.clone()
+            }),
+        };
+        steps.push((path.clone(), step));
+    }
+    (steps, conflicts)
+}
+
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, ServerError> + Send + 'static,
+) -> Result<T, ServerError> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| ServerError::Io(e.to_string()))?
+}
+
+impl Server {
+    fn versions(&self, dir: &Path) -> Result<Versions, ServerError> {
+        Ok(Versions::open(&self.store, dir, &self.client.local_peer())?)
+    }
+    /// the filter of a hosted directory a peer syncs with, it needs read and write rights on
+    /// the directory
+    async fn synced_dir(&self, peer: &PeerId, dir: &Path) -> Result<Filter, ServerError> {
+        let peer = peer.to_string();
+        let state = self.state.lock().await;
+        match state.catalog.dir(dir) {
+            Some(x)
+                if x.allows(&peer, &self.config.groups, Right::Read)
+                    && x.allows(&peer, &self.config.groups, Right::Write) =>
+            {
+                Ok(x.filter.clone())
+            }
+            _ => Err(ServerError::PermissionDenied),
+        }
+    }
+
+    pub async fn sync_index(
+        &self,
+        peer: &PeerId,
+        dir: PathBuf,
+    ) -> Result<BTreeMap<PathBuf, FileVersion>, ServerError> {
+        let filter = self.synced_dir(peer, &dir).await?;
+        let versions = self.versions(&dir)?;
+        blocking(move || versions.scan(&filter).map(|(x, _)| x)).await
+    }
+    pub async fn sync_read(
+        &self,
+        peer: &PeerId,
+        dir: PathBuf,
+        path: &Path,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, ServerError> {
+        let filter = self.synced_dir(peer, &dir).await?;
+        let path = relative(path)?.to_path_buf();
+        let full = dir.join(&path);
+        blocking(move || {
+            check_symlink(&dir, &path)?;
+            // only what the index lists may be read
+            match filter.ignores(&dir.join(&path)) {
+                true => Err(ServerError::PermissionDenied),
+                false => Ok(()),
+            }
+        })
+        .await?;
+        let mut file = tokio::fs::File::open(&full).await.map_err(io_err(&full))?;
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(io_err(&full))?;
+        let mut data = Vec::new();
+        file.take(len.min(FILE_PIECE))
+            .read_to_end(&mut data)
+            .await
+            .map_err(io_err(&full))?;
+        Ok(data)
+    }
+    pub async fn sync_write(
+        &self,
+        peer: &PeerId,
+        dir: PathBuf,
+        path: &Path,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<(), ServerError> {
+        let filter = self.synced_dir(peer, &dir).await?;
+        let path = relative(path)?.to_path_buf();
+        self.check_reserve(data.len() as u64)?;
+        let staged = staging_path(&dir.join(&path));
+        blocking(move || {
+            check_symlink(&dir, &path)?;
+            if filter.excludes(&dir.join(&path)) {
+                return Err(ServerError::PermissionDenied);
+            }
+            if let Some(parent) = staged.parent() {
+                std::fs::create_dir_all(parent).map_err(io_err(parent))?;
+            }
+            let mut file = File::options()
+                .write(true)
+                .create(true)
+                .truncate(offset == 0)
+                .open(&staged)
+                .map_err(io_err(&staged))?;
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.write_all(&data))
+                .map_err(io_err(&staged))
+        })
+        .await
+    }
+    pub async fn sync_commit(
+        &self,
+        peer: &PeerId,
+        dir: PathBuf,
+        path: PathBuf,
+        version: FileVersion,
+    ) -> Result<(), ServerError> {
+        let filter = self.synced_dir(peer, &dir).await?;
+        relative(&path)?;
+        let versions = self.versions(&dir)?;
+        blocking(move || {
+            let staged = staging_path(&dir.join(&path));
+            if filter.excludes(&dir.join(&path)) {
+                let _ = std::fs::remove_file(&staged);
+                return Err(ServerError::PermissionDenied);
+            }
+            let result =
+                versions.take(&path, &version, staged.exists().then_some(staged.as_path()));
+            let _ = std::fs::remove_file(&staged);
+            result
+        })
+        .await
+    }
+
+    /// make a hosted directory and a directory the host hosts the same
+    pub async fn sync(
+        &self,
+        host: &AnyHost,
+        dir: &Path,
+        remote_dir: Option<&Path>,
+        policy: Option<ConflictPolicy>,
+        reply: &Responder,
+    ) -> Result<SyncReport, ServerError> {
+        let peer = self.resolve_host(host).await?;
+        // hosted directories are kept by their canonical path
+        let dir = tokio::fs::canonicalize(dir).await.map_err(io_err(dir))?;
+        let dir = dir.as_path();
+        let remote_dir = remote_dir.unwrap_or(dir).to_path_buf();
+        let policy = policy.unwrap_or(self.config.sync.policy);
+        let filter = match self.state.lock().await.catalog.dir(dir) {
+            Some(x) => x.filter.clone(),
+            None => {
+                return Err(ServerError::Io(format!(
+                    "{} is not a hosted directory",
+                    dir.display()
+                )))
+            }
+        };
+        let (local, symlinks) = {
+            let versions = self.versions(dir)?;
+            blocking(move || versions.scan(&filter)).await?
+        };
+        let mut remote = match self
+            .request(host, SendPacket::SyncIndex(remote_dir.clone()))
+            .await?
+        {
+            ReceivePacket::SyncIndex(x) => x,
+            _ => return Err(ServerError::InvalidPacket),
+        };
+        if remote.keys().any(|x| relative(x).is_err()) {
+            return Err(ServerError::InvalidPacket);
+        }
+        // whatever the peer has where we have a symlink stays on both sides
+        remote.retain(|path, _| !path.ancestors().any(|x| symlinks.contains(x)));
+        let (steps, conflicts) = plan(&local, &remote, policy);
+
+        let mut report = SyncReport {
+            conflicts,
+            ..SyncReport::default()
+        };
+        let mut progress = ProgressTracker::new(reply.clone());
+        let transferred = |x: &Step| match x {
+            Step::Send(x) | Step::Take(x) | Step::Copy(x) => Some(x.size),
+            Step::Merge(_) => None,
+        };
+        progress.set_totals(
+            Some(steps.iter().filter_map(|(_, x)| transferred(x)).sum()),
+            Some(steps.len() as u64),
+        );
+        for (path, step) in steps {
+            progress.start_file(path.display().to_string());
+            let result = self
+                .sync_step(host, &peer, dir, &remote_dir, &path, &step, &mut progress)
+                .await;
+            match result {
+                Ok(()) => match step {
+                    Step::Send(x) if x.is_removed() => report.removed_there.push(path),
+                    Step::Send(_) => report.pushed.push(path),
+                    Step::Take(x) if x.is_removed() => report.removed_here.push(path),
+                    Step::Take(_) => report.pulled.push(path),
+                    Step::Merge(_) | Step::Copy(_) => {}
+                },
+                Err(ServerError::Changed(path)) => {
+                    if !report.conflicts.contains(&path) {
+                        report.conflicts.push(path);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+            progress.finish_file();
+        }
+        progress.finish();
+        Ok(report)
+    }
+    #[allow(clippy::too_many_arguments)]
+    async fn sync_step(
+        &self,
+        host: &AnyHost,
+        peer: &PeerId,
+        dir: &Path,
+        remote_dir: &Path,
+        path: &Path,
+        step: &Step,
+        progress: &mut ProgressTracker,
+    ) -> Result<(), ServerError> {
+        let versions = self.versions(dir)?;
+        let full = dir.join(path);
+        match step {
+            Step::Send(version) | Step::Merge(version) => {
+                if matches!(step, Step::Send(_)) && !version.is_removed() {
+                    self.sync_send(host, remote_dir, path, &full, progress)
+                        .await?;
+                }
+                let request = SendPacket::SyncCommit {
+                    dir: remote_dir.to_path_buf(),
+                    path: path.to_path_buf(),
+                    version: version.clone(),
+                };
+                self.request(host, request).await?;
+                let (path, version) = (path.to_path_buf(), version.clone());
+                blocking(move || versions.take(&path, &version, None)).await
+            }
+            Step::Take(version) => {
+                let staged = staging_path(&full);
+                if !version.is_removed() {
+                    self.sync_receive(host, remote_dir, path, &staged, progress)
+                        .await?;
+                }
+                let (path, version) = (path.to_path_buf(), version.clone());
+                blocking(move || {
+                    let result = versions.take(&path, &version, Some(staged.as_path()));
+                    let _ = std::fs::remove_file(&staged);
+                    result
+                })
+                .await
+            }
+            Step::Copy(version) => {
+                let staged = staging_path(&full);
+                self.sync_receive(host, remote_dir, path, &staged, progress)
+                    .await?;
+                let copy = conflict_path(&full, peer);
+                let mtime = version.mtime;
+                blocking(move || {
+                    meta::apply(&staged, &FileMeta::default(), mtime, false)
+                        .and_then(|()| std::fs::rename(&staged, &copy))
+                        .map_err(io_err(&copy))
+                })
+                .await
+            }
+        }
+    }
+    /// write the content of a file of the peer to `dest`
+    async fn sync_receive(
+        &self,
+        host: &AnyHost,
+        remote_dir: &Path,
+        path: &Path,
+        dest: &Path,
+        progress: &mut ProgressTracker,
+    ) -> Result<(), ServerError> {
+        use tokio::io::AsyncWriteExt;
+
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(io_err(parent))?;
+        }
+        let mut out = tokio::fs::File::create(dest).await.map_err(io_err(dest))?;
+        let mut offset = 0;
+        loop {
+            let request = SendPacket::SyncRead {
+                dir: remote_dir.to_path_buf(),
+                path: path.to_path_buf(),
+                offset,
+                len: FILE_PIECE,
+            };
+            let data = match self.request(host, request).await? {
+                ReceivePacket::FileData(x) => x,
+                _ => return Err(ServerError::InvalidPacket),
+            };
+            if data.is_empty() {
+                break;
+            }
+            out.write_all(&data).await.map_err(io_err(dest))?;
+            offset += data.len() as u64;
+            progress.add_bytes(data.len() as u64);
+        }
+        out.flush().await.map_err(io_err(dest))?;
+        Ok(())
+    }
+    /// send the content of a file to the peer, an empty file still gets a write so the peer
+    /// starts over
+    async fn sync_send(
+        &self,
+        host: &AnyHost,
+        remote_dir: &Path,
+        path: &Path,
+        full: &Path,
+        progress: &mut ProgressTracker,
+    ) -> Result<(), ServerError> {
+        let mut file = tokio::fs::File::open(full).await.map_err(io_err(full))?;
+        let mut offset = 0;
+        loop {
+            let mut data = Vec::new();
+            (&mut file)
+                .take(FILE_PIECE)
+                .read_to_end(&mut data)
+                .await
+                .map_err(io_err(full))?;
+            if data.is_empty() && offset > 0 {
+                break;
+            }
+            let len = data.len() as u64;
+            let request = SendPacket::SyncWrite {
+                dir: remote_dir.to_path_buf(),
+                path: path.to_path_buf(),
+                offset,
+                data,
+            };
+            match self.request(host, request).await? {
+                ReceivePacket::Ok => {}
+                _ => return Err(ServerError::InvalidPacket),
+            }
+            offset += len;
+            progress.add_bytes(len);
+            if len < FILE_PIECE {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use backit_core::sync::VersionVector;
+
+    use super::*;
+
+    /// a file with the change counts of peers `a` and `b`, `content` is unset for a removal
+    fn file(a: u64, b: u64, content: Option<&[u8]>, mtime: u64) -> FileVersion {
+        let mut version = VersionVector::default();
+        (0..a).for_each(|_| version.bump("a"));
+        (0..b).for_each(|_| version.bump("b"));
+        FileVersion {
+            version,
+            hash: content.map(ChunkHash::of),
+            size: content.map_or(0, |x| x.len() as u64),
+            mtime: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime)),
+        }
+    }
+
+    /// plan a single file, the steps are shown as the name of the step and the content it moves
+    fn plan_one(
+        ours: Option<FileVersion>,
+        theirs: Option<FileVersion>,
+        policy: ConflictPolicy,
+    ) -> (Vec<(&'static str, Option<ChunkHash>)>, bool) {
+        let path = PathBuf::from("x");
+        let local = ours.map(|x| (path.clone(), x)).into_iter().collect();
+        let remote = theirs.map(|x| (path.clone(), x)).into_iter().collect();
+        let (steps, conflicts) = plan(&local, &remote, policy);
+        let steps = steps
+            .into_iter()
+            .map(|(_, x)| match x {
+                Step::Send(x) => ("send", x.hash),
+                Step::Take(x) => ("take", x.hash),
+                Step::Merge(x) => ("merge", x.hash),
+                Step::Copy(x) => ("copy", x.hash),
+            })
+            .collect();
+        (steps, conflicts == [path])
+    }
+
+    fn hash(content: &[u8]) -> Option<ChunkHash> {
+        Some(ChunkHash::of(content))
+    }
+
+    #[test]
+    fn newer_version_wins() {
+        let policy = ConflictPolicy::Remote;
+        let old = file(1, 0, Some(b"old"), 1);
+        let new = file(2, 0, Some(b"new"), 2);
+        let plan = plan_one(Some(new.clone()), Some(old.clone()), policy);
+        assert_eq!(plan, (vec![("send", hash(b"new"))], false));
+        let plan = plan_one(Some(old.clone()), Some(new.clone()), policy);
+        assert_eq!(plan, (vec![("take", hash(b"new"))], false));
+        assert_eq!(
+            plan_one(Some(new.clone()), Some(new), policy),
+            (vec![], false)
+        );
+        // a file one side never had descends from nothing
+        let plan = plan_one(Some(old.clone()), None, policy);
+        assert_eq!(plan, (vec![("send", hash(b"old"))], false));
+        let plan = plan_one(None, Some(old), policy);
+        assert_eq!(plan, (vec![("take", hash(b"old"))], false));
+    }
+
+    #[test]
+    fn removal_is_passed_on() {
+        let removed = file(2, 0, None, 2);
+        let kept = file(1, 0, Some(b"old"), 1);
+        let plan = plan_one(
+            Some(removed.clone()),
+            Some(kept.clone()),
+            ConflictPolicy::Remote,
+        );
+        assert_eq!(plan, (vec![("send", None)], false));
+        let plan = plan_one(Some(kept), Some(removed), ConflictPolicy::Local);
+        assert_eq!(plan, (vec![("take", None)], false));
+    }
+
+    #[test]
+    fn same_content_is_merged() {
+        let ours = file(1, 0, Some(b"same"), 1);
+        let theirs = file(0, 1, Some(b"same"), 2);
+        let plan = plan_one(Some(ours), Some(theirs), ConflictPolicy::KeepBoth);
+        assert_eq!(plan, (vec![("merge", hash(b"same"))], false));
+    }
+
+    #[test]
+    fn conflict_policies() {
+        let ours = file(2, 1, Some(b"ours"), 2);
+        let theirs = file(1, 2, Some(b"theirs"), 1);
+        let plan = |policy| plan_one(Some(ours.clone()), Some(theirs.clone()), policy);
+        let newest = plan(ConflictPolicy::Newest);
+        assert_eq!(newest, (vec![("send", hash(b"ours"))], true));
+        let plan_newer = plan_one(
+            Some(ours.clone()),
+            Some(file(1, 2, Some(b"theirs"), 3)),
+            ConflictPolicy::Newest,
+        );
+        assert_eq!(plan_newer, (vec![("take", hash(b"theirs"))], true));
+        let both = plan(ConflictPolicy::KeepBoth);
+        let expected = vec![("copy", hash(b"theirs")), ("send", hash(b"ours"))];
+        assert_eq!(both, (expected, true));
+        let local = plan(ConflictPolicy::Local);
+        assert_eq!(local, (vec![("send", hash(b"ours"))], true));
+        let remote = plan(ConflictPolicy::Remote);
+        assert_eq!(remote, (vec![("take", hash(b"theirs"))], true));
+    }
+
+    #[test]
+    fn conflict_with_removal() {
+        let changed = file(2, 1, Some(b"changed"), 1);
+        let removed = file(1, 2, None, 2);
+        let plan = |ours: &FileVersion, theirs: &FileVersion, policy| {
+            plan_one(Some(ours.clone()), Some(theirs.clone()), policy)
+        };
+        // a change always wins from a removal when both are kept, there is nothing to copy
+        let both = plan(&changed, &removed, ConflictPolicy::KeepBoth);
+        assert_eq!(both, (vec![("send", hash(b"changed"))], true));
+        let both = plan(&removed, &changed, ConflictPolicy::KeepBoth);
+        assert_eq!(both, (vec![("take", hash(b"changed"))], true));
+        // the removal is newer
+        let newest = plan(&changed, &removed, ConflictPolicy::Newest);
+        assert_eq!(newest, (vec![("take", None)], true));
+        let local = plan(&removed, &changed, ConflictPolicy::Local);
+        assert_eq!(local, (vec![("send", None)], true));
+        let remote = plan(&changed, &removed, ConflictPolicy::Remote);
+        assert_eq!(remote, (vec![("take", None)], true));
+    }
+}
@@ -0,0 +1,309 @@
+//! rate limits on the traffic with other daemons
+//!
+//! every request and response is counted with its encoded size against a token bucket for all
+//! peers together and one for the peer it goes to or comes from. a packet is sent once the
+//! bucket is not in debt, so a packet larger than a second of traffic still goes through and
+//! the packets after it wait longer. what we receive can only be counted once it arrived, the
+//! wait before the next request is what slows the peer down
+//!
+//! background transfers, like automatic backups, wait while an interactive one waits for the
+//! same bucket. without a limit nothing waits, so this only matters once a limit is set
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use backit_core::ipc::{to_server::AnyHost, ServerError};
+use bytesize::ByteSize;
+use chrono::{Local, NaiveTime};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::Server;
+
+/// how long a background transfer waits before it looks again whether it may go
+const YIELD: Duration = Duration::from_millis(50);
+
+tokio::task_local! {
+    static PRIORITY: Priority;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// a transfer someone waits for, the default
+    Interactive,
+    Background,
+}
+impl Priority {
+    fn current() -> Self {
+        PRIORITY.try_with(|x| *x).unwrap_or(Priority::Interactive)
+    }
+}
+
+/// run a future with its traffic as background traffic
+pub async fn background<F: Future>(f: F) -> F::Output {
+    PRIORITY.scope(Priority::Background, f).await
+}
+
+/// limits in bytes per second, no limit when unset
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    pub upload: Option<ByteSize>,
+    pub download: Option<ByteSize>,
+}
+impl RateLimit {
+    fn get(&self, direction: Direction) -> Option<u64> {
+        match direction {
+            Direction::Upload => self.upload,
+            Direction::Download => self.download,
+        }
+        .map(|x| x.0)
+    }
+}
+
+/// limits for all peers together during part of the day, `to` may be before `from` to go past
+/// midnight
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledLimit {
+    /// local time, like `"08:00"`
+    #[serde(with = "clock")]
+    pub from: NaiveTime,
+    #[serde(with = "clock")]
+    pub to: NaiveTime,
+    #[serde(flatten)]
+    pub limit: RateLimit,
+}
+impl ScheduledLimit {
+    fn contains(&self, time: NaiveTime) -> bool {
+        match self.from <= self.to {
+            true => self.from <= time && time < self.to,
+            false => self.from <= time || time < self.to,
+        }
+    }
+}
+
+mod clock {
+    use chrono::NaiveTime;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(&time.format("%H:%M"))
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<NaiveTime, D::Error> {
+        let x = String::deserialize(d)?;
+        NaiveTime::parse_from_str(&x, "%H:%M").map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    /// limits for all peers together
+    #[serde(flatten)]
+    pub limit: RateLimit,
+    /// replace the limits for all peers together during part of the day, the first entry that
+    /// contains the current time is used
+    pub schedule: Vec<ScheduledLimit>,
+    /// limits by peer id, on top of those for all peers
+    pub peers: BTreeMap<String, RateLimit>,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// negative when packets went out before the bytes were there
+    tokens: f64,
+    refilled: Instant,
+    /// interactive transfers that wait for this bucket
+    interactive: usize,
+}
+
+/// counts an interactive transfer as waiting for a bucket until it is dropped, so a cancelled
+/// transfer stops counting as well
+struct Waiting<'a> {
+    throttle: &'a Throttle,
+    key: (Option<PeerId>, Direction),
+}
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut buckets = self.throttle.buckets.lock().expect("not poisoned");
+        if let Some(bucket) = buckets.get_mut(&self.key) {
+            bucket.interactive -= 1;
+        }
+    }
+}
+
+/// the limits of the daemon and what is left of them
+#[derive(Debug)]
+pub struct Throttle {
+    config: ThrottleConfig,
+    /// limits set with the throttle command, a direction that is set wins from the config until
+    /// the daemon restarts. a limit of 0 is set to no limit
+    global: Mutex<RateLimit>,
+    peers: Mutex<HashMap<PeerId, RateLimit>>,
+    /// by peer, `None` is the bucket of all peers together
+    buckets: Mutex<HashMap<(Option<PeerId>, Direction), Bucket>>,
+}
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            config,
+            global: Mutex::new(RateLimit::default()),
+            peers: Mutex::new(HashMap::new()),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// change the limits of a peer, or of all peers together, until the daemon restarts, a
+    /// direction that is not set keeps its limit
+    pub fn set(&self, peer: Option<PeerId>, limit: RateLimit) {
+        let set = |x: &mut RateLimit| {
+            x.upload = limit.upload.or(x.upload);
+            x.download = limit.download.or(x.download);
+        };
+        match peer {
+            Some(peer) => set(self
+                .peers
+                .lock()
+                .expect("not poisoned")
+                .entry(peer)
+                .or_default()),
+            None => set(&mut self.global.lock().expect("not poisoned")),
+        }
+    }
+    /// go back to the limits of the config
+    pub fn reset(&self, peer: Option<PeerId>) {
+        match peer {
+            Some(peer) => {
+                self.peers.lock().expect("not poisoned").remove(&peer);
+            }
+            None => *self.global.lock().expect("not poisoned") = RateLimit::default(),
+        }
+    }
+
+    /// the limits that hold right now
+    fn current(&self, peer: Option<&PeerId>) -> RateLimit {
+        let (set, config) = match peer {
+            Some(peer) => (
+                self.peers
+                    .lock()
+                    .expect("not poisoned")
+                    .get(peer)
+                    .copied()
+                    .unwrap_or_default(),
+                self.config
+                    .peers
+                    .get(&peer.to_string())
+                    .copied()
+                    .unwrap_or_default(),
+            ),
+            None => {
+                let now = Local::now().time();
+                let config = self
+                    .config
+                    .schedule
+                    .iter()
+                    .find(|x| x.contains(now))
+                    .map_or(self.config.limit, |x| x.limit);
+                (*self.global.lock().expect("not poisoned"), config)
+            }
+        };
+        RateLimit {
+            upload: set.upload.or(config.upload),
+            download: set.download.or(config.download),
+        }
+    }
+    fn limit(&self, peer: Option<&PeerId>, direction: Direction) -> Option<u64> {
+        self.current(peer).get(direction).filter(|x| *x > 0)
+    }
+
+    /// wait until `bytes` may go through a bucket, or right away without a limit
+    async fn take(&self, peer: Option<PeerId>, direction: Direction, bytes: u64) {
+        let key = (peer, direction);
+        let priority = Priority::current();
+        let mut waiting = None;
+        loop {
+            let Some(rate) = self.limit(peer.as_ref(), direction) else {
+                return;
+            };
+            let wait = {
+                let mut buckets = self.buckets.lock().expect("not poisoned");
+                let now = Instant::now();
+                let bucket = buckets.entry(key).or_insert_with(|| Bucket {
+                    tokens: rate as f64,
+                    refilled: now,
+                    interactive: 0,
+                });
+                // at most a second worth of traffic is saved up
+                let elapsed = (now - bucket.refilled).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64);
+                bucket.refilled = now;
+                if priority == Priority::Background && bucket.interactive > 0 {
+                    Some(YIELD)
+                } else if bucket.tokens >= 0.0 {
+                    bucket.tokens -= bytes as f64;
+                    None
+                } else {
+                    if priority == Priority::Interactive && waiting.is_none() {
+                        bucket.interactive += 1;
+                        waiting = Some(Waiting {
+                            throttle: self,
+                            key,
+                        });
+                    }
+                    Some(Duration::from_secs_f64(-bucket.tokens / rate as f64))
+                }
+            };
+            match wait {
+                Some(x) => tokio::time::sleep(x).await,
+                None => return,
+            }
+        }
+    }
+    /// wait until a packet of `bytes` may be sent to a peer
+    pub async fn upload(&self, peer: &PeerId, bytes: u64) {
+        self.take(Some(*peer), Direction::Upload, bytes).await;
+        self.take(None, Direction::Upload, bytes).await;
+    }
+    /// count a packet of `bytes` we got from a peer, this waits when we got more than allowed
+    pub async fn download(&self, peer: &PeerId, bytes: u64) {
+        self.take(Some(*peer), Direction::Download, bytes).await;
+        self.take(None, Direction::Download, bytes).await;
+    }
+}
+
+impl Server {
+    /// change the limits of a peer, or of all peers together, from the throttle command
+    pub async fn throttle(
+        &self,
+        host: Option<AnyHost>,
+        upload: Option<u64>,
+        download: Option<u64>,
+        reset: bool,
+    ) -> Result<(), ServerError> {
+        let peer = match &host {
+            Some(x) => Some(self.resolve_host(x).await?),
+            None => None,
+        };
+        let throttle = self.client.throttle();
+        if reset {
+            throttle.reset(peer);
+            return Ok(());
+        }
+        let limit = RateLimit {
+            upload: upload.map(ByteSize),
+            download: download.map(ByteSize),
+        };
+        throttle.set(peer, limit);
+        Ok(())
+    }
+}
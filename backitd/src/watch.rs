@@ -0,0 +1,189 @@
+//! keeps the catalog current while hosted directories change
+//!
+//! changes are picked up with inotify through notify, a periodic rescan catches up on events the
+//! watcher missed, for example when its queue overflowed. jobs with an `auto` config are backed
+//! up once their files were left alone for the debounce period
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use backit_core::ipc::{
+    to_server::{AnyHost, Command, FileTarget, HostId, Share},
+    ServerError,
+};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher as _};
+use tokio::{
+    sync::mpsc,
+    time::{sleep_until, Instant},
+};
+
+use crate::{
+    catalog::{HostScan, HostedFile, Scan},
+    queue::AUTO_PRIORITY,
+    Server, State,
+};
+
+/// the inotify watches on the hosted directories
+pub struct Watcher {
+    inner: RecommendedWatcher,
+    watched: BTreeSet<PathBuf>,
+}
+impl Watcher {
+    pub fn new(tx: mpsc::UnboundedSender<notify::Result<Event>>) -> notify::Result<Self> {
+        let inner = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })?;
+        Ok(Self {
+            inner,
+            watched: BTreeSet::new(),
+        })
+    }
+    /// watch exactly the given directories, a directory that can not be watched is tried again
+    /// on the next sync
+    pub fn sync<'a>(&mut self, dirs: impl Iterator<Item = &'a Path>) {
+        let dirs: BTreeSet<PathBuf> = dirs.map(Path::to_path_buf).collect();
+        for dir in self.watched.difference(&dirs) {
+            let _ = self.inner.unwatch(dir);
+        }
+        self.watched.retain(|x| dirs.contains(x));
+        for dir in dirs {
+            if self.watched.contains(&dir) {
+                continue;
+            }
+            match self.inner.watch(&dir, RecursiveMode::Recursive) {
+                Ok(()) => {
+                    self.watched.insert(dir);
+                }
+                Err(e) => eprintln!("could not watch {}: {e}", dir.display()),
+            }
+        }
+    }
+}
+
+impl State {
+    /// make the watcher follow the hosted directories of the catalog
+    pub fn sync_watches(&mut self) {
+        if let Some(watcher) = &mut self.watcher {
+            watcher.sync(self.catalog.dirs());
+        }
+    }
+}
+
+impl Server {
+    /// host a file or every file in a directory, the directory is walked without the state lock
+    pub async fn host(
+        &self,
+        target: &FileTarget,
+        tags: &[String],
+        shares: &[Share],
+    ) -> Result<usize, ServerError> {
+        let shares = self.resolve_shares(shares).await?;
+        let (target, ignore) = (target.clone(), self.config.ignore.clone());
+        let scan = tokio::task::spawn_blocking(move || HostScan::new(&target, &ignore))
+            .await
+            .map_err(|e| ServerError::Io(e.to_string()))?
+            .map_err(|e| ServerError::Io(e.to_string()))?;
+        let mut state = self.state.lock().await;
+        let count = state.catalog.host(scan, tags, &shares);
+        state.sync_watches();
+        Ok(count)
+    }
+    /// bring the paths up to date in the catalog, the disk is read without the state lock
+    async fn refresh(&self, paths: Vec<PathBuf>) -> Vec<HostedFile> {
+        let wanted = self.state.lock().await.catalog.to_scan(paths);
+        let scans = tokio::task::spawn_blocking(move || {
+            wanted
+                .into_iter()
+                .map(|(path, filter)| Scan::new(path, filter.as_ref()))
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_default();
+        let mut state = self.state.lock().await;
+        scans
+            .into_iter()
+            .flat_map(|x| state.catalog.refresh(x))
+            .collect()
+    }
+    async fn rescan(&self) -> Vec<HostedFile> {
+        let paths = self.state.lock().await.catalog.paths();
+        let changed = self.refresh(paths).await;
+        // a hosted directory that was removed and made again lost its watch
+        self.state.lock().await.sync_watches();
+        changed
+    }
+    fn auto_backup(&self, job: String) {
+        let Some(auto) = self.config.jobs.get(&job).and_then(|x| x.auto.clone()) else {
+            return;
+        };
+        let server = self.clone();
+        tokio::spawn(async move {
+            let command = Command::Backup {
+                host: auto.host.map(|x| AnyHost::HostId(HostId::new_nickname(x))),
+                target: auto.target,
+                full: false,
+                priority: AUTO_PRIORITY,
+                job,
+            };
+            if let Err(e) = server.enqueue(command, AUTO_PRIORITY, true, None).await {
+                eprintln!("could not queue an automatic backup: {e:?}");
+            }
+        });
+    }
+
+    /// watch the hosted directories until the daemon stops
+    pub async fn watch_loop(self) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        match Watcher::new(tx) {
+            Ok(watcher) => {
+                let mut state = self.state.lock().await;
+                state.watcher = Some(watcher);
+                state.sync_watches();
+            }
+            Err(e) => eprintln!("could not start the watcher, only rescans pick up changes: {e}"),
+        }
+        let mut rescan = tokio::time::interval(self.config.watch.rescan_interval);
+        // the first tick is immediate and the catalog starts out empty
+        rescan.tick().await;
+        // when each job with an auto backup is due, pushed back on every change
+        let mut due: BTreeMap<String, Instant> = BTreeMap::new();
+        loop {
+            let next = due.values().min().copied();
+            let changed = tokio::select! {
+                Some(event) = rx.recv() => match event {
+                    Ok(event) if event.need_rescan() => self.rescan().await,
+                    Ok(event) if event.kind.is_access() => continue,
+                    Ok(event) => self.refresh(event.paths).await,
+                    Err(e) => {
+                        eprintln!("watch error: {e}");
+                        self.rescan().await
+                    }
+                },
+                _ = rescan.tick() => self.rescan().await,
+                _ = sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                    let now = Instant::now();
+                    let ready: Vec<String> = due
+                        .iter()
+                        .filter(|(_, x)| **x <= now)
+                        .map(|(job, _)| job.clone())
+                        .collect();
+                    for job in ready {
+                        due.remove(&job);
+                        self.auto_backup(job);
+                    }
+                    continue;
+                }
+            };
+            for (job, config) in &self.config.jobs {
+                let Some(auto) = &config.auto else {
+                    continue;
+                };
+                if changed.iter().any(|x| x.matches(&auto.target)) {
+                    due.insert(job.clone(), Instant::now() + auto.debounce);
+                }
+            }
+        }
+    }
+}